@@ -1,80 +1,183 @@
+use std::net::SocketAddr;
 use std::time::{Duration, SystemTime};
 
 use cached::stores::ExpiringSizedCache;
 use tokio::sync::RwLock;
 
-use super::protocol::{Question, ResourceRecord};
+use super::config::Config;
+use super::protocol::{Flags, Message, NOERROR, NXDOMAIN, Question, ResourceRecord};
+use super::rdata::{OPT, RData};
 
-// The max TTL seconds allowed by the cache.
-const MAX_TTL_SECONDS: u32 = 1800; // 30 minutes
-
-struct DnsCacheValue {
-    answers: Vec<ResourceRecord>,
-    inserted_at: SystemTime,
+/// The cached response to a question, everything but the header ID, the question and the OPT
+/// record, which come from the query it answers. The negative answers, NXDOMAIN or NODATA, are
+/// cached for the TTL of the SOA record of their authority section, RFC 2308 5.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub flags: Flags,
+    /// The RCODE, including the bits of the OPT record.
+    pub rcode: u16,
+    pub answers: Vec<ResourceRecord>,
+    pub authorities: Vec<ResourceRecord>,
+    /// The additional records but the OPT record.
+    pub additionals: Vec<ResourceRecord>,
 }
 
-fn min_ttl(rr: &[ResourceRecord]) -> Option<Duration> {
-    rr.iter()
-        .min_by_key(|rr| rr.ttl)
-        // If the TTL is greater than the max TTL allowed by the cache, use the max TTL.
-        .map(|k| {
-            if k.ttl > MAX_TTL_SECONDS {
-                MAX_TTL_SECONDS
-            } else {
-                k.ttl
+impl CachedResponse {
+    /// The response to cache, `None` for the errors and the negative answers without an SOA
+    /// record.
+    pub fn from_response(res: &Message) -> Option<CachedResponse> {
+        let rcode = res.rcode();
+        if rcode != NOERROR && rcode != NXDOMAIN {
+            return None;
+        }
+        let mut authorities = res.authorities.clone();
+        if rcode == NXDOMAIN || res.answers.is_empty() {
+            let soa = authorities
+                .iter_mut()
+                .find(|rr| matches!(rr.rdata, RData::Soa(_)))?;
+            if let RData::Soa(data) = &soa.rdata {
+                // the TTL of a negative answer, RFC 2308 5.
+                soa.ttl = soa.ttl.min(data.minimum);
             }
+        }
+        Some(CachedResponse {
+            flags: res.header.flags.clone(),
+            rcode,
+            answers: res.answers.clone(),
+            authorities,
+            additionals: res
+                .additionals
+                .iter()
+                .filter(|rr| rr.rtype != OPT)
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// The response to `query`. It keeps the OPT record of the query, which is replaced by ours
+    /// before it is sent.
+    pub fn into_response(self, query: Message) -> Message {
+        let rd = query.header.flags.rd();
+        let edns = query.edns().ok().flatten();
+        let mut response = query;
+        response.header.flags = self.flags;
+        response.header.flags.set_rd(rd);
+        response.header.ancount = self.answers.len() as u16;
+        response.answers = self.answers;
+        response.header.nscount = self.authorities.len() as u16;
+        response.authorities = self.authorities;
+        response.additionals = self.additionals;
+        response.set_edns(edns);
+        response.set_rcode(self.rcode);
+        response
+    }
+
+    fn records_mut(&mut self) -> impl Iterator<Item = &mut ResourceRecord> {
+        self.answers
+            .iter_mut()
+            .chain(self.authorities.iter_mut())
+            .chain(self.additionals.iter_mut())
+    }
+
+    /// The entry expires with the first of its records.
+    fn min_ttl(&self) -> Option<Duration> {
+        self.answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+            .map(|rr| rr.ttl)
+            .min()
+            .map(|ttl| Duration::from_secs(ttl as u64))
+    }
+}
+
+/// The key of a cached response. The DO and CD bits of the query decide whether the response has
+/// the DNSSEC records and whether it was validated, RFC 4035 3.2, so they are part of the key.
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone)]
+pub struct CacheKey {
+    question: Question,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+}
+
+impl CacheKey {
+    /// The key of a query, `None` unless it has a single question.
+    pub fn new(query: &Message) -> Option<CacheKey> {
+        let [question] = query.questions.as_slice() else {
+            return None;
+        };
+        Some(CacheKey {
+            question: question.clone(),
+            dnssec_ok: query
+                .edns()
+                .ok()
+                .flatten()
+                .is_some_and(|edns| edns.dnssec_ok),
+            checking_disabled: query.header.flags.cd() == 1,
         })
-        .map(|ttl| Duration::from_secs(ttl as u64))
+    }
+}
+
+struct DnsCacheValue {
+    response: CachedResponse,
+    inserted_at: SystemTime,
 }
 
 pub struct DnsCache {
-    cache: RwLock<ExpiringSizedCache<Question, DnsCacheValue>>,
+    cache: RwLock<ExpiringSizedCache<CacheKey, DnsCacheValue>>,
+    /// The addresses of the name servers of the zones learned from referrals, by zone name.
+    delegations: RwLock<ExpiringSizedCache<String, Vec<SocketAddr>>>,
+    min_ttl: u32,
+    max_ttl: u32,
 }
 
 impl DnsCache {
-    // TODO consider upper bound on size
-    pub fn new() -> DnsCache {
+    /// When the cache is full the entries closest to expiring are evicted.
+    pub fn new(config: &Config) -> DnsCache {
+        let mut cache = ExpiringSizedCache::new(Duration::from_secs(config.max_ttl as u64));
+        cache.size_limit(config.cache_size);
+        let mut delegations = ExpiringSizedCache::new(Duration::from_secs(config.max_ttl as u64));
+        delegations.size_limit(config.cache_size);
         DnsCache {
-            cache: RwLock::new(ExpiringSizedCache::new(Duration::from_secs(
-                MAX_TTL_SECONDS as u64,
-            ))),
+            cache: RwLock::new(cache),
+            delegations: RwLock::new(delegations),
+            min_ttl: config.min_ttl,
+            max_ttl: config.max_ttl,
         }
     }
 
-    pub async fn get(&self, question: &Question) -> Option<Vec<ResourceRecord>> {
+    pub async fn get(&self, key: &CacheKey) -> Option<CachedResponse> {
         let cache = self.cache.read().await;
-        cache.get(question).map(|v| {
-            let mut answers = v.answers.clone(); // TODO this clone can be prevented if the tll is updated as the message is being written out
-            // return a copy of the answers with the TTLs adjusted.
+        cache.get(key).map(|v| {
+            let mut response = v.response.clone(); // TODO this clone can be prevented if the tll is updated as the message is being written out
+            // return a copy of the response with the TTLs adjusted.
             let elapsed = v.inserted_at.elapsed().unwrap().as_secs() as u32;
-            for rr in &mut answers {
+            for rr in response.records_mut() {
                 rr.ttl = rr.ttl.saturating_sub(elapsed)
             }
-            answers
+            response
         })
     }
 
-    /// Adjust the TTL, anything that will go into the cache cannot exceed the caches configured
-    /// TTL.
-    pub fn normalise_ttl(answers: &mut Vec<ResourceRecord>) {
-        for ans in answers {
-            if ans.ttl > MAX_TTL_SECONDS {
-                ans.ttl = MAX_TTL_SECONDS
-            }
+    /// Adjust the TTL, anything that will go into the cache must be within the configured TTL
+    /// clamps.
+    pub fn normalise_ttl(&self, response: &mut CachedResponse) {
+        for rr in response.records_mut() {
+            rr.ttl = rr.ttl.clamp(self.min_ttl, self.max_ttl);
         }
     }
 
-    pub async fn set(&self, question: &Question, answers: Vec<ResourceRecord>) {
-        let min_ttl = min_ttl(&answers).unwrap();
+    pub async fn set(&self, key: &CacheKey, response: CachedResponse) {
+        let min_ttl = response.min_ttl().unwrap();
 
         let mut cache = self.cache.write().await;
 
         if !min_ttl.is_zero() {
             cache
                 .insert_ttl_evict(
-                    question.clone(),
+                    key.clone(),
                     DnsCacheValue {
-                        answers,
+                        response,
                         inserted_at: SystemTime::now(),
                     },
                     Some(min_ttl),
@@ -83,4 +186,25 @@ impl DnsCache {
                 .expect("could not set key");
         }
     }
+
+    /// The name servers of `zone`, the zone name is lower case.
+    pub async fn get_delegation(&self, zone: &str) -> Option<Vec<SocketAddr>> {
+        self.delegations.read().await.get_borrowed(zone).cloned()
+    }
+
+    pub async fn set_delegation(&self, zone: &str, servers: Vec<SocketAddr>, ttl: u32) {
+        let ttl = ttl.clamp(self.min_ttl, self.max_ttl);
+        if ttl > 0 {
+            self.delegations
+                .write()
+                .await
+                .insert_ttl_evict(
+                    zone.to_string(),
+                    servers,
+                    Some(Duration::from_secs(ttl as u64)),
+                    true,
+                )
+                .expect("could not set key");
+        }
+    }
 }
@@ -1,16 +1,22 @@
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use tokio::{
-    net::UdpSocket,
+    net::{TcpStream, UdpSocket},
     sync::{Mutex, oneshot},
     task::JoinHandle,
     time::{sleep, timeout},
 };
 
-use super::protocol::Message;
+use super::edns::Edns;
+use super::protocol::{FORMERR, Message, Question};
+use super::tcp;
+
+/// The size of the receive buffer, advertised to the upstream as our UDP payload size.
+const UDP_BUFFER_SIZE: usize = 4096;
 
 /// Slots tracks that state to support de-multiplexing responses.
 struct Slots {
@@ -50,8 +56,9 @@ impl Slots {
 
 struct Channel {
     socket: UdpSocket,
-    addr: String,
+    addr: SocketAddr,
     slots: Mutex<Slots>,
+    timeout: Duration,
 }
 
 pub struct DnsClient {
@@ -59,16 +66,21 @@ pub struct DnsClient {
     r_handle: JoinHandle<()>,
 }
 
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
-
 impl DnsClient {
-    pub async fn connect(addr: &str) -> Result<DnsClient> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await.expect("couldn't bind");
+    /// Queries to `addr` fail with `TimedOut` when no response arrives within `timeout`.
+    pub async fn connect(addr: SocketAddr, timeout: Duration) -> Result<DnsClient> {
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await?;
 
         let st = Arc::new(Channel {
             socket,
-            addr: String::from(addr),
+            addr,
             slots: Mutex::new(Slots::new()),
+            timeout,
         });
 
         let r_handle = Self::start_receive_loop(st.clone());
@@ -78,7 +90,7 @@ impl DnsClient {
 
     fn start_receive_loop(st: Arc<Channel>) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let mut buf = [0; 4096];
+            let mut buf = [0; UDP_BUFFER_SIZE];
             loop {
                 match st.socket.recv_from(&mut buf).await {
                     Ok((len, _)) => match Message::from_bytes(&buf[..len]) {
@@ -109,20 +121,40 @@ impl DnsClient {
         })
     }
 
+    /// Send a query over UDP, and again over TCP if the response is truncated. The query carries
+    /// our own OPT record, the options of the client are not forwarded but its DO bit is.
     pub async fn query(&self, msg: &Message) -> Result<Message> {
+        let mut msg = msg.clone();
+        let dnssec_ok = msg.edns()?.is_some_and(|edns| edns.dnssec_ok);
+        msg.set_edns(Some(Edns {
+            dnssec_ok,
+            ..Edns::new(UDP_BUFFER_SIZE as u16)
+        }));
+        let mut res = self.query_udp(&msg).await?;
+        if edns_unsupported(&res) {
+            msg.set_edns(None);
+            res = self.query_udp(&msg).await?;
+        }
+        if res.header.flags.tc() == 1 {
+            return query_tcp(self.st.addr, &msg, self.st.timeout).await;
+        }
+        Ok(res)
+    }
+
+    async fn query_udp(&self, msg: &Message) -> Result<Message> {
         let (client_id, rx) = self.st.slots.lock().await.create(msg.header.id)?;
         let packet = msg.to_udp_packet(Some(client_id)).unwrap();
         if let Err(e) = self
             .st
             .socket
-            .send_to(packet.as_slice(), &self.st.addr)
+            .send_to(packet.as_slice(), self.st.addr)
             .await
         {
             self.st.slots.lock().await.remove(client_id);
             return Err(e);
         }
 
-        match timeout(CLIENT_TIMEOUT, rx).await {
+        match timeout(self.st.timeout, rx).await {
             Ok(rcv) => match rcv {
                 Ok(res) => res,
                 Err(e) => {
@@ -144,15 +176,108 @@ impl Drop for DnsClient {
     }
 }
 
+/// Ask `server` the question on a socket of its own, bound to a random port, with a random ID.
+/// The replies from another address, with another ID or for another question are dropped, RFC
+/// 5452 9.1. The question is asked again over TCP if the response is truncated.
+pub async fn query_server(
+    server: SocketAddr,
+    question: &Question,
+    timeout: Duration,
+) -> Result<Message> {
+    let id = getrandom::u32()? as u16;
+    let mut msg = Message::query(id, question.clone());
+    msg.set_edns(Some(Edns::new(UDP_BUFFER_SIZE as u16)));
+    let mut res = exchange_udp(server, &msg, timeout).await?;
+    if edns_unsupported(&res) {
+        msg.set_edns(None);
+        res = exchange_udp(server, &msg, timeout).await?;
+    }
+    if res.header.flags.tc() == 1 {
+        res = query_tcp(server, &msg, timeout).await?;
+        if !is_reply(&msg, &res) {
+            return Err(Error::new(ErrorKind::InvalidData, "reply to another query"));
+        }
+    }
+    Ok(res)
+}
+
+async fn exchange_udp(server: SocketAddr, msg: &Message, wait: Duration) -> Result<Message> {
+    let local = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.send_to(&msg.to_udp_packet(None)?, server).await?;
+    let receive = async {
+        let mut buf = [0; UDP_BUFFER_SIZE];
+        loop {
+            let (len, src) = socket.recv_from(&mut buf).await?;
+            if src != server {
+                eprintln!("dropped a datagram from {}, expecting {}", src, server);
+                continue;
+            }
+            match Message::from_bytes(&buf[..len]) {
+                Ok(res) if is_reply(msg, &res) => return Ok(res),
+                Ok(_) => eprintln!("dropped a reply of {} to another query", server),
+                Err(e) => eprintln!("malformed reply of {}: {}", server, e),
+            }
+        }
+    };
+    timeout(wait, receive)
+        .await
+        .map_err(|e| Error::new(ErrorKind::TimedOut, e))?
+}
+
+/// Whether `res` answers `query`, the same ID and question.
+fn is_reply(query: &Message, res: &Message) -> bool {
+    res.header.id == query.header.id
+        && res.header.flags.qr() == 1
+        && res.questions.len() == query.questions.len()
+        && res.questions.iter().zip(&query.questions).all(|(a, b)| {
+            a.qtype == b.qtype && a.qclass == b.qclass && a.qname.eq_ignore_ascii_case(&b.qname)
+        })
+}
+
+/// An upstream without EDNS answers FORMERR without an OPT record, the query is sent again
+/// without ours, RFC 6891 6.2.2.
+fn edns_unsupported(res: &Message) -> bool {
+    res.rcode() == FORMERR && matches!(res.edns(), Ok(None))
+}
+
+/// Send a query on a connection of its own.
+async fn query_tcp(addr: SocketAddr, msg: &Message, wait: Duration) -> Result<Message> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        tcp::write_message(&mut stream, msg, None).await?;
+        tcp::read_message(&mut stream)
+            .await?
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "connection closed"))
+    };
+    let packet = timeout(wait, exchange)
+        .await
+        .map_err(|e| Error::new(ErrorKind::TimedOut, e))??;
+    Message::from_bytes(&packet)
+}
+
 #[cfg(test)]
 mod client_tests {
-    use crate::client::DnsClient;
-    use crate::protocol::Message;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use crate::client::{DnsClient, query_server};
+    use crate::edns::Edns;
+    use crate::protocol::{FORMERR, Message, Question, ResourceRecord};
+    use crate::rdata::RData;
 
     // TODO distinguish "manual" tests from unit tests.
     #[tokio::test]
     async fn test_connect() {
-        let client = DnsClient::connect("8.8.8.8:53").await.unwrap();
+        let addr = "8.8.8.8:53".parse().unwrap();
+        let client = DnsClient::connect(addr, Duration::from_secs(30))
+            .await
+            .unwrap();
         let sample = [
             15, 245, 1, 32, 0, 1, 0, 0, 0, 0, 0, 1, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108,
             101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0,
@@ -161,4 +286,110 @@ mod client_tests {
         let res = client.query(&message).await.unwrap();
         println!("{:?}", res)
     }
+
+    #[tokio::test]
+    async fn advertise_udp_size_upstream() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = DnsClient::connect(upstream.local_addr().unwrap(), Duration::from_secs(2))
+            .await
+            .unwrap();
+        // a query without an OPT record.
+        let sample = [
+            0, 7, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101,
+            3, 99, 111, 109, 0, 0, 1, 0, 1,
+        ];
+        let message = Message::from_bytes(&sample).unwrap();
+        let query = tokio::spawn(async move { client.query(&message).await });
+
+        let mut buf = [0; 512];
+        let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+        let mut received = Message::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(Some(Edns::new(4096)), received.edns().unwrap());
+
+        received.header.flags.set_qr(1);
+        let packet = received.to_udp_packet(None).unwrap();
+        upstream.send_to(&packet, src).await.unwrap();
+        let res = query.await.unwrap().unwrap();
+        assert_eq!(7, res.header.id);
+    }
+
+    #[tokio::test]
+    async fn retry_without_edns_on_formerr() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = DnsClient::connect(upstream.local_addr().unwrap(), Duration::from_secs(2))
+            .await
+            .unwrap();
+        let sample = [
+            0, 7, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101,
+            3, 99, 111, 109, 0, 0, 1, 0, 1,
+        ];
+        let message = Message::from_bytes(&sample).unwrap();
+        let query = tokio::spawn(async move { client.query(&message).await });
+
+        // an upstream which does not know EDNS.
+        let mut buf = [0; 512];
+        for _ in 0..2 {
+            let (len, src) = upstream.recv_from(&mut buf).await.unwrap();
+            let mut received = Message::from_bytes(&buf[..len]).unwrap();
+            received.header.flags.set_qr(1);
+            if received.edns().unwrap().is_some() {
+                received.set_edns(None);
+                received.set_rcode(FORMERR);
+            }
+            let packet = received.to_udp_packet(None).unwrap();
+            upstream.send_to(&packet, src).await.unwrap();
+        }
+        let res = query.await.unwrap().unwrap();
+        assert_eq!(0, res.rcode());
+        assert_eq!(7, res.header.id);
+    }
+
+    #[tokio::test]
+    async fn drop_spoofed_replies() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let question = Question {
+            qname: "www.google.com".into(),
+            qtype: 1,
+            qclass: 1,
+        };
+        let query =
+            tokio::spawn(
+                async move { query_server(addr, &question, Duration::from_secs(2)).await },
+            );
+
+        let mut buf = [0; 512];
+        let (len, src) = server.recv_from(&mut buf).await.unwrap();
+        let mut reply = Message::from_bytes(&buf[..len]).unwrap();
+        reply.header.flags.set_qr(1);
+
+        // from another address, with another ID and for another question.
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let packet = reply.to_udp_packet(None).unwrap();
+        spoofer.send_to(&packet, src).await.unwrap();
+        let packet = reply.to_udp_packet(Some(reply.header.id ^ 1)).unwrap();
+        server.send_to(&packet, src).await.unwrap();
+        let mut other = reply.clone();
+        other.questions[0].qname = "www.example.com".into();
+        server
+            .send_to(&other.to_udp_packet(None).unwrap(), src)
+            .await
+            .unwrap();
+
+        // the genuine reply has the answer.
+        reply.answers.push(ResourceRecord {
+            name: "www.google.com".into(),
+            rtype: 1,
+            rclass: 1,
+            ttl: 60,
+            rdata: RData::A([10, 0, 0, 1].into()),
+        });
+        reply.header.ancount = 1;
+        server
+            .send_to(&reply.to_udp_packet(None).unwrap(), src)
+            .await
+            .unwrap();
+        let res = query.await.unwrap().unwrap();
+        assert_eq!(1, res.answers.len());
+    }
 }
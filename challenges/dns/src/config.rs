@@ -0,0 +1,143 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+/// The configuration of the forwarder. It is read from a TOML file, the command line flags
+/// override the settings of the file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The addresses the server listens on.
+    pub listen: Vec<SocketAddr>,
+    /// The resolvers the queries are forwarded to. They are tried in order, the next one is
+    /// queried when one fails or times out.
+    pub upstreams: Vec<SocketAddr>,
+    /// How long to wait for the answer of an upstream, in milliseconds. In recursive mode, how
+    /// long the resolution of a question may take.
+    pub timeout_ms: u64,
+    /// The maximum number of questions held in the cache.
+    pub cache_size: usize,
+    /// The TTLs of the cached records are raised to at least this many seconds.
+    pub min_ttl: u32,
+    /// The TTLs of the cached records are capped to this many seconds.
+    pub max_ttl: u32,
+    /// Resolve the queries iteratively from the root servers instead of forwarding them to the
+    /// upstreams.
+    pub recursive: bool,
+    /// The root servers the recursive resolution starts from. The name servers learned from the
+    /// referrals are queried on port 53.
+    pub root_hints: Vec<SocketAddr>,
+}
+
+/// The IPv4 addresses of the root servers a to m, https://www.iana.org/domains/root/servers.
+const ROOT_SERVERS: [[u8; 4]; 13] = [
+    [198, 41, 0, 4],
+    [170, 247, 170, 2],
+    [192, 33, 4, 12],
+    [199, 7, 91, 13],
+    [192, 203, 230, 10],
+    [192, 5, 5, 241],
+    [192, 112, 36, 4],
+    [198, 97, 190, 53],
+    [192, 36, 148, 17],
+    [192, 58, 128, 30],
+    [193, 0, 14, 129],
+    [199, 7, 83, 42],
+    [202, 12, 27, 33],
+];
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 1053))],
+            upstreams: vec![SocketAddr::from(([8, 8, 8, 8], 53))],
+            timeout_ms: 30_000,
+            cache_size: 10_000,
+            min_ttl: 0,
+            max_ttl: 1800, // 30 minutes
+            recursive: false,
+            root_hints: ROOT_SERVERS
+                .iter()
+                .map(|ip| SocketAddr::from((*ip, 53)))
+                .collect(),
+        }
+    }
+}
+
+impl Config {
+    pub fn read(path: &Path) -> Result<Config> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: &str| Err(Error::new(ErrorKind::InvalidInput, msg));
+        if self.listen.is_empty() {
+            return invalid("at least one listen address is required");
+        }
+        if self.recursive && self.root_hints.is_empty() {
+            return invalid("at least one root hint is required");
+        }
+        if !self.recursive && self.upstreams.is_empty() {
+            return invalid("at least one upstream is required");
+        }
+        if self.cache_size == 0 {
+            return invalid("the cache size must be at least 1");
+        }
+        if self.min_ttl > self.max_ttl {
+            return invalid("the min TTL must not exceed the max TTL");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+            listen = ["127.0.0.1:5353", "[::1]:5353"]
+            upstreams = ["1.1.1.1:53"]
+            max_ttl = 300
+            "#,
+        )
+        .unwrap();
+        assert_eq!(2, config.listen.len());
+        assert_eq!(
+            "1.1.1.1:53".parse::<SocketAddr>().unwrap(),
+            config.upstreams[0]
+        );
+        assert_eq!(300, config.max_ttl);
+        assert_eq!(Config::default().timeout_ms, config.timeout_ms);
+        assert!(config.validate().is_ok());
+
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+        let config = Config {
+            min_ttl: 60,
+            max_ttl: 30,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str(
+            r#"
+            recursive = true
+            upstreams = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(13, config.root_hints.len());
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,127 @@
+use super::protocol::ResourceRecord;
+use super::rdata::{EdnsOption, OPT, RData};
+
+/// The EDNS version we implement, RFC 6891 6.1.3.
+pub const EDNS_VERSION: u8 = 0;
+
+/// The extended RCODE of a response to a query with an unsupported EDNS version.
+pub const BADVERS: u16 = 16;
+
+/// The EDNS(0) parameters carried by an OPT pseudo-record, RFC 6891 6.1. The class of the record
+/// holds the UDP payload size and its TTL the extended RCODE, the version and the flags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    /// The largest UDP payload the sender can receive.
+    pub udp_size: u16,
+    /// The upper 8 bits of the 12 bit RCODE, the header holds the lower 4.
+    pub ext_rcode: u8,
+    pub version: u8,
+    /// DO, the sender can handle DNSSEC records, RFC 3225.
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(udp_size: u16) -> Edns {
+        Edns {
+            udp_size,
+            ext_rcode: 0,
+            version: EDNS_VERSION,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// The parameters of an OPT record, `None` for the other records.
+    pub fn from_record(rr: &ResourceRecord) -> Option<Edns> {
+        if rr.rtype != OPT {
+            return None;
+        }
+        let [ext_rcode, version, flags, _] = rr.ttl.to_be_bytes();
+        let options = match &rr.rdata {
+            RData::Opt(options) => options.clone(),
+            _ => Vec::new(),
+        };
+        Some(Edns {
+            udp_size: rr.rclass,
+            ext_rcode,
+            version,
+            dnssec_ok: flags & 0x80 != 0,
+            options,
+        })
+    }
+
+    pub fn to_record(&self) -> ResourceRecord {
+        let flags = if self.dnssec_ok { 0x80 } else { 0 };
+        ResourceRecord {
+            name: String::new(),
+            rtype: OPT,
+            rclass: self.udp_size,
+            ttl: u32::from_be_bytes([self.ext_rcode, self.version, flags, 0]),
+            rdata: RData::Opt(self.options.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Message;
+
+    #[test]
+    fn edns_roundtrip() {
+        let sample = [
+            112, 27, 1, 32, 0, 1, 0, 0, 0, 0, 0, 1, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108,
+            101, 3, 99, 111, 109, 0, 0, 15, 0, 3, 0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let mut message = Message::from_bytes(&sample).unwrap();
+        assert_eq!(Some(Edns::new(4096)), message.edns().unwrap());
+
+        let edns = Edns {
+            udp_size: 1232,
+            ext_rcode: 1,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![EdnsOption {
+                code: 10,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        };
+        message.set_edns(Some(edns.clone()));
+        let packet = message.to_udp_packet(None).unwrap();
+        let parsed = Message::from_bytes(&packet).unwrap();
+        assert_eq!(1, parsed.header.arcount);
+        assert_eq!(Some(edns), parsed.edns().unwrap());
+        // the DO bit is the top bit of the flags.
+        assert_eq!(0x0100_8000, parsed.additionals[0].ttl);
+        assert_eq!(BADVERS, parsed.rcode());
+
+        message.set_edns(None);
+        assert_eq!(0, message.header.arcount);
+        assert_eq!(None, message.edns().unwrap());
+    }
+
+    #[test]
+    fn extended_rcode() {
+        let sample = [0, 1, 129, 128, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut message = Message::from_bytes(&sample).unwrap();
+        message.set_edns(Some(Edns::new(4096)));
+        message.set_rcode(BADVERS);
+        assert_eq!(0, message.header.flags.rcode());
+        assert_eq!(1, message.edns().unwrap().unwrap().ext_rcode);
+        assert_eq!(BADVERS, message.rcode());
+
+        message.set_rcode(3);
+        assert_eq!(3, message.header.flags.rcode());
+        assert_eq!(3, message.rcode());
+    }
+
+    #[test]
+    fn multiple_opt_records() {
+        let sample = [0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut message = Message::from_bytes(&sample).unwrap();
+        message.additionals = vec![Edns::new(512).to_record(), Edns::new(4096).to_record()];
+        message.header.arcount = 2;
+        assert!(message.edns().is_err());
+    }
+}
@@ -1,12 +1,93 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::Parser;
+use config::Config;
 use server::Processor;
 
 mod cache;
 mod client;
+mod config;
+mod edns;
 mod protocol;
+mod rdata;
+mod resolver;
 mod server;
+mod tcp;
+
+/// A caching DNS forwarder and recursive resolver.
+#[derive(Parser, Debug)]
+#[clap(name = "dns")]
+struct Cli {
+    /// A TOML file with the configuration, the flags override its settings.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// An address to listen on, may be repeated. Defaults to 127.0.0.1:1053.
+    #[clap(long)]
+    listen: Vec<SocketAddr>,
+
+    /// An upstream resolver, may be repeated. They are tried in order. Defaults to 8.8.8.8:53.
+    #[clap(long = "upstream")]
+    upstreams: Vec<SocketAddr>,
+
+    /// How long to wait for an upstream, in milliseconds.
+    #[clap(long)]
+    timeout_ms: Option<u64>,
+
+    /// The maximum number of questions held in the cache.
+    #[clap(long)]
+    cache_size: Option<usize>,
+
+    /// The minimum TTL in seconds of the cached records.
+    #[clap(long)]
+    min_ttl: Option<u32>,
+
+    /// The maximum TTL in seconds of the cached records.
+    #[clap(long)]
+    max_ttl: Option<u32>,
+
+    /// Resolve the queries from the root servers instead of forwarding them to the upstreams.
+    #[clap(long)]
+    recursive: bool,
+
+    /// A root server to start the recursive resolution from, may be repeated. Defaults to the
+    /// IPv4 addresses of the root servers.
+    #[clap(long = "root-hint")]
+    root_hints: Vec<SocketAddr>,
+}
+
+impl Cli {
+    /// The configuration of the file, or the defaults, with the flags applied on top.
+    fn config(self) -> std::io::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::read(path)?,
+            None => Config::default(),
+        };
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+        if !self.upstreams.is_empty() {
+            config.upstreams = self.upstreams;
+        }
+        config.timeout_ms = self.timeout_ms.unwrap_or(config.timeout_ms);
+        config.cache_size = self.cache_size.unwrap_or(config.cache_size);
+        config.min_ttl = self.min_ttl.unwrap_or(config.min_ttl);
+        config.max_ttl = self.max_ttl.unwrap_or(config.max_ttl);
+        config.recursive |= self.recursive;
+        if !self.root_hints.is_empty() {
+            config.root_hints = self.root_hints;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
 
 #[tokio::main]
-async fn main() {
-    let processor = Processor::build().await.expect("could not startup");
+async fn main() -> std::io::Result<()> {
+    let config = Cli::parse().config()?;
+    let processor = Processor::build(&config).await?;
+    println!("listening on {:?}", processor.local_addrs()?);
     processor.run_loop().await;
+    Ok(())
 }
@@ -4,6 +4,31 @@ use std::io::Result;
 use std::io::{Cursor, Read, Seek, Write};
 use std::{fmt, io};
 
+use super::edns::Edns;
+use super::rdata::{OPT, RData};
+
+/// The largest UDP payload a client accepts when it does not advertise a size, RFC 1035 4.2.1.
+pub const MAX_UDP_SIZE: usize = 512;
+
+/// The longest label of a name, RFC 1035 2.3.4.
+const MAX_LABEL_LEN: usize = 63;
+
+/// The pointers followed to read a name. A name has at most 127 labels, but genuine messages
+/// only point to a few earlier names.
+const MAX_POINTERS: usize = 16;
+
+/// A compression pointer holds a 14 bit offset, names written past it cannot be pointed to.
+const MAX_POINTER_OFFSET: usize = 0x4000;
+
+/// The longest message, its length has to fit the two bytes prefix of TCP, RFC 1035 4.2.2.
+const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
+
+// Response codes, RFC 1035 4.1.1.
+pub const NOERROR: u16 = 0;
+pub const FORMERR: u16 = 1;
+pub const SERVFAIL: u16 = 2;
+pub const NXDOMAIN: u16 = 3;
+
 #[derive(Clone)]
 pub struct Flags([u8; 2]);
 
@@ -41,14 +66,26 @@ impl Flags {
     pub fn tc(&self) -> u8 {
         (self.0[0] >> 1) & 0x01
     }
+    pub fn set_tc(&mut self, tc: u8) {
+        assert!(tc < 2, "tc must be 0 or 1");
+        self.0[0] = (self.0[0] & 0xFD) | (tc << 1);
+    }
     /// RD, recursion desired. If 0, the query is an iterative query. If 1, the query is recursive.
     pub fn rd(&self) -> u8 {
         self.0[0] & 0x01
     }
+    pub fn set_rd(&mut self, rd: u8) {
+        assert!(rd < 2, "rd must be 0 or 1");
+        self.0[0] = (self.0[0] & 0xFE) | rd;
+    }
     /// RA, recursion available. Set on response if the server supports recursion.
     pub fn ra(&self) -> u8 {
         self.0[1] >> 7
     }
+    pub fn set_ra(&mut self, ra: u8) {
+        assert!(ra < 2, "ra must be 0 or 1");
+        self.0[1] = (self.0[1] & 0x7F) | (ra << 7);
+    }
     /// Z. Reserved for future use, must be set to 0 on all queries and responses.
     pub fn z(&self) -> u8 {
         (self.0[1] >> 6) & 0x01
@@ -161,8 +198,7 @@ pub struct ResourceRecord {
     pub rtype: u16,
     pub rclass: u16,
     pub ttl: u32,
-    pub rdlength: u16,
-    pub rdata: Vec<u8>,
+    pub rdata: RData,
 }
 
 impl ResourceRecord {
@@ -172,14 +208,12 @@ impl ResourceRecord {
         let rclass = read_u16(r)?;
         let ttl = read_u32(r)?;
         let rdlength = read_u16(r)?;
-        let mut rdata = vec![0; rdlength as usize];
-        r.read_exact(&mut rdata)?;
+        let rdata = RData::read(r, rtype, rdlength)?;
         Ok(ResourceRecord {
             name,
             rtype,
             rclass,
             ttl,
-            rdlength,
             rdata,
         })
     }
@@ -197,9 +231,7 @@ impl ResourceRecord {
         writer.write_all(&self.rtype.to_be_bytes())?;
         writer.write_all(&self.rclass.to_be_bytes())?;
         writer.write_all(&self.ttl.to_be_bytes())?;
-        writer.write_all(&self.rdlength.to_be_bytes())?;
-        writer.write_all(&self.rdata)?;
-        Ok(())
+        writer.write_rdata(&self.rdata)
     }
 }
 
@@ -213,14 +245,38 @@ pub struct Message {
 }
 
 impl Message {
+    /// A query for `question`, without recursion desired.
+    pub fn query(id: u16, question: Question) -> Message {
+        Message {
+            header: Header {
+                id,
+                flags: Flags::from_bytes(0, 0),
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![question],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
     pub fn from_bytes(b: &[u8]) -> Result<Message> {
+        if b.len() < 12 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message shorter than a header",
+            ));
+        }
         let header = Header::from_bytes(b);
         let mut cur = Cursor::new(b);
         cur.set_position(12);
 
         let questions = match header.qdcount {
             0 => Vec::new(),
-            1 => vec![Question::read(&mut cur).expect("could not parse question")],
+            1 => vec![Question::read(&mut cur)?],
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
@@ -242,12 +298,148 @@ impl Message {
         })
     }
 
+    /// A FORMERR response to a query which could not be parsed, `None` if not even its header is
+    /// complete. Only the ID and the opcode of the query are kept, RFC 1035 4.1.1.
+    pub fn format_error(b: &[u8]) -> Option<Message> {
+        if b.len() < 12 {
+            return None;
+        }
+        let query = Header::from_bytes(b);
+        let mut flags = Flags::from_bytes(b[2] & 0x78, 0);
+        flags.set_qr(1);
+        flags.set_rcode(FORMERR as u8);
+        Some(Message {
+            header: Header {
+                id: query.id,
+                flags,
+                qdcount: 0,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        })
+    }
+
     pub fn to_udp_packet(&self, alt_id: Option<u16>) -> Result<Vec<u8>> {
         let mut writer = MessageWriter::new(Vec::new());
         self.write(&mut writer, alt_id)?;
         Ok(writer.underlying)
     }
 
+    /// The EDNS parameters of the OPT record, an error if there is more than one, RFC 6891 6.1.1.
+    pub fn edns(&self) -> Result<Option<Edns>> {
+        let mut opts = self.additionals.iter().filter_map(Edns::from_record);
+        let edns = opts.next();
+        if opts.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "more than one OPT record",
+            ));
+        }
+        Ok(edns)
+    }
+
+    /// Replace the OPT record, or remove it with `None`.
+    pub fn set_edns(&mut self, edns: Option<Edns>) {
+        self.additionals.retain(|rr| rr.rtype != OPT);
+        self.additionals.extend(edns.map(|edns| edns.to_record()));
+        self.header.arcount = self.additionals.len() as u16;
+    }
+
+    /// The RCODE, extended by the OPT record when there is one.
+    pub fn rcode(&self) -> u16 {
+        let ext_rcode = self.edns().ok().flatten().map_or(0, |edns| edns.ext_rcode);
+        ((ext_rcode as u16) << 4) | self.header.flags.rcode() as u16
+    }
+
+    /// Set the RCODE, an OPT record is added for the codes over 15 if there is none.
+    pub fn set_rcode(&mut self, rcode: u16) {
+        self.header.flags.set_rcode((rcode & 0x0F) as u8);
+        let ext_rcode = (rcode >> 4) as u8;
+        match self.edns().ok().flatten() {
+            Some(edns) => self.set_edns(Some(Edns { ext_rcode, ..edns })),
+            None if ext_rcode > 0 => self.set_edns(Some(Edns {
+                ext_rcode,
+                ..Edns::new(MAX_UDP_SIZE as u16)
+            })),
+            None => {}
+        }
+    }
+
+    /// The largest UDP response the sender of this query accepts, the payload size of its OPT
+    /// record, RFC 6891 6.2.5.
+    pub fn max_udp_size(&self) -> usize {
+        match self.edns() {
+            Ok(Some(edns)) => (edns.udp_size as usize).max(MAX_UDP_SIZE),
+            _ => MAX_UDP_SIZE,
+        }
+    }
+
+    /// Encode the message in at most `max_size` bytes. The records which do not fit are left out
+    /// and the counts of the header adjusted. TC is set when answer or authority records are left
+    /// out, but not for additional records only, RFC 2181 9. The OPT record is always kept so the
+    /// client still learns our payload size, RFC 6891 7.
+    pub fn to_truncated_udp_packet(&self, alt_id: Option<u16>, max_size: usize) -> Result<Vec<u8>> {
+        let packet = self.to_udp_packet(alt_id)?;
+        if packet.len() <= max_size {
+            return Ok(packet);
+        }
+
+        let mut writer = MessageWriter::new(Vec::new());
+        self.header.write(&mut writer, alt_id)?;
+        for question in &self.questions {
+            question.write(&mut writer)?;
+        }
+        let opts: Vec<_> = self
+            .additionals
+            .iter()
+            .filter(|rr| rr.rtype == OPT)
+            .collect();
+        let mut reserved = 0;
+        for opt in &opts {
+            let mut opt_writer = MessageWriter::new(Vec::new());
+            opt.write(&mut opt_writer)?;
+            reserved += opt_writer.underlying.len();
+        }
+        let limit = max_size.saturating_sub(reserved);
+        let sections = [&self.answers, &self.authorities, &self.additionals];
+        let mut counts = [0u16; 3];
+        let mut truncated = false;
+        'sections: for (i, section) in sections.into_iter().enumerate() {
+            for rr in section.iter().filter(|rr| rr.rtype != OPT) {
+                let pos = writer.pos;
+                rr.write(&mut writer)?;
+                if writer.underlying.len() > limit {
+                    writer.truncate(pos);
+                    // the answer and authority sections have to be complete.
+                    truncated = i < 2;
+                    break 'sections;
+                }
+                counts[i] += 1;
+            }
+        }
+        for opt in opts {
+            opt.write(&mut writer)?;
+            counts[2] += 1;
+        }
+
+        let mut header = self.header.clone();
+        header.ancount = counts[0];
+        header.nscount = counts[1];
+        header.arcount = counts[2];
+        if truncated {
+            header.flags.set_tc(1);
+        }
+        let mut packet = writer.underlying;
+        let mut header_writer = MessageWriter::new(&mut packet[..12]);
+        header.write(&mut header_writer, alt_id)?;
+        Ok(packet)
+    }
+
     fn write<W: MsgWrite>(&self, writer: &mut W, alt_id: Option<u16>) -> Result<()> {
         self.header.write(writer, alt_id)?;
         // TODO make conditional a) it being a query (?) and b) if a question is present ? or
@@ -268,16 +460,21 @@ impl Message {
     }
 }
 
-trait MsgWrite {
+pub trait MsgWrite {
     fn write_name(&mut self, name: &str) -> Result<()>;
+    /// Write a name without pointers to earlier names, for the fields which must not be
+    /// compressed.
+    fn write_name_uncompressed(&mut self, name: &str) -> Result<()>;
+    /// Write the RDATA prefixed with its length.
+    fn write_rdata(&mut self, rdata: &RData) -> Result<()>;
     fn write_all(&mut self, buf: &[u8]) -> Result<()>;
 }
 
 #[derive(Debug)]
 struct MessageWriter<W: Write> {
     underlying: W,
-    label_tally: HashMap<String, u16>,
-    pos: u16,
+    label_tally: HashMap<String, usize>,
+    pos: usize,
 }
 
 impl<W: Write> MessageWriter<W> {
@@ -290,28 +487,51 @@ impl<W: Write> MessageWriter<W> {
     }
 }
 
+impl<W: Write> MessageWriter<W> {
+    /// Write a label prefixed with its length.
+    fn write_label(&mut self, label: &str) -> Result<()> {
+        if label.len() > MAX_LABEL_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "label longer than 63 bytes",
+            ));
+        }
+        self.write_all(&[label.len() as u8])?;
+        self.write_all(label.as_bytes())
+    }
+}
+
+impl MessageWriter<Vec<u8>> {
+    /// Drop what was written from `pos` on, including the names it introduced.
+    fn truncate(&mut self, pos: usize) {
+        self.underlying.truncate(pos);
+        self.label_tally.retain(|_, at| *at < pos);
+        self.pos = pos;
+    }
+}
+
 impl<W: Write> MsgWrite for MessageWriter<W> {
     fn write_name(&mut self, name: &str) -> Result<()> {
         if name.is_empty() {
             self.write_all(&[0])
         } else {
             match self.label_tally.get(name) {
-                Some(pos) => {
+                Some(&pos) => {
                     // write pointer and terminate
-                    self.write_all(&(pos | 0xC000).to_be_bytes())
+                    self.write_all(&(pos as u16 | 0xC000).to_be_bytes())
                 }
                 None => {
-                    self.label_tally.insert(name.to_string(), self.pos);
+                    if self.pos < MAX_POINTER_OFFSET {
+                        self.label_tally.insert(name.to_string(), self.pos);
+                    }
 
                     match name.split_once('.') {
                         None => {
-                            self.write_all(&[name.len() as u8])?;
-                            self.write_all(name.as_bytes())?;
+                            self.write_label(name)?;
                             self.write_all(&[0])
                         }
                         Some((left, rest)) => {
-                            self.write_all(&[left.len() as u8])?;
-                            self.write_all(left.as_bytes())?;
+                            self.write_label(left)?;
                             // TODO lets get rid of the recursion -- rust does not support tailrec.
                             self.write_name(rest)
                         }
@@ -321,28 +541,61 @@ impl<W: Write> MsgWrite for MessageWriter<W> {
         }
     }
 
+    fn write_name_uncompressed(&mut self, name: &str) -> Result<()> {
+        for label in name.split('.').filter(|label| !label.is_empty()) {
+            self.write_label(label)?;
+        }
+        self.write_all(&[0])
+    }
+
+    fn write_rdata(&mut self, rdata: &RData) -> Result<()> {
+        // the length comes first, so the RDATA is written aside. Its names are compressed against
+        // the message, as if they were written in place after the length.
+        let mut rdata_writer = MessageWriter {
+            underlying: Vec::new(),
+            label_tally: std::mem::take(&mut self.label_tally),
+            pos: self.pos + 2,
+        };
+        let written = rdata.write(&mut rdata_writer);
+        self.label_tally = rdata_writer.label_tally;
+        written?;
+        let rdata = rdata_writer.underlying;
+        self.write_all(&(rdata.len() as u16).to_be_bytes())?;
+        self.write_all(&rdata)
+    }
+
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        if self.pos + buf.len() > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message longer than 65535 bytes",
+            ));
+        }
         self.underlying.write_all(buf)?;
-        self.pos += buf.len() as u16;
+        self.pos += buf.len();
         Ok(())
     }
 }
 
-fn read_u16<R: Read>(r: &mut R) -> Result<u16> {
+pub fn read_u16<R: Read>(r: &mut R) -> Result<u16> {
     let mut buf = [0; 2];
     r.read_exact(&mut buf)?;
     Ok(u16::from_be_bytes(buf))
 }
 
-fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
+pub fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
     let mut buf = [0; 4];
     r.read_exact(&mut buf)?;
     Ok(u32::from_be_bytes(buf))
 }
 
-fn read_labels_to_str<R: Read + Seek>(r: &mut R) -> Result<String> {
+pub fn read_labels_to_str<R: Read + Seek>(r: &mut R) -> Result<String> {
     let mut qname = String::new();
+    // where the name ends in the message, after its first pointer.
+    let mut end = None;
+    let mut hops = 0;
     loop {
+        let pos = r.stream_position()?;
         match LabelKind::read(r)? {
             LabelKind::Absent => break,
             LabelKind::Data(len) => {
@@ -351,19 +604,28 @@ fn read_labels_to_str<R: Read + Seek>(r: &mut R) -> Result<String> {
                 }
                 let mut label = vec![0; len];
                 r.read_exact(&mut label)?;
-                qname.push_str(std::str::from_utf8(&label).expect("invalid utf8 label"));
+                let label = std::str::from_utf8(&label)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "label not UTF-8"))?;
+                qname.push_str(label);
             }
             LabelKind::Pointer(offset) => {
-                let pos = r.stream_position()?;
+                // a pointer goes back to a prior occurrence of the name, RFC 1035 4.1.4. The hops
+                // are capped as backward pointers can still loop through the labels between them.
+                hops += 1;
+                if offset as u64 >= pos || hops > MAX_POINTERS {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid name pointer",
+                    ));
+                }
+                end.get_or_insert(r.stream_position()?);
                 r.seek(io::SeekFrom::Start(offset as u64))?;
-                // TODO 1: is the as_str bad ? Can I switch the String used in qname to &str ?
-                // TODO 2: is the recursion here bad ?
-                qname.push_str(read_labels_to_str(r)?.as_str());
-                r.seek(io::SeekFrom::Start(pos))?;
-                break;
             }
         }
     }
+    if let Some(end) = end {
+        r.seek(io::SeekFrom::Start(end))?;
+    }
     Ok(qname)
 }
 
@@ -418,6 +680,74 @@ mod tests {
         assert_eq!(sample, message.to_udp_packet(None).unwrap().as_slice());
     }
 
+    fn a_record(name: &str, ip: u8) -> ResourceRecord {
+        ResourceRecord {
+            name: name.to_string(),
+            rtype: 1,
+            rclass: 1,
+            ttl: 60,
+            rdata: RData::A(std::net::Ipv4Addr::new(10, 0, 0, ip)),
+        }
+    }
+
+    #[test]
+    fn truncated_udp_packet() {
+        let sample = [
+            112, 27, 1, 32, 0, 1, 0, 0, 0, 0, 0, 1, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108,
+            101, 3, 99, 111, 109, 0, 0, 15, 0, 3, 0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let mut message = Message::from_bytes(&sample).unwrap();
+        assert_eq!(4096, message.max_udp_size());
+        message.header.flags.set_qr(1);
+        message.answers = (0..100).map(|i| a_record("www.google.com", i)).collect();
+        message.header.ancount = 100;
+
+        // the answers are cut short, the OPT record is kept.
+        let packet = message.to_truncated_udp_packet(None, 512).unwrap();
+        assert!(packet.len() <= 512);
+        let truncated = Message::from_bytes(&packet).unwrap();
+        assert_eq!(1, truncated.header.flags.tc());
+        assert!(!truncated.answers.is_empty() && truncated.answers.len() < 100);
+        assert_eq!(truncated.answers.len() as u16, truncated.header.ancount);
+        assert_eq!(OPT, truncated.additionals[0].rtype);
+
+        // leaving out additional records does not set TC.
+        message.answers.truncate(1);
+        message.header.ancount = 1;
+        message.additionals = (0..100)
+            .map(|i| a_record(&format!("ns{}.google.com", i), i))
+            .chain(message.additionals.clone())
+            .collect();
+        message.header.arcount = 101;
+        let packet = message.to_truncated_udp_packet(None, 512).unwrap();
+        assert!(packet.len() <= 512);
+        let truncated = Message::from_bytes(&packet).unwrap();
+        assert_eq!(0, truncated.header.flags.tc());
+        assert_eq!(1, truncated.answers.len());
+        assert_eq!(OPT, truncated.additionals.last().unwrap().rtype);
+
+        // a message which fits is not changed.
+        let small = Message::from_bytes(&sample).unwrap();
+        assert_eq!(
+            sample,
+            small.to_truncated_udp_packet(None, 512).unwrap().as_slice()
+        );
+    }
+
+    #[test]
+    fn truncated_question() {
+        // the name of the question stops short.
+        let sample = [112, 27, 1, 32, 0, 1, 0, 0, 0, 0, 0, 0, 3, 119, 119];
+        assert!(Message::from_bytes(&sample).is_err());
+
+        let res = Message::format_error(&sample).unwrap();
+        assert_eq!(112 * 256 + 27, res.header.id);
+        assert_eq!(1, res.header.flags.qr());
+        assert_eq!(FORMERR, res.rcode());
+        assert_eq!(0, res.header.qdcount);
+        assert!(Message::format_error(&sample[..11]).is_none());
+    }
+
     #[test]
     fn labelkind_parsing() -> Result<()> {
         assert_eq!(LabelKind::read(&mut Cursor::new(&[0]))?, LabelKind::Absent);
@@ -429,6 +759,59 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn extended_rcode() {
+        let sample = [112, 27, 1, 32, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut message = Message::from_bytes(&sample).unwrap();
+        message.set_rcode(FORMERR);
+        assert_eq!(None, message.edns().unwrap());
+        assert_eq!(FORMERR, message.rcode());
+
+        // the OPT record carrying the upper bits is added.
+        message.set_rcode(16);
+        assert_eq!(16, message.rcode());
+        assert_eq!(0, message.header.flags.rcode());
+        assert_eq!(1, message.edns().unwrap().unwrap().ext_rcode);
+        assert_eq!(1, message.header.arcount);
+    }
+
+    #[test]
+    fn name_pointers() {
+        // the second name points back to the first one, the reading goes on after the pointer.
+        let mut cur = Cursor::new([1, b'a', 0, 1, b'b', 0xC0, 0, 7]);
+        assert_eq!("a", read_labels_to_str(&mut cur).unwrap());
+        assert_eq!("b.a", read_labels_to_str(&mut cur).unwrap());
+        assert_eq!(7, cur.get_ref()[cur.position() as usize]);
+
+        // a pointer to itself, and one forward.
+        for sample in [&[0xC0, 0][..], &[0xC0, 2, 0]] {
+            let err = read_labels_to_str(&mut Cursor::new(sample)).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+
+        // a loop of backward pointers through a label.
+        let err = read_labels_to_str(&mut Cursor::new([1, b'a', 0xC0, 0])).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn non_utf8_label() {
+        let err = read_labels_to_str(&mut Cursor::new([2, 0xC3, 0x28, 0])).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn label_too_long() {
+        let name = format!("{}.com", "a".repeat(64));
+        let mut writer = MessageWriter::new(Vec::new());
+        assert!(writer.write_name(&name).is_err());
+        assert!(writer.write_name_uncompressed(&name).is_err());
+
+        let name = format!("{}.com", "a".repeat(63));
+        assert!(writer.write_name(&name).is_ok());
+        assert!(writer.write_name_uncompressed(&name).is_ok());
+    }
+
     #[test]
     fn set_qr() {
         let sample = [
@@ -448,4 +831,26 @@ mod tests {
         tracker.write_name("google.com").unwrap();
         println!("{:?}", tracker.underlying); // TODO asserts
     }
+
+    #[test]
+    fn no_pointers_past_14_bits() {
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.write_all(&vec![0; MAX_POINTER_OFFSET]).unwrap();
+        writer.write_name("example.com").unwrap();
+        writer.write_name("example.com").unwrap();
+        // the name is written out twice, a pointer to it would wrap around to the start.
+        let name = b"\x07example\x03com\x00";
+        assert_eq!(
+            [name.as_slice(), name.as_slice()].concat(),
+            writer.underlying[MAX_POINTER_OFFSET..]
+        );
+    }
+
+    #[test]
+    fn message_too_long() {
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.write_all(&vec![0; MAX_MESSAGE_LEN - 1]).unwrap();
+        let err = writer.write_name("example.com").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
 }
@@ -0,0 +1,382 @@
+use std::io::{self, Read, Result, Seek};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use super::protocol::{MsgWrite, read_labels_to_str, read_u16, read_u32};
+
+// The record types with a typed RDATA, RFC 1035 3.2.2 and the RFCs listed next to the variants.
+pub const A: u16 = 1;
+pub const NS: u16 = 2;
+pub const CNAME: u16 = 5;
+pub const SOA: u16 = 6;
+pub const PTR: u16 = 12;
+pub const MX: u16 = 15;
+pub const TXT: u16 = 16;
+pub const AAAA: u16 = 28;
+pub const SRV: u16 = 33;
+pub const OPT: u16 = 41;
+pub const CAA: u16 = 257;
+
+/// The start of authority of a zone, RFC 1035 3.3.13.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Soa {
+    /// The primary name server of the zone.
+    pub mname: String,
+    /// The mailbox of the person responsible for the zone, the first label is the local part.
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    /// The TTL of negative answers from the zone, RFC 2308 4.
+    pub minimum: u32,
+}
+
+/// An option of an OPT record, RFC 6891 6.1.2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The RDATA of a resource record. The names are decompressed when read, and compressed again
+/// against the message they are written to, except where the RFCs forbid it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    /// RFC 3596.
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Ns(String),
+    Ptr(String),
+    Mx {
+        preference: u16,
+        exchange: String,
+    },
+    Soa(Soa),
+    /// One or more character strings of up to 255 bytes each.
+    Txt(Vec<Vec<u8>>),
+    /// RFC 2782, the target is never compressed.
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// RFC 8659.
+    Caa {
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+    },
+    /// The EDNS pseudo-record, RFC 6891.
+    Opt(Vec<EdnsOption>),
+    /// The RDATA of any other type, kept as is. It must not contain compressed names, RFC 3597 4.
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    /// Read `rdlength` bytes of RDATA of type `rtype`. The reader is positioned within the whole
+    /// message, compressed names may point anywhere before the record.
+    pub fn read<R: Read + Seek>(r: &mut R, rtype: u16, rdlength: u16) -> Result<RData> {
+        let end = r.stream_position()? + rdlength as u64;
+        let remaining = |r: &mut R| -> Result<usize> {
+            let pos = r.stream_position()?;
+            end.checked_sub(pos)
+                .map(|n| n as usize)
+                .ok_or_else(|| invalid("RDATA longer than its length"))
+        };
+        let rdata = match rtype {
+            A => {
+                let mut octets = [0; 4];
+                r.read_exact(&mut octets)?;
+                RData::A(Ipv4Addr::from(octets))
+            }
+            AAAA => {
+                let mut octets = [0; 16];
+                r.read_exact(&mut octets)?;
+                RData::Aaaa(Ipv6Addr::from(octets))
+            }
+            CNAME => RData::Cname(read_labels_to_str(r)?),
+            NS => RData::Ns(read_labels_to_str(r)?),
+            PTR => RData::Ptr(read_labels_to_str(r)?),
+            MX => RData::Mx {
+                preference: read_u16(r)?,
+                exchange: read_labels_to_str(r)?,
+            },
+            SOA => RData::Soa(Soa {
+                mname: read_labels_to_str(r)?,
+                rname: read_labels_to_str(r)?,
+                serial: read_u32(r)?,
+                refresh: read_u32(r)?,
+                retry: read_u32(r)?,
+                expire: read_u32(r)?,
+                minimum: read_u32(r)?,
+            }),
+            TXT => {
+                let mut strings = Vec::new();
+                while remaining(r)? > 0 {
+                    strings.push(read_character_string(r)?);
+                }
+                RData::Txt(strings)
+            }
+            SRV => RData::Srv {
+                priority: read_u16(r)?,
+                weight: read_u16(r)?,
+                port: read_u16(r)?,
+                target: read_labels_to_str(r)?,
+            },
+            CAA => {
+                let mut flags = [0];
+                r.read_exact(&mut flags)?;
+                let tag = String::from_utf8(read_character_string(r)?)
+                    .map_err(|_| invalid("CAA tag is not UTF-8"))?;
+                let mut value = vec![0; remaining(r)?];
+                r.read_exact(&mut value)?;
+                RData::Caa {
+                    flags: flags[0],
+                    tag,
+                    value,
+                }
+            }
+            OPT => {
+                let mut options = Vec::new();
+                while remaining(r)? > 0 {
+                    let code = read_u16(r)?;
+                    let mut data = vec![0; read_u16(r)? as usize];
+                    r.read_exact(&mut data)?;
+                    options.push(EdnsOption { code, data });
+                }
+                RData::Opt(options)
+            }
+            _ => {
+                let mut data = vec![0; rdlength as usize];
+                r.read_exact(&mut data)?;
+                RData::Unknown(data)
+            }
+        };
+        if remaining(r)? != 0 {
+            return Err(invalid("RDATA shorter than its length"));
+        }
+        Ok(rdata)
+    }
+
+    /// Write the RDATA, without its length.
+    pub fn write<W: MsgWrite>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            RData::A(ip) => writer.write_all(&ip.octets()),
+            RData::Aaaa(ip) => writer.write_all(&ip.octets()),
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => writer.write_name(name),
+            RData::Mx {
+                preference,
+                exchange,
+            } => {
+                writer.write_all(&preference.to_be_bytes())?;
+                writer.write_name(exchange)
+            }
+            RData::Soa(soa) => {
+                writer.write_name(&soa.mname)?;
+                writer.write_name(&soa.rname)?;
+                for n in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    writer.write_all(&n.to_be_bytes())?;
+                }
+                Ok(())
+            }
+            RData::Txt(strings) => {
+                for s in strings {
+                    write_character_string(writer, s)?;
+                }
+                Ok(())
+            }
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                writer.write_all(&priority.to_be_bytes())?;
+                writer.write_all(&weight.to_be_bytes())?;
+                writer.write_all(&port.to_be_bytes())?;
+                writer.write_name_uncompressed(target)
+            }
+            RData::Caa { flags, tag, value } => {
+                writer.write_all(&[*flags])?;
+                write_character_string(writer, tag.as_bytes())?;
+                writer.write_all(value)
+            }
+            RData::Opt(options) => {
+                for option in options {
+                    writer.write_all(&option.code.to_be_bytes())?;
+                    writer.write_all(&(option.data.len() as u16).to_be_bytes())?;
+                    writer.write_all(&option.data)?;
+                }
+                Ok(())
+            }
+            RData::Unknown(data) => writer.write_all(data),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A string prefixed with its length as a byte, RFC 1035 3.3.
+fn read_character_string<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0];
+    r.read_exact(&mut len)?;
+    let mut s = vec![0; len[0] as usize];
+    r.read_exact(&mut s)?;
+    Ok(s)
+}
+
+fn write_character_string<W: MsgWrite>(writer: &mut W, s: &[u8]) -> Result<()> {
+    if s.len() > 255 {
+        return Err(invalid("character string longer than 255 bytes"));
+    }
+    writer.write_all(&[s.len() as u8])?;
+    writer.write_all(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Message, ResourceRecord};
+
+    fn record(name: &str, rtype: u16, rdata: RData) -> ResourceRecord {
+        ResourceRecord {
+            name: name.to_string(),
+            rtype,
+            rclass: 1,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    #[test]
+    fn rdata_roundtrip() {
+        let query = [
+            0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111,
+            109, 0, 0, 255, 0, 1,
+        ];
+        let mut message = Message::from_bytes(&query).unwrap();
+        message.answers = vec![
+            record("example.com", A, RData::A(Ipv4Addr::new(93, 184, 216, 34))),
+            record("example.com", AAAA, RData::Aaaa(Ipv6Addr::LOCALHOST)),
+            record("www.example.com", CNAME, RData::Cname("example.com".into())),
+            record("example.com", NS, RData::Ns("ns1.example.com".into())),
+            record("example.com", PTR, RData::Ptr("host.example.com".into())),
+            record(
+                "example.com",
+                MX,
+                RData::Mx {
+                    preference: 10,
+                    exchange: "mail.example.com".into(),
+                },
+            ),
+            record(
+                "example.com",
+                SOA,
+                RData::Soa(Soa {
+                    mname: "ns1.example.com".into(),
+                    rname: "hostmaster.example.com".into(),
+                    serial: 2024010101,
+                    refresh: 7200,
+                    retry: 3600,
+                    expire: 1209600,
+                    minimum: 300,
+                }),
+            ),
+            record(
+                "example.com",
+                TXT,
+                RData::Txt(vec![b"v=spf1 -all".to_vec(), Vec::new()]),
+            ),
+            record(
+                "_sip._tcp.example.com",
+                SRV,
+                RData::Srv {
+                    priority: 1,
+                    weight: 5,
+                    port: 5060,
+                    target: "sip.example.com".into(),
+                },
+            ),
+            record(
+                "example.com",
+                CAA,
+                RData::Caa {
+                    flags: 0,
+                    tag: "issue".into(),
+                    value: b"letsencrypt.org".to_vec(),
+                },
+            ),
+            record("example.com", 99, RData::Unknown(vec![1, 2, 3])),
+        ];
+        message.header.ancount = message.answers.len() as u16;
+        message.additionals = vec![ResourceRecord {
+            name: String::new(),
+            rtype: OPT,
+            rclass: 1232,
+            ttl: 0,
+            rdata: RData::Opt(vec![EdnsOption {
+                code: 10,
+                data: vec![0; 8],
+            }]),
+        }];
+        message.header.arcount = 1;
+
+        let packet = message.to_udp_packet(None).unwrap();
+        let parsed = Message::from_bytes(&packet).unwrap();
+        for (expected, actual) in message.answers.iter().zip(&parsed.answers) {
+            assert_eq!(expected.name, actual.name);
+            assert_eq!(expected.rdata, actual.rdata);
+        }
+        assert_eq!(message.answers.len(), parsed.answers.len());
+        assert_eq!(message.additionals[0].rdata, parsed.additionals[0].rdata);
+
+        // the names of the RDATA are compressed, the SRV target is not.
+        let count = |needle: &[u8]| {
+            packet
+                .windows(needle.len())
+                .filter(|w| w == &needle)
+                .count()
+        };
+        assert_eq!(1, count(b"\x04mail\xc0"));
+        assert_eq!(1, count(b"\x03sip\x07example\x03com\x00"));
+        // once in the question and once in the SRV target.
+        assert_eq!(2, count(b"\x07example"));
+    }
+
+    #[test]
+    fn recompress_into_another_message() {
+        // www.google.com CNAME www.l.google.com, compressed against the question.
+        let sample = [
+            0, 2, 129, 128, 0, 1, 0, 1, 0, 0, 0, 0, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108,
+            101, 3, 99, 111, 109, 0, 0, 5, 0, 1, 192, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 8, 3, 119,
+            119, 119, 1, 108, 192, 16,
+        ];
+        let message = Message::from_bytes(&sample).unwrap();
+        assert_eq!(
+            RData::Cname("www.l.google.com".into()),
+            message.answers[0].rdata
+        );
+
+        // moved to a message without the question, the pointers must not be copied.
+        let mut moved = message.clone();
+        moved.questions.clear();
+        moved.header.qdcount = 0;
+        let parsed = Message::from_bytes(&moved.to_udp_packet(None).unwrap()).unwrap();
+        assert_eq!("www.google.com", parsed.answers[0].name);
+        assert_eq!(message.answers[0].rdata, parsed.answers[0].rdata);
+    }
+
+    #[test]
+    fn rdata_length_mismatch() {
+        // an A record with a length of 5.
+        let sample = [
+            0, 3, 129, 128, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 5, 10, 0, 0, 1,
+            0,
+        ];
+        assert!(Message::from_bytes(&sample).is_err());
+    }
+}
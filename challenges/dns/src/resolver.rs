@@ -0,0 +1,767 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+
+use super::cache::DnsCache;
+use super::client;
+use super::config::Config;
+use super::protocol::{Message, NOERROR, NXDOMAIN, Question, ResourceRecord};
+use super::rdata::{A, RData};
+
+/// The referrals followed for a name before giving up.
+const MAX_REFERRALS: usize = 16;
+
+/// The CNAME records followed for a question, RFC 1034 5.3.3 leaves the bound to the resolver.
+const MAX_CNAMES: usize = 8;
+
+/// How deep the addresses of name servers without glue are resolved, each level may need its own
+/// name servers resolved.
+const MAX_DEPTH: usize = 3;
+
+/// The queries sent to name servers to resolve a question, the name servers resolved along the
+/// way included.
+const MAX_QUERIES: usize = 32;
+
+/// The queries in flight, each has a socket of its own.
+const MAX_SOCKETS: usize = 256;
+
+/// The port of the name servers learned from referrals.
+const DNS_PORT: u16 = 53;
+
+/// How long a name server has to answer before the next one is asked. A quarter of the time of
+/// the resolution if that is shorter, so a silent name server leaves time for the others.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// An iterative resolver, RFC 1034 5.3.3. The resolution starts from the root servers, or the
+/// closest zone whose name servers are cached, and follows the referrals down to a name server
+/// which answers.
+pub struct Resolver {
+    root_hints: Vec<SocketAddr>,
+    /// The addresses the name servers learned from referrals are reached on instead of port 53,
+    /// for the stand-in name servers of the tests.
+    addresses: HashMap<IpAddr, SocketAddr>,
+    /// The time the resolution of a question may take.
+    deadline: Duration,
+    /// The time each name server has to answer.
+    server_timeout: Duration,
+    /// A permit per query in flight.
+    sockets: Semaphore,
+}
+
+/// What the answer of a name server leads to.
+enum Step {
+    /// An answer, possibly negative, which ends the resolution of the name.
+    Answer(Message),
+    /// The name is in a zone delegated to other name servers.
+    Referral {
+        zone: String,
+        ns: Vec<String>,
+        glue: Vec<SocketAddr>,
+        ttl: u32,
+    },
+}
+
+type Resolution<'a> = Pin<Box<dyn Future<Output = Result<Message>> + Send + 'a>>;
+
+/// The queries left to resolve a question.
+struct Budget(AtomicUsize);
+
+impl Budget {
+    fn new() -> Budget {
+        Budget(AtomicUsize::new(MAX_QUERIES))
+    }
+
+    /// Take a query from the budget, an error once it is spent.
+    fn spend(&self) -> Result<()> {
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .map(|_| ())
+            .map_err(|_| Error::other("too many queries"))
+    }
+}
+
+impl Resolver {
+    pub fn new(config: &Config) -> Result<Resolver> {
+        if config.root_hints.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "at least one root hint is required",
+            ));
+        }
+        Ok(Resolver {
+            root_hints: config.root_hints.clone(),
+            addresses: HashMap::new(),
+            deadline: config.timeout(),
+            server_timeout: (config.timeout() / 4).min(SERVER_TIMEOUT),
+            sockets: Semaphore::new(MAX_SOCKETS),
+        })
+    }
+
+    /// Reach the name servers learned from referrals on `addresses`, for the stand-in name
+    /// servers of the tests.
+    #[cfg(test)]
+    fn with_addresses(self, addresses: HashMap<IpAddr, SocketAddr>) -> Resolver {
+        Resolver { addresses, ..self }
+    }
+
+    /// The address a name server learned from a referral is queried on.
+    fn name_server(&self, ip: IpAddr) -> SocketAddr {
+        self.addresses
+            .get(&ip)
+            .copied()
+            .unwrap_or(SocketAddr::new(ip, DNS_PORT))
+    }
+
+    /// Resolve the question of `query`. The answers of the response start with the CNAME records
+    /// followed to the name which has the records.
+    pub async fn resolve(&self, query: &Message, cache: &DnsCache) -> Result<Message> {
+        let [question] = query.questions.as_slice() else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "only queries with one question are resolved",
+            ));
+        };
+        let budget = Budget::new();
+        let resolution = self.resolve_question(question, cache, 0, &budget);
+        let res = tokio::time::timeout(self.deadline, resolution)
+            .await
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::TimedOut,
+                    format!("resolution of {} timed out", question.qname),
+                )
+            })??;
+        let mut response = query.clone();
+        response.header.flags.set_qr(1);
+        response.header.flags.set_ra(1);
+        response.set_rcode(res.rcode());
+        response.header.ancount = res.answers.len() as u16;
+        response.answers = res.answers;
+        response.header.nscount = res.authorities.len() as u16;
+        response.authorities = res.authorities;
+        Ok(response)
+    }
+
+    /// Resolve `question`, following the CNAME records which do not lead to records of its type
+    /// within the answer of the name server. The resolution restarts at the targets out of the
+    /// zone of the name server, its records for them are dropped.
+    fn resolve_question<'a>(
+        &'a self,
+        question: &'a Question,
+        cache: &'a DnsCache,
+        depth: usize,
+        budget: &'a Budget,
+    ) -> Resolution<'a> {
+        Box::pin(async move {
+            let mut chain = Vec::new();
+            let mut question = question.clone();
+            for _ in 0..MAX_CNAMES {
+                let mut res = self.resolve_name(&question, cache, depth, budget).await?;
+                chain.append(&mut res.answers);
+                match cname_target(&chain, &question) {
+                    Some(target) if res.rcode() == NOERROR => question.qname = target,
+                    _ => {
+                        res.answers = chain;
+                        return Ok(res);
+                    }
+                }
+            }
+            Err(Error::other(format!(
+                "too many CNAMEs for {}",
+                question.qname
+            )))
+        })
+    }
+
+    /// Follow the referrals from the closest known zone to a name server answering `question`.
+    async fn resolve_name(
+        &self,
+        question: &Question,
+        cache: &DnsCache,
+        depth: usize,
+        budget: &Budget,
+    ) -> Result<Message> {
+        let (mut zone, mut servers) = self.closest_servers(&question.qname, cache).await;
+        for _ in 0..MAX_REFERRALS {
+            match self.ask(&servers, &zone, question, budget).await? {
+                Step::Answer(res) => return Ok(res),
+                Step::Referral {
+                    zone: child,
+                    ns,
+                    glue,
+                    ttl,
+                } => {
+                    servers = if glue.is_empty() {
+                        self.resolve_addresses(&ns, cache, depth, budget).await
+                    } else {
+                        glue
+                    };
+                    if servers.is_empty() {
+                        return Err(Error::other(format!(
+                            "no address for the name servers of {:?}",
+                            child
+                        )));
+                    }
+                    cache.set_delegation(&child, servers.clone(), ttl).await;
+                    zone = child;
+                }
+            }
+        }
+        Err(Error::other(format!(
+            "too many referrals for {}",
+            question.qname
+        )))
+    }
+
+    /// The closest enclosing zone of `name` with cached name servers, or the root.
+    async fn closest_servers(&self, name: &str, cache: &DnsCache) -> (String, Vec<SocketAddr>) {
+        let name = name.to_ascii_lowercase();
+        let mut zone = name.as_str();
+        while !zone.is_empty() {
+            if let Some(servers) = cache.get_delegation(zone).await {
+                return (zone.to_string(), servers);
+            }
+            zone = zone.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        (String::new(), self.root_hints.clone())
+    }
+
+    /// Ask the name servers of `zone` in turn, until one answers. The servers which time out,
+    /// fail or turn out to be lame are skipped.
+    async fn ask(
+        &self,
+        servers: &[SocketAddr],
+        zone: &str,
+        question: &Question,
+        budget: &Budget,
+    ) -> Result<Step> {
+        for server in servers {
+            budget.spend()?;
+            let _socket = self.sockets.acquire().await.map_err(Error::other)?;
+            match client::query_server(*server, question, self.server_timeout).await {
+                Ok(res) => match self.classify(res, zone, question) {
+                    Some(step) => return Ok(step),
+                    None => eprintln!("lame delegation of {:?} to {}", zone, server),
+                },
+                Err(e) => eprintln!("name server {} failed: {}", server, e),
+            }
+        }
+        Err(Error::other(format!(
+            "no name server of {:?} answered",
+            zone
+        )))
+    }
+
+    /// What the answer of a name server of `zone` leads to, `None` if the server is lame: it
+    /// failed, refused the query or does not know about the zone. The answer and authority
+    /// records out of `zone` are dropped, the server has no authority over them.
+    fn classify(&self, mut res: Message, zone: &str, question: &Question) -> Option<Step> {
+        res.answers.retain(|rr| in_zone(&rr.name, zone));
+        res.header.ancount = res.answers.len() as u16;
+        res.authorities.retain(|rr| in_zone(&rr.name, zone));
+        res.header.nscount = res.authorities.len() as u16;
+        let rcode = res.rcode();
+        if rcode == NXDOMAIN || (rcode == NOERROR && !res.answers.is_empty()) {
+            return Some(Step::Answer(res));
+        }
+        if rcode != NOERROR {
+            return None;
+        }
+        // a referral must bring the name closer, a referral upwards is lame.
+        if let Some(child) = res.authorities.iter().find_map(|rr| match rr.rdata {
+            RData::Ns(_) => Some(rr.name.to_ascii_lowercase()),
+            _ => None,
+        }) {
+            if child != zone && in_zone(&child, zone) && in_zone(&question.qname, &child) {
+                return Some(self.referral(&res, zone, child));
+            }
+            return None;
+        }
+        // NODATA, the name exists without records of the type.
+        let soa = res
+            .authorities
+            .iter()
+            .any(|rr| matches!(rr.rdata, RData::Soa(_)));
+        (soa || res.header.flags.aa() == 1).then_some(Step::Answer(res))
+    }
+
+    /// The referral of a name server of `zone` to `child`. The glue outside of `zone` is ignored,
+    /// the server has no authority over it.
+    fn referral(&self, res: &Message, zone: &str, child: String) -> Step {
+        let delegation: Vec<_> = res
+            .authorities
+            .iter()
+            .filter(|rr| rr.name.eq_ignore_ascii_case(&child))
+            .filter_map(|rr| match &rr.rdata {
+                RData::Ns(ns) => Some((ns.to_ascii_lowercase(), rr.ttl)),
+                _ => None,
+            })
+            .collect();
+        let ns: Vec<_> = delegation.iter().map(|(ns, _)| ns.clone()).collect();
+        let ttl = delegation.iter().map(|(_, ttl)| *ttl).min().unwrap_or(0);
+        let mut glue: Vec<_> = res
+            .additionals
+            .iter()
+            .filter(|rr| in_zone(&rr.name, zone) && ns.contains(&rr.name.to_ascii_lowercase()))
+            .filter_map(|rr| match rr.rdata {
+                RData::A(ip) => Some(self.name_server(IpAddr::V4(ip))),
+                RData::Aaaa(ip) => Some(self.name_server(IpAddr::V6(ip))),
+                _ => None,
+            })
+            .collect();
+        // IPv6 may well be unreachable, the IPv4 addresses are tried first.
+        glue.sort_by_key(|addr| addr.is_ipv6());
+        Step::Referral {
+            zone: child,
+            ns,
+            glue,
+            ttl,
+        }
+    }
+
+    /// The IPv4 addresses of the first of the name servers `ns` which resolves.
+    async fn resolve_addresses(
+        &self,
+        ns: &[String],
+        cache: &DnsCache,
+        depth: usize,
+        budget: &Budget,
+    ) -> Vec<SocketAddr> {
+        if depth >= MAX_DEPTH {
+            return Vec::new();
+        }
+        for name in ns {
+            let question = Question {
+                qname: name.clone(),
+                qtype: A,
+                qclass: 1,
+            };
+            match self
+                .resolve_question(&question, cache, depth + 1, budget)
+                .await
+            {
+                Ok(res) => {
+                    let addrs: Vec<_> = res
+                        .answers
+                        .iter()
+                        .filter_map(|rr| match rr.rdata {
+                            RData::A(ip) => Some(self.name_server(IpAddr::V4(ip))),
+                            _ => None,
+                        })
+                        .collect();
+                    if !addrs.is_empty() {
+                        return addrs;
+                    }
+                }
+                Err(e) => eprintln!("couldn't resolve name server {}: {}", name, e),
+            }
+        }
+        Vec::new()
+    }
+}
+
+/// Whether `name` is `zone` or below it, the root zone is the empty name.
+fn in_zone(name: &str, zone: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let zone = zone.to_ascii_lowercase();
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// The name the CNAME records of `answers` lead to from the name of `question`, `None` when
+/// there are none or they lead to records of the type of `question`.
+fn cname_target(answers: &[ResourceRecord], question: &Question) -> Option<String> {
+    let mut name = question.qname.clone();
+    let mut followed = false;
+    // a loop of CNAME records ends when the records are exhausted.
+    for _ in 0..=answers.len() {
+        let mut records = answers
+            .iter()
+            .filter(|rr| rr.name.eq_ignore_ascii_case(&name));
+        if records.clone().any(|rr| rr.rtype == question.qtype) {
+            return None;
+        }
+        match records.find_map(|rr| match &rr.rdata {
+            RData::Cname(target) => Some(target.clone()),
+            _ => None,
+        }) {
+            Some(target) => {
+                name = target;
+                followed = true;
+            }
+            None => break,
+        }
+    }
+    followed.then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::rdata::{CNAME, NS, SOA, Soa};
+
+    fn record(name: &str, rtype: u16, rdata: RData) -> ResourceRecord {
+        ResourceRecord {
+            name: name.to_string(),
+            rtype,
+            rclass: 1,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    fn respond(query: &Message, rcode: u16) -> Message {
+        let mut res = query.clone();
+        res.header.flags.set_qr(1);
+        res.set_rcode(rcode);
+        res
+    }
+
+    /// A referral to `zone`, served by the name servers `ns` with their glue if they have any.
+    fn referral(query: &Message, zone: &str, ns: &[(&str, Option<[u8; 4]>)]) -> Message {
+        let mut res = respond(query, NOERROR);
+        for (name, glue) in ns {
+            res.authorities
+                .push(record(zone, NS, RData::Ns(name.to_string())));
+            if let Some(ip) = glue {
+                res.additionals
+                    .push(record(name, A, RData::A(Ipv4Addr::from(*ip))));
+            }
+        }
+        res.header.nscount = res.authorities.len() as u16;
+        res.header.arcount = res.additionals.len() as u16;
+        res
+    }
+
+    fn answer(query: &Message, answers: Vec<ResourceRecord>) -> Message {
+        let mut res = respond(query, NOERROR);
+        res.header.ancount = answers.len() as u16;
+        res.answers = answers;
+        res
+    }
+
+    /// A stand-in name server on `addr`, answering the queries with `serve`. Returns the number of
+    /// queries it received.
+    async fn stand_in(
+        addr: SocketAddr,
+        serve: impl Fn(&Message) -> Message + Send + 'static,
+    ) -> Arc<AtomicUsize> {
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            while let Ok((len, src)) = socket.recv_from(&mut buf).await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let query = Message::from_bytes(&buf[..len]).unwrap();
+                let packet = serve(&query).to_udp_packet(None).unwrap();
+                socket.send_to(&packet, src).await.unwrap();
+            }
+        });
+        queries
+    }
+
+    /// The root refers `example` to a name server which does not answer, a lame one and a good
+    /// one, in this order. It refers `other` to the good one, without glue as its name is in
+    /// `example`.
+    fn root(query: &Message) -> Message {
+        let qname = &query.questions[0].qname;
+        if in_zone(qname, "example") {
+            referral(
+                query,
+                "example",
+                &[
+                    ("a.ns.example", Some([127, 0, 0, 4])),
+                    ("b.ns.example", Some([127, 0, 0, 3])),
+                    ("c.ns.example", Some([127, 0, 0, 2])),
+                ],
+            )
+        } else if in_zone(qname, "other") {
+            referral(query, "other", &[("c.ns.example", None)])
+        } else {
+            respond(query, NXDOMAIN)
+        }
+    }
+
+    /// A lame name server, it refers every query back to the root.
+    fn lame(query: &Message) -> Message {
+        referral(query, "", &[("a.root", Some([127, 0, 0, 1]))])
+    }
+
+    /// The name server of `example` and `other`.
+    fn authority(query: &Message) -> Message {
+        match query.questions[0].qname.as_str() {
+            // the address of host.other is out of the zone, the resolution restarts there.
+            "www.example" => answer(
+                query,
+                vec![
+                    record("www.example", CNAME, RData::Cname("host.other".into())),
+                    record("host.other", A, RData::A([6, 6, 6, 6].into())),
+                ],
+            ),
+            "c.ns.example" => answer(
+                query,
+                vec![record("c.ns.example", A, RData::A([127, 0, 0, 2].into()))],
+            ),
+            "host.other" => answer(
+                query,
+                vec![record("host.other", A, RData::A([10, 0, 0, 1].into()))],
+            ),
+            _ => {
+                let mut res = respond(query, NXDOMAIN);
+                res.authorities = vec![record(
+                    "example",
+                    SOA,
+                    RData::Soa(Soa {
+                        mname: "c.ns.example".into(),
+                        rname: "hostmaster.example".into(),
+                        serial: 1,
+                        refresh: 3600,
+                        retry: 600,
+                        expire: 86400,
+                        minimum: 60,
+                    }),
+                )];
+                res.header.nscount = 1;
+                res
+            }
+        }
+    }
+
+    /// A free address on 127.0.0.1 for a stand-in name server, nothing listens on it until then.
+    fn free_addr() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap()
+    }
+
+    /// A resolver starting from `root`. The name servers are given addresses of 127/8 in the
+    /// referrals, `stand_ins` maps them to the stand-ins on 127.0.0.1 and 127.0.0.1 itself is
+    /// the root.
+    fn resolver(
+        root: SocketAddr,
+        stand_ins: &[([u8; 4], SocketAddr)],
+        timeout_ms: u64,
+    ) -> (Resolver, DnsCache) {
+        let config = Config {
+            recursive: true,
+            root_hints: vec![root],
+            timeout_ms,
+            ..Config::default()
+        };
+        let mut addresses: HashMap<_, _> = stand_ins
+            .iter()
+            .map(|(ip, addr)| (IpAddr::from(*ip), *addr))
+            .collect();
+        addresses.insert(root.ip(), root);
+        let resolver = Resolver::new(&config).unwrap().with_addresses(addresses);
+        (resolver, DnsCache::new(&config))
+    }
+
+    /// The resolution time of the tests, a name server has a quarter of it.
+    const TIMEOUT_MS: u64 = 1000;
+
+    fn question(qname: &str) -> Question {
+        Question {
+            qname: qname.into(),
+            qtype: A,
+            qclass: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_from_root_hints() {
+        let root_addr = free_addr();
+        let (lame_addr, authority_addr) = (free_addr(), free_addr());
+        let root_queries = stand_in(root_addr, root).await;
+        stand_in(lame_addr, lame).await;
+        stand_in(authority_addr, authority).await;
+        // nothing listens on the address of a.ns.example.
+        let stand_ins = [
+            ([127, 0, 0, 4], free_addr()),
+            ([127, 0, 0, 3], lame_addr),
+            ([127, 0, 0, 2], authority_addr),
+        ];
+        let (resolver, cache) = resolver(root_addr, &stand_ins, TIMEOUT_MS);
+
+        // www.example is a CNAME to host.other, whose name server has no glue.
+        let question = Question {
+            qname: "www.example".into(),
+            qtype: A,
+            qclass: 1,
+        };
+        let res = resolver
+            .resolve(&Message::query(1, question), &cache)
+            .await
+            .unwrap();
+        assert_eq!(NOERROR, res.rcode());
+        assert_eq!(1, res.header.flags.ra());
+        assert_eq!(
+            vec![
+                RData::Cname("host.other".into()),
+                RData::A([10, 0, 0, 1].into())
+            ],
+            res.answers
+                .iter()
+                .map(|rr| rr.rdata.clone())
+                .collect::<Vec<_>>()
+        );
+        assert!(cache.get_delegation("example").await.is_some());
+        assert!(cache.get_delegation("other").await.is_some());
+
+        // the delegation of example is cached, the root is not asked again.
+        let asked = root_queries.load(Ordering::SeqCst);
+        let question = Question {
+            qname: "missing.example".into(),
+            qtype: A,
+            qclass: 1,
+        };
+        let res = resolver
+            .resolve(&Message::query(2, question), &cache)
+            .await
+            .unwrap();
+        assert_eq!(NXDOMAIN, res.rcode());
+        assert!(matches!(res.authorities[0].rdata, RData::Soa(_)));
+        assert_eq!(asked, root_queries.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn zones() {
+        assert!(in_zone("www.example.com", ""));
+        assert!(in_zone("www.Example.com", "example.com"));
+        assert!(in_zone("example.com", "example.com"));
+        assert!(!in_zone("www.badexample.com", "example.com"));
+        assert!(!in_zone("com", "example.com"));
+    }
+
+    #[tokio::test]
+    async fn referral_limit() {
+        // each query is referred one label closer to the name, by the same name server.
+        let root_addr = free_addr();
+        let referrals = AtomicUsize::new(0);
+        let queries = stand_in(root_addr, move |query| {
+            let labels: Vec<_> = query.questions[0].qname.split('.').collect();
+            let n = referrals.fetch_add(1, Ordering::SeqCst) + 1;
+            let child = labels[labels.len() - n..].join(".");
+            referral(
+                query,
+                &child,
+                &[(&format!("ns.{}", child), Some([127, 0, 0, 1]))],
+            )
+        })
+        .await;
+        let (resolver, cache) = resolver(root_addr, &[], TIMEOUT_MS);
+
+        let name = vec!["a"; 20].join(".");
+        let query = Message::query(1, question(&name));
+        let err = resolver.resolve(&query, &cache).await.unwrap_err();
+        assert!(err.to_string().contains("referrals"), "{}", err);
+        assert_eq!(MAX_REFERRALS, queries.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn cname_limit() {
+        // cN is a CNAME to cN+1.
+        let root_addr = free_addr();
+        let queries = stand_in(root_addr, |query| {
+            let qname = &query.questions[0].qname;
+            let n: usize = qname[1..].parse().unwrap();
+            let target = RData::Cname(format!("c{}", n + 1));
+            answer(query, vec![record(qname, CNAME, target)])
+        })
+        .await;
+        let (resolver, cache) = resolver(root_addr, &[], TIMEOUT_MS);
+
+        let query = Message::query(1, question("c0"));
+        let err = resolver.resolve(&query, &cache).await.unwrap_err();
+        assert!(err.to_string().contains("CNAMEs"), "{}", err);
+        assert_eq!(MAX_CNAMES, queries.load(Ordering::SeqCst));
+    }
+
+    /// A name server referring the zone dN of each name to `fan_out` name servers in dN+1, without
+    /// glue.
+    fn glueless(fan_out: usize) -> impl Fn(&Message) -> Message {
+        move |query| {
+            let qname = &query.questions[0].qname;
+            let zone = qname.rsplit('.').next().unwrap();
+            let n: usize = zone[1..].parse().unwrap();
+            let ns: Vec<_> = (0..fan_out)
+                .map(|i| format!("ns{}.d{}", i, n + 1))
+                .collect();
+            let ns: Vec<_> = ns.iter().map(|name| (name.as_str(), None)).collect();
+            referral(query, zone, &ns)
+        }
+    }
+
+    #[tokio::test]
+    async fn depth_limit() {
+        let root_addr = free_addr();
+        let queries = stand_in(root_addr, glueless(1)).await;
+        let (resolver, cache) = resolver(root_addr, &[], TIMEOUT_MS);
+
+        let query = Message::query(1, question("x.d0"));
+        let err = resolver.resolve(&query, &cache).await.unwrap_err();
+        assert!(err.to_string().contains("no address"), "{}", err);
+        // x.d0, then the name servers of d0 to d2 before giving up.
+        assert_eq!(MAX_DEPTH + 1, queries.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn query_budget() {
+        // four name servers without glue for each zone, more queries than the budget.
+        let root_addr = free_addr();
+        let queries = stand_in(root_addr, glueless(4)).await;
+        let (resolver, cache) = resolver(root_addr, &[], TIMEOUT_MS);
+
+        let query = Message::query(1, question("x.d0"));
+        assert!(resolver.resolve(&query, &cache).await.is_err());
+        assert_eq!(MAX_QUERIES, queries.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn resolution_deadline() {
+        // the root refers to eight name servers which never answer, and each has a quarter of the
+        // resolution time.
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let root_addr = free_addr();
+        let ns: Vec<_> = (0..8).map(|i| format!("ns{}.example", i)).collect();
+        stand_in(root_addr, move |query| {
+            let glue: Vec<_> = (0..8)
+                .map(|i| (ns[i].as_str(), Some([127, 0, 1, i as u8])))
+                .collect();
+            referral(query, "example", &glue)
+        })
+        .await;
+        let stand_ins: Vec<_> = (0..8)
+            .map(|i| ([127, 0, 1, i], silent.local_addr().unwrap()))
+            .collect();
+        let (resolver, cache) = resolver(root_addr, &stand_ins, 400);
+
+        let query = Message::query(1, question("www.example"));
+        let started = std::time::Instant::now();
+        let err = resolver.resolve(&query, &cache).await.unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, err.kind(), "{}", err);
+        assert!(started.elapsed() < Duration::from_millis(800));
+    }
+
+    #[test]
+    fn root_hints_required() {
+        let config = Config {
+            recursive: true,
+            root_hints: Vec::new(),
+            ..Config::default()
+        };
+        assert!(Resolver::new(&config).is_err());
+    }
+}
@@ -1,17 +1,39 @@
 use std::io::Result;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::client::DnsClient;
-use tokio::net::UdpSocket;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{Semaphore, mpsc};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
 
-use super::cache::DnsCache;
-use super::protocol::{Message, ResourceRecord};
+use super::cache::{CacheKey, CachedResponse, DnsCache};
+use super::config::Config;
+use super::edns::{BADVERS, EDNS_VERSION, Edns};
+use super::protocol::{FORMERR, MAX_UDP_SIZE, Message, SERVFAIL};
+use super::resolver::Resolver;
+use super::tcp;
+
+/// The largest UDP payload of our responses, clients may advertise larger sizes. It is the payload
+/// size of the OPT record of our responses.
+const MAX_EDNS_UDP_SIZE: usize = 4096;
+
+/// TCP connections are closed when no query arrives for this long, RFC 7766 6.2.3.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The queries of a TCP connection resolved at once, the next ones are not read until one of them
+/// is answered.
+const MAX_TCP_PIPELINE: usize = 16;
 
 /// Context is a struct that holds the processing state of the Processor.
 struct Context {
-    socket: UdpSocket,
-    client: DnsClient,
+    /// One client per upstream, in the order they are tried.
+    clients: Vec<DnsClient>,
+    /// Set in recursive mode, the upstreams are not used then.
+    resolver: Option<Resolver>,
     cache: DnsCache,
 }
 
@@ -19,28 +41,133 @@ struct Context {
 /// DNS server.
 pub struct Processor {
     ctx: Arc<Context>,
+    sockets: Vec<Arc<UdpSocket>>,
+    /// A TCP listener on the address of each socket.
+    listeners: Vec<Arc<TcpListener>>,
 }
 
 impl Processor {
-    pub async fn build() -> Result<Processor> {
+    pub async fn build(config: &Config) -> Result<Processor> {
+        let mut sockets = Vec::with_capacity(config.listen.len());
+        let mut listeners = Vec::with_capacity(config.listen.len());
+        for addr in &config.listen {
+            let socket = UdpSocket::bind(addr).await?;
+            // bound to the address of the socket, the same port when the port was left to the OS.
+            listeners.push(Arc::new(TcpListener::bind(socket.local_addr()?).await?));
+            sockets.push(Arc::new(socket));
+        }
+        let mut clients = Vec::with_capacity(config.upstreams.len());
+        let mut resolver = None;
+        if config.recursive {
+            resolver = Some(Resolver::new(config)?);
+        } else {
+            for addr in &config.upstreams {
+                clients.push(DnsClient::connect(*addr, config.timeout()).await?);
+            }
+        }
         Ok(Processor {
             ctx: Arc::new(Context {
-                socket: UdpSocket::bind("127.0.0.1:1053")
-                    .await
-                    .expect("couldn't bind to address"),
-                client: DnsClient::connect("8.8.8.8:53")
-                    .await
-                    .expect("couldn't connect forwarder"),
-                cache: DnsCache::new(),
+                clients,
+                resolver,
+                cache: DnsCache::new(config),
             }),
+            sockets,
+            listeners,
         })
     }
 
+    /// The addresses the sockets are bound to, which tells the ports picked for port 0.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.sockets.iter().map(|s| s.local_addr()).collect()
+    }
+
     pub async fn run_loop(&self) {
+        let mut loops = JoinSet::new();
+        for socket in &self.sockets {
+            loops.spawn(Self::receive_loop(socket.clone(), self.ctx.clone()));
+        }
+        for listener in &self.listeners {
+            loops.spawn(Self::accept_loop(listener.clone(), self.ctx.clone()));
+        }
+        while loops.join_next().await.is_some() {}
+    }
+
+    async fn accept_loop(listener: Arc<TcpListener>, ctx: Arc<Context>) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(Self::serve_tcp(stream, ctx.clone()));
+                }
+                Err(e) => eprintln!("couldn't accept a connection: {}", e),
+            }
+        }
+    }
+
+    /// Serve the queries of a TCP connection. The queries may be pipelined, each one is answered
+    /// as soon as it is resolved so the answers can be out of order. At most `MAX_TCP_PIPELINE`
+    /// queries are resolved at once.
+    async fn serve_tcp(stream: TcpStream, ctx: Arc<Context>) {
+        let (mut reader, mut writer) = stream.into_split();
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MAX_TCP_PIPELINE);
+        let writes = tokio::spawn(async move {
+            while let Some(packet) = rx.recv().await {
+                if writer.write_all(&packet).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let pipeline = Arc::new(Semaphore::new(MAX_TCP_PIPELINE));
+        loop {
+            let packet = match timeout(TCP_IDLE_TIMEOUT, tcp::read_message(&mut reader)).await {
+                Ok(Ok(Some(packet))) => packet,
+                Ok(Ok(None)) | Err(_) => break,
+                Ok(Err(e)) => {
+                    eprintln!("couldn't read a query: {}", e);
+                    break;
+                }
+            };
+            let query = match Message::from_bytes(&packet) {
+                Ok(query) => query,
+                Err(e) => {
+                    eprintln!("Error parsing query: {:?}", e);
+                    // the framing is intact, the connection carries on after the FORMERR.
+                    let Some(Ok(formerr)) = Message::format_error(&packet)
+                        .map(|res| res.to_udp_packet(None).and_then(tcp::frame))
+                    else {
+                        break;
+                    };
+                    if tx.send(formerr).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let Ok(permit) = pipeline.clone().acquire_owned().await else {
+                break;
+            };
+            let ctx = ctx.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let response = Self::handle_query(query, &ctx).await;
+                match response.to_udp_packet(None).and_then(tcp::frame) {
+                    Ok(packet) => {
+                        let _ = tx.send(packet).await;
+                    }
+                    Err(e) => eprintln!("couldn't encode a response: {}", e),
+                }
+                drop(permit);
+            });
+        }
+        // the answers to the queries already received are still sent.
+        drop(tx);
+        let _ = writes.await;
+    }
+
+    async fn receive_loop(socket: Arc<UdpSocket>, ctx: Arc<Context>) {
         loop {
             let mut buf = [0; 4096];
-            match self.ctx.socket.recv_from(&mut buf).await {
-                Ok((amt, src)) => self.handle_packet(&buf[..amt], src),
+            match socket.recv_from(&mut buf).await {
+                Ok((amt, src)) => Self::handle_packet(&buf[..amt], src, &socket, &ctx),
                 Err(e) => {
                     // TODO this should probably take down the server or a watchdog should be
                     // trying to re-establish the socket ?
@@ -50,76 +177,596 @@ impl Processor {
         }
     }
 
-    fn handle_packet(&self, buf: &[u8], src: SocketAddr) {
+    fn handle_packet(buf: &[u8], src: SocketAddr, socket: &Arc<UdpSocket>, ctx: &Arc<Context>) {
         // println!("Data: {:?}", &buf[..amt]);
         // println!("Received {} bytes from {}", amt, src);
         match Message::from_bytes(buf) {
             Ok(query) => {
-                let ctx = self.ctx.clone();
+                let ctx = ctx.clone();
+                let socket = socket.clone();
+                let max_size = query.max_udp_size().clamp(MAX_UDP_SIZE, MAX_EDNS_UDP_SIZE);
                 tokio::spawn(async move {
-                    Self::handle_query(&src, query, &ctx).await;
+                    let response = Self::handle_query(query, &ctx).await;
+                    if let Some(packet) = Self::encode_udp(response, max_size) {
+                        let _ = socket.send_to(packet.as_slice(), &src).await;
+                    }
                 });
             }
             Err(e) => {
-                eprintln!("Error parsing query: {:?}", e); // TODO return an error the client
+                eprintln!("Error parsing query: {:?}", e);
+                if let Some(res) = Message::format_error(buf)
+                    && let Ok(packet) = res.to_udp_packet(None)
+                {
+                    let socket = socket.clone();
+                    tokio::spawn(async move {
+                        let _ = socket.send_to(packet.as_slice(), &src).await;
+                    });
+                }
             }
         };
     }
 
-    async fn handle_query(src: &SocketAddr, query: Message, ctx: &Context) {
+    /// Encode the response in at most `max_size` bytes, or a SERVFAIL when it cannot be encoded.
+    fn encode_udp(response: Message, max_size: usize) -> Option<Vec<u8>> {
+        match response.to_truncated_udp_packet(None, max_size) {
+            Ok(packet) => Some(packet),
+            Err(e) => {
+                eprintln!("couldn't encode a response: {}", e);
+                let mut response = response;
+                response.answers.clear();
+                response.authorities.clear();
+                response.header.ancount = 0;
+                response.header.nscount = 0;
+                let edns = response.edns().ok().flatten();
+                response.additionals.clear();
+                response.set_edns(edns);
+                response.set_rcode(SERVFAIL);
+                response.to_udp_packet(None).ok()
+            }
+        }
+    }
+
+    /// Answer a query from the cache or the upstreams.
+    async fn handle_query(query: Message, ctx: &Context) -> Message {
         // Todo validate the query
         // Todo add cache hit/miss metrics
         println!("Query: {:?}", query);
-        if query.questions.len() == 1 {
-            if let Some(answers) = ctx.cache.get(&query.questions[0]).await {
-                Self::respond_from_cache(src, query, ctx, answers).await
-            } else {
-                Self::do_query(src, query, ctx, true).await
+        let edns = match query.edns() {
+            Ok(edns) => edns,
+            Err(e) => {
+                eprintln!("{}", e);
+                return Self::error_response(query, FORMERR);
+            }
+        };
+        if let Some(edns) = &edns
+            && edns.version > EDNS_VERSION
+        {
+            let mut response = Self::error_response(query, 0);
+            response.set_edns(Some(Self::response_edns(edns)));
+            response.set_rcode(BADVERS);
+            return response;
+        }
+
+        let mut response = match CacheKey::new(&query) {
+            Some(key) => {
+                if let Some(cached) = ctx.cache.get(&key).await {
+                    println!("from cache");
+                    cached.into_response(query)
+                } else {
+                    Self::do_query(query, ctx, Some(&key)).await
+                }
             }
-        } else {
             // more than one question -- we just pass that through
-            Self::do_query(src, query, ctx, false).await
+            None => Self::do_query(query, ctx, None).await,
+        };
+        // the OPT record of the upstream is replaced with ours, or removed when the client did not
+        // send one, RFC 6891 7.
+        let ext_rcode = response
+            .edns()
+            .ok()
+            .flatten()
+            .map_or(0, |edns| edns.ext_rcode);
+        response.set_edns(edns.map(|edns| Edns {
+            ext_rcode,
+            ..Self::response_edns(&edns)
+        }));
+        response
+    }
+
+    /// The OPT record of a response to a query with `query`.
+    fn response_edns(query: &Edns) -> Edns {
+        Edns {
+            dnssec_ok: query.dnssec_ok,
+            ..Edns::new(MAX_EDNS_UDP_SIZE as u16)
         }
     }
 
-    async fn respond_from_cache(
-        src: &SocketAddr,
-        query: Message,
-        ctx: &Context,
-        answers: Vec<ResourceRecord>,
-    ) {
-        println!("from cache");
+    fn error_response(query: Message, rcode: u16) -> Message {
         let mut response = query;
+        response.set_edns(None);
         response.header.flags.set_qr(1);
-        response.header.ancount = answers.len() as u16;
-        response.answers.clone_from(&answers);
-        let _ = ctx
-            .socket
-            .send_to(response.to_udp_packet(None).unwrap().as_slice(), &src)
-            .await;
-    }
-
-    async fn do_query(src: &SocketAddr, query: Message, ctx: &Context, set_cache: bool) {
-        match ctx.client.query(&query).await {
-            Ok(mut res) => {
-                let packet = if set_cache && !res.answers.is_empty() {
-                    DnsCache::normalise_ttl(&mut res.answers);
-                    let packet = res.to_udp_packet(None).unwrap();
-                    ctx.cache.set(&query.questions[0], res.answers).await;
-                    packet
-                } else {
-                    res.to_udp_packet(None).unwrap()
-                };
-                let _ = ctx.socket.send_to(packet.as_slice(), &src).await;
+        response.header.flags.set_ra(1);
+        response.set_rcode(rcode);
+        response
+    }
+
+    /// Resolve the query, the response is cached under `key` if there is one.
+    async fn do_query(query: Message, ctx: &Context, key: Option<&CacheKey>) -> Message {
+        match Self::resolve(&query, ctx).await {
+            Ok(res) => {
+                if let Some(key) = key
+                    && let Some(mut cached) = CachedResponse::from_response(&res)
+                {
+                    ctx.cache.normalise_ttl(&mut cached);
+                    ctx.cache.set(key, cached.clone()).await;
+                    // the first response is served like the cached ones, with the TTLs of the cache.
+                    return cached.into_response(query);
+                }
+                res
             }
             Err(e) => {
                 eprintln!("{}", e);
-                let mut response = query;
-                response.header.flags.set_qr(1);
-                response.header.flags.set_rcode(2); // Server failure
-                let packet = response.to_udp_packet(None).unwrap();
-                let _ = ctx.socket.send_to(packet.as_slice(), &src).await;
+                Self::error_response(query, SERVFAIL)
+            }
+        }
+    }
+
+    /// Resolve the query recursively, or forward it to the upstreams.
+    async fn resolve(query: &Message, ctx: &Context) -> Result<Message> {
+        match &ctx.resolver {
+            Some(resolver) => resolver.resolve(query, &ctx.cache).await,
+            None => Self::forward(query, ctx).await,
+        }
+    }
+
+    /// Send the query to the upstreams in turn until one answers.
+    async fn forward(query: &Message, ctx: &Context) -> Result<Message> {
+        let mut last_err = None;
+        for client in &ctx.clients {
+            match client.query(query).await {
+                Ok(res) => return Ok(res),
+                Err(e) => {
+                    eprintln!("upstream failed: {}", e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.expect("at least one upstream is configured"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::protocol::{NXDOMAIN, ResourceRecord};
+    use crate::rdata::{NS, RData, SOA, Soa, TXT};
+
+    const QUERY: [u8; 43] = [
+        15, 245, 1, 32, 0, 1, 0, 0, 0, 0, 0, 1, 3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101,
+        3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    /// The reply of a stand-in upstream to every query.
+    #[derive(Clone, Default)]
+    struct Reply {
+        rcode: u16,
+        answers: Vec<ResourceRecord>,
+        authorities: Vec<ResourceRecord>,
+        additionals: Vec<ResourceRecord>,
+    }
+
+    impl Reply {
+        fn answers(answers: Vec<ResourceRecord>) -> Reply {
+            Reply {
+                answers,
+                ..Reply::default()
+            }
+        }
+    }
+
+    fn respond(query: &[u8], reply: &Reply) -> Message {
+        let mut res = Message::from_bytes(query).unwrap();
+        res.header.flags.set_qr(1);
+        res.header.flags.set_ra(1);
+        res.set_rcode(reply.rcode);
+        res.header.ancount = reply.answers.len() as u16;
+        res.answers = reply.answers.clone();
+        res.header.nscount = reply.authorities.len() as u16;
+        res.authorities = reply.authorities.clone();
+        let edns = res.edns().unwrap();
+        res.additionals = reply.additionals.clone();
+        res.set_edns(edns);
+        res
+    }
+
+    /// A stand-in upstream on loopback answering every query with `reply`, over UDP and TCP. With
+    /// `truncate` the UDP responses only have the TC flag set. Returns its address and the number
+    /// of queries it received.
+    async fn stand_in_upstream(reply: Reply, truncate: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        let udp_reply = reply.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            while let Ok((len, src)) = socket.recv_from(&mut buf).await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut res = respond(&buf[..len], &udp_reply);
+                if truncate {
+                    res.header.flags.set_tc(1);
+                    res.header.ancount = 0;
+                    res.answers.clear();
+                }
+                let packet = res.to_udp_packet(None).unwrap();
+                socket.send_to(&packet, src).await.unwrap();
+            }
+        });
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                while let Ok(Some(query)) = tcp::read_message(&mut stream).await {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let res = respond(&query, &reply);
+                    tcp::write_message(&mut stream, &res, None).await.unwrap();
+                }
+            }
+        });
+        (addr, queries)
+    }
+
+    /// Start a forwarder to `upstreams` listening on a free port of loopback.
+    async fn start_forwarder(upstreams: Vec<SocketAddr>) -> SocketAddr {
+        let config = Config {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            upstreams,
+            timeout_ms: 200,
+            max_ttl: 60,
+            ..Config::default()
+        };
+        let processor = Processor::build(&config).await.unwrap();
+        let addr = processor.local_addrs().unwrap()[0];
+        tokio::spawn(async move { processor.run_loop().await });
+        addr
+    }
+
+    async fn exchange(server: SocketAddr, query: &[u8]) -> Message {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(query, server).await.unwrap();
+        let mut buf = [0; 4096];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        Message::from_bytes(&buf[..len]).unwrap()
+    }
+
+    fn a_record(ttl: u32) -> ResourceRecord {
+        ResourceRecord {
+            name: "www.google.com".to_string(),
+            rtype: 1,
+            rclass: 1,
+            ttl,
+            rdata: RData::A(Ipv4Addr::new(142, 250, 179, 228)),
+        }
+    }
+
+    fn soa_record() -> ResourceRecord {
+        ResourceRecord {
+            name: "google.com".to_string(),
+            rtype: SOA,
+            rclass: 1,
+            ttl: 3600,
+            rdata: RData::Soa(Soa {
+                mname: "ns1.google.com".to_string(),
+                rname: "dns-admin.google.com".to_string(),
+                serial: 1,
+                refresh: 900,
+                retry: 900,
+                expire: 1800,
+                minimum: 30,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_to_upstream_and_cache() {
+        // the first upstream does not answer, the query fails over to the second one.
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (upstream, queries) =
+            stand_in_upstream(Reply::answers(vec![a_record(3600)]), false).await;
+        let server = start_forwarder(vec![dead.local_addr().unwrap(), upstream]).await;
+
+        let res = exchange(server, &QUERY).await;
+        assert_eq!(1, res.header.flags.qr());
+        assert_eq!(0, res.header.flags.rcode());
+        assert_eq!(
+            RData::A(Ipv4Addr::new(142, 250, 179, 228)),
+            res.answers[0].rdata
+        );
+        // the TTL is capped to the configured max.
+        assert_eq!(60, res.answers[0].ttl);
+
+        let res = exchange(server, &QUERY).await;
+        assert_eq!(1, res.answers.len());
+        assert_eq!(1, queries.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn tcp_pipelined_queries() {
+        let (upstream, _) = stand_in_upstream(Reply::answers(vec![a_record(60)]), false).await;
+        let server = start_forwarder(vec![upstream]).await;
+
+        // more queries than are resolved at once.
+        let count = MAX_TCP_PIPELINE as u16 * 2;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let mut query = Message::from_bytes(&QUERY).unwrap();
+        for id in 1..=count {
+            query.header.id = id;
+            tcp::write_message(&mut stream, &query, None).await.unwrap();
+        }
+        let mut ids = Vec::new();
+        for _ in 0..count {
+            let packet = tcp::read_message(&mut stream).await.unwrap().unwrap();
+            let res = Message::from_bytes(&packet).unwrap();
+            assert_eq!(1, res.answers.len());
+            ids.push(res.header.id);
+        }
+        ids.sort();
+        assert_eq!((1..=count).collect::<Vec<_>>(), ids);
+    }
+
+    #[tokio::test]
+    async fn format_error_on_truncated_question() {
+        let (upstream, _) = stand_in_upstream(Reply::answers(vec![a_record(60)]), false).await;
+        let server = start_forwarder(vec![upstream]).await;
+        let truncated = &QUERY[..16];
+
+        let res = exchange(server, truncated).await;
+        assert_eq!(QUERY[..2], res.header.id.to_be_bytes());
+        assert_eq!(FORMERR, res.rcode());
+
+        // the TCP connection is kept after the FORMERR.
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        for packet in [truncated, &QUERY] {
+            stream
+                .write_all(&tcp::frame(packet.to_vec()).unwrap())
+                .await
+                .unwrap();
+        }
+        let packet = tcp::read_message(&mut stream).await.unwrap().unwrap();
+        assert_eq!(FORMERR, Message::from_bytes(&packet).unwrap().rcode());
+        let packet = tcp::read_message(&mut stream).await.unwrap().unwrap();
+        assert_eq!(1, Message::from_bytes(&packet).unwrap().answers.len());
+    }
+
+    #[test]
+    fn servfail_when_response_cannot_be_encoded() {
+        let mut response = Message::from_bytes(&QUERY).unwrap();
+        response.header.flags.set_qr(1);
+        response.answers = vec![ResourceRecord {
+            rtype: TXT,
+            rdata: RData::Txt(vec![vec![b'a'; 300]]),
+            ..a_record(60)
+        }];
+        response.header.ancount = 1;
+
+        let packet = Processor::encode_udp(response, MAX_UDP_SIZE).unwrap();
+        let res = Message::from_bytes(&packet).unwrap();
+        assert_eq!(SERVFAIL, res.rcode());
+        assert!(res.answers.is_empty());
+        assert_eq!(15 * 256 + 245, res.header.id);
+        // the OPT record of the response is kept.
+        assert!(res.edns().unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn retry_over_tcp_when_truncated() {
+        let (upstream, queries) = stand_in_upstream(Reply::answers(vec![a_record(60)]), true).await;
+        let server = start_forwarder(vec![upstream]).await;
+
+        let res = exchange(server, &QUERY).await;
+        assert_eq!(0, res.header.flags.tc());
+        assert_eq!(
+            RData::A(Ipv4Addr::new(142, 250, 179, 228)),
+            res.answers[0].rdata
+        );
+        assert_eq!(2, queries.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn truncate_to_client_udp_size() {
+        let answers = vec![a_record(60); 40];
+        let (upstream, _) = stand_in_upstream(Reply::answers(answers), false).await;
+        let server = start_forwarder(vec![upstream]).await;
+
+        // without an OPT record the client accepts 512 bytes.
+        let query = &QUERY[..QUERY.len() - 11];
+        let mut query = query.to_vec();
+        query[11] = 0;
+        let res = exchange(server, &query).await;
+        assert_eq!(1, res.header.flags.tc());
+        assert!(res.answers.len() < 40);
+
+        // the query advertises 4096 bytes.
+        let res = exchange(server, &QUERY).await;
+        assert_eq!(0, res.header.flags.tc());
+        assert_eq!(40, res.answers.len());
+    }
+
+    #[tokio::test]
+    async fn edns_negotiation() {
+        let (upstream, _) = stand_in_upstream(Reply::answers(vec![a_record(60)]), false).await;
+        let server = start_forwarder(vec![upstream]).await;
+
+        // our payload size is advertised, the DO bit of the query is echoed.
+        let mut query = Message::from_bytes(&QUERY).unwrap();
+        query.set_edns(Some(Edns {
+            dnssec_ok: true,
+            ..Edns::new(1232)
+        }));
+        let res = exchange(server, &query.to_udp_packet(None).unwrap()).await;
+        let edns = res.edns().unwrap().unwrap();
+        assert_eq!(4096, edns.udp_size);
+        assert!(edns.dnssec_ok);
+        assert_eq!(1, res.answers.len());
+
+        // no OPT record in the response to a query without one.
+        query.set_edns(None);
+        let res = exchange(server, &query.to_udp_packet(None).unwrap()).await;
+        assert_eq!(None, res.edns().unwrap());
+        assert_eq!(1, res.answers.len());
+
+        // an unsupported version.
+        query.set_edns(Some(Edns {
+            version: 1,
+            ..Edns::new(4096)
+        }));
+        let res = exchange(server, &query.to_udp_packet(None).unwrap()).await;
+        assert_eq!(BADVERS, res.rcode());
+        assert_eq!(0, res.edns().unwrap().unwrap().version);
+
+        // more than one OPT record.
+        let mut query = Message::from_bytes(&QUERY).unwrap();
+        query.additionals.push(Edns::new(512).to_record());
+        query.header.arcount = 2;
+        let res = exchange(server, &query.to_udp_packet(None).unwrap()).await;
+        assert_eq!(FORMERR, res.rcode());
+    }
+
+    #[tokio::test]
+    async fn cache_by_dnssec_bits() {
+        let (upstream, queries) =
+            stand_in_upstream(Reply::answers(vec![a_record(60)]), false).await;
+        let server = start_forwarder(vec![upstream]).await;
+
+        // two clients differing only in DO, then only in CD.
+        let plain = Message::from_bytes(&QUERY).unwrap();
+        let mut dnssec_ok = plain.clone();
+        dnssec_ok.set_edns(Some(Edns {
+            dnssec_ok: true,
+            ..Edns::new(4096)
+        }));
+        let mut checking_disabled = QUERY;
+        checking_disabled[3] |= 0x10;
+        let queries_sent = [
+            QUERY.to_vec(),
+            dnssec_ok.to_udp_packet(None).unwrap(),
+            checking_disabled.to_vec(),
+        ];
+        for (i, query) in queries_sent.iter().enumerate() {
+            let res = exchange(server, query).await;
+            assert_eq!(1, res.answers.len());
+            assert_eq!(i + 1, queries.load(Ordering::SeqCst));
+        }
+
+        // each of them is answered from its own entry.
+        for query in &queries_sent {
+            let res = exchange(server, query).await;
+            assert_eq!(1, res.answers.len());
+        }
+        assert_eq!(3, queries.load(Ordering::SeqCst));
+        let res = exchange(server, &queries_sent[1]).await;
+        assert!(res.edns().unwrap().unwrap().dnssec_ok);
+    }
+
+    #[tokio::test]
+    async fn negative_caching() {
+        let reply = Reply {
+            rcode: NXDOMAIN,
+            authorities: vec![soa_record()],
+            ..Reply::default()
+        };
+        let (upstream, queries) = stand_in_upstream(reply, false).await;
+        let server = start_forwarder(vec![upstream]).await;
+
+        for _ in 0..2 {
+            let res = exchange(server, &QUERY).await;
+            assert_eq!(NXDOMAIN, res.rcode());
+            assert!(res.answers.is_empty());
+            assert!(matches!(res.authorities[0].rdata, RData::Soa(_)));
+            // the TTL of the negative answer is the SOA minimum.
+            assert!(res.authorities[0].ttl <= 30);
+        }
+        assert_eq!(1, queries.load(Ordering::SeqCst));
+
+        // NODATA, a name without records of the type.
+        let reply = Reply {
+            authorities: vec![soa_record()],
+            ..Reply::default()
+        };
+        let (upstream, queries) = stand_in_upstream(reply, false).await;
+        let server = start_forwarder(vec![upstream]).await;
+        for _ in 0..2 {
+            let res = exchange(server, &QUERY).await;
+            assert_eq!(0, res.rcode());
+            assert!(res.answers.is_empty());
+            assert_eq!(1, res.authorities.len());
+        }
+        assert_eq!(1, queries.load(Ordering::SeqCst));
+
+        // without an SOA record the negative answer is not cached.
+        let reply = Reply {
+            rcode: NXDOMAIN,
+            ..Reply::default()
+        };
+        let (upstream, queries) = stand_in_upstream(reply, false).await;
+        let server = start_forwarder(vec![upstream]).await;
+        for _ in 0..2 {
+            assert_eq!(NXDOMAIN, exchange(server, &QUERY).await.rcode());
+        }
+        assert_eq!(2, queries.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn cached_response_is_complete() {
+        let ns = ResourceRecord {
+            name: "google.com".to_string(),
+            rtype: NS,
+            rclass: 1,
+            ttl: 600,
+            rdata: RData::Ns("ns1.google.com".to_string()),
+        };
+        let glue = ResourceRecord {
+            name: "ns1.google.com".to_string(),
+            ..a_record(600)
+        };
+        let reply = Reply {
+            answers: vec![a_record(300)],
+            authorities: vec![ns],
+            additionals: vec![glue],
+            ..Reply::default()
+        };
+        let (upstream, queries) = stand_in_upstream(reply, false).await;
+        let server = start_forwarder(vec![upstream]).await;
+
+        let first = exchange(server, &QUERY).await;
+        let cached = exchange(server, &QUERY).await;
+        assert_eq!(1, queries.load(Ordering::SeqCst));
+        assert_eq!(1, cached.header.flags.ra());
+        assert_eq!(
+            format!("{:?}", first.header),
+            format!("{:?}", cached.header)
+        );
+        for (first, cached) in [
+            (&first.answers, &cached.answers),
+            (&first.authorities, &cached.authorities),
+            (&first.additionals, &cached.additionals),
+        ] {
+            assert_eq!(first.len(), cached.len());
+            for (first, cached) in first.iter().zip(cached) {
+                assert_eq!(first.name, cached.name);
+                assert_eq!(first.rdata, cached.rdata);
+                assert!(cached.ttl <= first.ttl);
             }
         }
+        assert_eq!(1, cached.authorities.len());
+        // the glue and our OPT record.
+        assert_eq!(2, cached.additionals.len());
     }
 }
@@ -0,0 +1,39 @@
+use std::io::{Error, ErrorKind, Result};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::protocol::Message;
+
+// DNS over TCP (RFC 1035 4.2.2, RFC 7766) prefixes each message with its length as a u16. A
+// connection carries any number of messages in both directions.
+
+/// Read the next message of a connection, `None` if the peer closed it between messages.
+pub async fn read_message<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+    match r.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    msg: &Message,
+    alt_id: Option<u16>,
+) -> Result<()> {
+    w.write_all(&frame(msg.to_udp_packet(alt_id)?)?).await
+}
+
+/// Prefix an encoded message with its length.
+pub fn frame(packet: Vec<u8>) -> Result<Vec<u8>> {
+    let len = u16::try_from(packet.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "message too large for TCP"))?;
+    let mut framed = Vec::with_capacity(packet.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend(packet);
+    Ok(framed)
+}
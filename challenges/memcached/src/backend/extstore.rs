@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tracing::error;
+
+use crate::data::{CHUNK_SIZE, Data};
+use crate::protocol::Value;
+
+/// A sealed segment is compacted once at least this fraction of its bytes are dead.
+const COMPACTION_THRESHOLD: f64 = 0.5;
+
+/// A segment is an append-only file of item data. Items are addressed by a slot index rather than
+/// by their offset, so compaction can move the data of a segment without the cache entries that
+/// reference it having to be updated.
+struct Segment {
+    path: PathBuf,
+    /// shared with the reads in progress, which keep reading the old file after a compaction.
+    file: Arc<File>,
+    len: u64,
+    /// offset and length of the data of each slot, `None` once the slot has been released.
+    slots: Vec<Option<(u64, u32)>>,
+    dead_bytes: u64,
+    sealed: bool,
+    compacting: bool,
+}
+
+impl Segment {
+    fn create(path: PathBuf) -> Result<Segment> {
+        Ok(Segment {
+            file: Arc::new(create_file(&path)?),
+            path,
+            len: 0,
+            slots: Vec::new(),
+            dead_bytes: 0,
+            sealed: false,
+            compacting: false,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.slots.iter().all(Option::is_none)
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.sealed
+            && !self.compacting
+            && self.len > 0
+            && self.dead_bytes as f64 / self.len as f64 >= COMPACTION_THRESHOLD
+    }
+}
+
+/// The live slots of a sealed segment, copied into a fresh file without holding the lock.
+struct Compaction {
+    id: u32,
+    tmp_path: PathBuf,
+    file: Arc<File>,
+    slots: Vec<Option<(u64, u32)>>,
+}
+
+/// The copy made by a compaction, with the offsets of the slots in it.
+struct Compacted {
+    file: File,
+    len: u64,
+    slots: Vec<Option<(u64, u32)>>,
+}
+
+impl Compaction {
+    fn copy(&self) -> Result<Compacted> {
+        let mut tmp = create_file(&self.tmp_path)?;
+        let mut tmp_len = 0;
+        let mut slots = Vec::with_capacity(self.slots.len());
+        for slot in &self.slots {
+            let Some((offset, len)) = *slot else {
+                slots.push(None);
+                continue;
+            };
+            for chunk in read_at(&self.file, offset, len as usize)?.chunks() {
+                tmp.write_all(chunk)?;
+            }
+            slots.push(Some((tmp_len, len)));
+            tmp_len += len as u64;
+        }
+        Ok(Compacted {
+            file: tmp,
+            len: tmp_len,
+            slots,
+        })
+    }
+}
+
+struct Segments {
+    dir: PathBuf,
+    segment_size: u64,
+    next_id: u32,
+    active: u32,
+    segments: HashMap<u32, Segment>,
+}
+
+impl Segments {
+    fn open_segment(&mut self) -> Result<u32> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let segment = Segment::create(self.dir.join(format!("segment-{}.dat", id)))?;
+        self.segments.insert(id, segment);
+        Ok(id)
+    }
+
+    /// Seal the active segment and open a new one. Sealed segments with enough dead space are
+    /// compacted after a roll, this bounds the amount of compaction work to one per segment
+    /// written.
+    fn roll(&mut self) -> Result<()> {
+        if let Some(active) = self.segments.get_mut(&self.active) {
+            active.sealed = true;
+        }
+        self.active = self.open_segment()?;
+        Ok(())
+    }
+
+    fn start_compaction(&mut self) -> Option<Compaction> {
+        let (&id, segment) = self
+            .segments
+            .iter_mut()
+            .find(|(_, segment)| segment.needs_compaction())?;
+        segment.compacting = true;
+        Some(Compaction {
+            id,
+            tmp_path: segment.path.with_extension("compact"),
+            file: segment.file.clone(),
+            slots: segment.slots.clone(),
+        })
+    }
+
+    /// Swap the compacted file in. The slots released while the copy was made are dead space in
+    /// the new file.
+    fn finish_compaction(&mut self, compaction: Compaction, copy: Result<Compacted>) -> Result<()> {
+        let Some(segment) = self.segments.get_mut(&compaction.id) else {
+            // every slot was released during the copy and the segment has been deleted.
+            let _ = fs::remove_file(&compaction.tmp_path);
+            return Ok(());
+        };
+        segment.compacting = false;
+        let renamed = copy.and_then(|copy| {
+            fs::rename(&compaction.tmp_path, &segment.path)?;
+            Ok(copy)
+        });
+        let compacted = match renamed {
+            Ok(copy) => copy,
+            Err(err) => {
+                let _ = fs::remove_file(&compaction.tmp_path);
+                return Err(err);
+            }
+        };
+        let mut dead_bytes = 0;
+        segment.slots = segment
+            .slots
+            .iter()
+            .zip(compacted.slots)
+            .map(|(slot, offset)| match (slot, offset) {
+                (Some(_), offset) => offset,
+                (None, Some((_, len))) => {
+                    dead_bytes += len as u64;
+                    None
+                }
+                (None, None) => None,
+            })
+            .collect();
+        segment.file = Arc::new(compacted.file);
+        segment.len = compacted.len;
+        segment.dead_bytes = dead_bytes;
+        Ok(())
+    }
+
+    fn release(&mut self, id: u32, slot: u32) {
+        let Some(segment) = self.segments.get_mut(&id) else {
+            return;
+        };
+        if let Some((_, len)) = segment.slots[slot as usize].take() {
+            segment.dead_bytes += len as u64;
+        }
+        if segment.sealed && segment.is_empty() {
+            let segment = self.segments.remove(&id).unwrap();
+            let _ = fs::remove_file(segment.path);
+        }
+    }
+}
+
+/// A flash tier for large values, modelled after memcached's extstore. The data of an item is
+/// appended to the active segment file and the cache keeps an `Extent` to it in place of the
+/// data.
+///
+/// The file I/O runs on the blocking thread pool. The lock on the segments is only held to update
+/// their metadata, never while data is read or written.
+#[derive(Clone)]
+pub(crate) struct ExtStore {
+    segments: Arc<Mutex<Segments>>,
+    /// serialises the appends to the active segment.
+    writer: Arc<Mutex<()>>,
+}
+
+impl ExtStore {
+    /// Open an extstore in `dir`. Cache contents do not survive a restart, so any existing
+    /// segment files are discarded.
+    pub(crate) fn open(dir: &Path, segment_size: u64) -> Result<ExtStore> {
+        fs::create_dir_all(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|e| e == "dat" || e == "compact")
+            {
+                fs::remove_file(path)?;
+            }
+        }
+        let mut segments = Segments {
+            dir: dir.to_path_buf(),
+            segment_size,
+            next_id: 0,
+            active: 0,
+            segments: HashMap::new(),
+        };
+        segments.active = segments.open_segment()?;
+        Ok(ExtStore {
+            segments: Arc::new(Mutex::new(segments)),
+            writer: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Append the data of `value` to the active segment. Compaction of the sealed segments is
+    /// started in the background if the write rolled the active segment.
+    pub(crate) async fn write(&self, value: Arc<Value>) -> Result<Extent> {
+        let store = self.clone();
+        let (extent, rolled) = tokio::task::spawn_blocking(move || store.append(&value.data))
+            .await
+            .map_err(Error::other)??;
+        if rolled {
+            let store = self.clone();
+            tokio::task::spawn_blocking(move || store.compact());
+        }
+        Ok(extent)
+    }
+
+    /// Append `data` to the active segment, a chunk at a time, returning whether the active
+    /// segment was rolled.
+    fn append(&self, data: &Data) -> Result<(Extent, bool)> {
+        let _writer = self.writer.lock().unwrap();
+        let (id, file, offset, rolled) = {
+            let mut segments = self.segments.lock().unwrap();
+            let active = &segments.segments[&segments.active];
+            let rolled = active.sealed || active.len + data.len() as u64 > segments.segment_size;
+            if rolled {
+                segments.roll()?;
+            }
+            let active = &segments.segments[&segments.active];
+            (segments.active, active.file.clone(), active.len, rolled)
+        };
+        let written = data
+            .chunks()
+            .try_for_each(|chunk| file.as_ref().write_all(chunk));
+
+        let mut segments = self.segments.lock().unwrap();
+        let segment = segments.segments.get_mut(&id).unwrap();
+        if let Err(err) = written {
+            // cut off the part that was written, so `len` stays the end of the file. If that
+            // fails too the segment is sealed and the next write rolls to a new one.
+            if file.set_len(offset).is_err() {
+                segment.sealed = true;
+            }
+            return Err(err);
+        }
+        let slot = segment.slots.len() as u32;
+        segment.slots.push(Some((offset, data.len() as u32)));
+        segment.len += data.len() as u64;
+        let extent = Extent {
+            store: self.segments.clone(),
+            segment: id,
+            slot,
+        };
+        Ok((extent, rolled))
+    }
+
+    /// Compact the sealed segments with enough dead space, one at a time.
+    fn compact(&self) {
+        loop {
+            let Some(compaction) = self.segments.lock().unwrap().start_compaction() else {
+                return;
+            };
+            let copy = compaction.copy();
+            let mut segments = self.segments.lock().unwrap();
+            if let Err(err) = segments.finish_compaction(compaction, copy) {
+                error!("extstore compaction failed: {:?}", err);
+                return;
+            }
+        }
+    }
+
+    /// The total size of the segment files, including dead space awaiting compaction.
+    pub(crate) fn disk_usage(&self) -> u64 {
+        let segments = self.segments.lock().unwrap();
+        segments.segments.values().map(|s| s.len).sum()
+    }
+}
+
+/// A reference to the data of an item in the extstore. The data is released, becoming dead space
+/// in its segment, when the last reference is dropped.
+pub(crate) struct Extent {
+    store: Arc<Mutex<Segments>>,
+    segment: u32,
+    slot: u32,
+}
+
+impl Extent {
+    pub(crate) async fn read(&self) -> Result<Data> {
+        let (file, offset, len) = {
+            let segments = self.store.lock().unwrap();
+            let released = || Error::new(ErrorKind::NotFound, "extent has been released");
+            let segment = segments.segments.get(&self.segment).ok_or_else(released)?;
+            let (offset, len) = segment.slots[self.slot as usize].ok_or_else(released)?;
+            (segment.file.clone(), offset, len)
+        };
+        tokio::task::spawn_blocking(move || read_at(&file, offset, len as usize))
+            .await
+            .map_err(Error::other)?
+    }
+}
+
+fn create_file(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.set_len(0)?;
+    Ok(file)
+}
+
+/// Read `len` bytes at `offset`, chunked if they are over `CHUNK_SIZE` like the data read from a
+/// socket.
+fn read_at(file: &File, offset: u64, len: usize) -> Result<Data> {
+    let mut chunks = Vec::with_capacity(len.div_ceil(CHUNK_SIZE));
+    let mut pos = 0;
+    while pos < len {
+        let mut chunk = vec![0; (len - pos).min(CHUNK_SIZE)];
+        file.read_exact_at(&mut chunk, offset + pos as u64)?;
+        pos += chunk.len();
+        chunks.push(chunk);
+    }
+    Ok(match chunks.len() {
+        0 => Data::default(),
+        1 => Data::Contiguous(chunks.pop().unwrap()),
+        _ => Data::Chunked(chunks),
+    })
+}
+
+impl Drop for Extent {
+    fn drop(&mut self) {
+        if let Ok(mut segments) = self.store.lock() {
+            segments.release(self.segment, self.slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("memcached-{}-{}", name, std::process::id()))
+    }
+
+    fn value(data: impl Into<Data>) -> Arc<Value> {
+        Arc::new(Value {
+            flags: 0,
+            exp_time: 0,
+            cas: 0,
+            data: data.into(),
+            compressed: None,
+        })
+    }
+
+    /// Compaction runs in the background, wait for the disk usage it should leave.
+    async fn wait_for_disk_usage(store: &ExtStore, expected: u64) {
+        for _ in 0..100 {
+            if store.disk_usage() == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(expected, store.disk_usage());
+    }
+
+    #[tokio::test]
+    async fn test_extstore_write_read_release() -> Result<()> {
+        let dir = temp_dir("extstore-release");
+        let store = ExtStore::open(&dir, 16)?;
+
+        let a = store.write(value(b"aaaaaaaa".to_vec())).await?;
+        let b = store.write(value(b"bbbbbbbb".to_vec())).await?;
+        // does not fit in the first segment, rolls to a second one.
+        let c = store.write(value(b"cccc".to_vec())).await?;
+        assert_eq!(b"aaaaaaaa".to_vec(), a.read().await?);
+        assert_eq!(b"bbbbbbbb".to_vec(), b.read().await?);
+        assert_eq!(b"cccc".to_vec(), c.read().await?);
+        assert_eq!(20, store.disk_usage());
+
+        // the first segment is sealed, it is deleted once all its data has been released.
+        drop(a);
+        drop(b);
+        assert_eq!(4, store.disk_usage());
+        assert_eq!(b"cccc".to_vec(), c.read().await?);
+
+        fs::remove_dir_all(dir)
+    }
+
+    #[tokio::test]
+    async fn test_extstore_compaction() -> Result<()> {
+        let dir = temp_dir("extstore-compaction");
+        let store = ExtStore::open(&dir, 12)?;
+
+        let a = store.write(value(b"aaaa".to_vec())).await?;
+        let b = store.write(value(b"bbbb".to_vec())).await?;
+        let c = store.write(value(b"cccc".to_vec())).await?;
+        drop(a);
+        drop(b);
+        // rolling the active segment compacts the sealed segment, it is now 2/3 dead.
+        let d = store.write(value(b"dddd".to_vec())).await?;
+        wait_for_disk_usage(&store, 8).await;
+        assert_eq!(b"cccc".to_vec(), c.read().await?);
+        assert_eq!(b"dddd".to_vec(), d.read().await?);
+
+        fs::remove_dir_all(dir)
+    }
+
+    #[tokio::test]
+    async fn test_extstore_compaction_failure() -> Result<()> {
+        let dir = temp_dir("extstore-compaction-failure");
+        let store = ExtStore::open(&dir, 12)?;
+        // the compacted file cannot be created, the segment is left as it was.
+        fs::create_dir(dir.join("segment-0.compact"))?;
+
+        let a = store.write(value(b"aaaa".to_vec())).await?;
+        let b = store.write(value(b"bbbb".to_vec())).await?;
+        let c = store.write(value(b"cccc".to_vec())).await?;
+        drop(a);
+        drop(b);
+        let d = store.write(value(b"dddd".to_vec())).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(16, store.disk_usage());
+        assert_eq!(b"cccc".to_vec(), c.read().await?);
+        assert_eq!(b"dddd".to_vec(), d.read().await?);
+
+        fs::remove_dir_all(dir)
+    }
+
+    #[tokio::test]
+    async fn test_extstore_write_failure() -> Result<()> {
+        let dir = temp_dir("extstore-write-failure");
+        let store = ExtStore::open(&dir, 16)?;
+        {
+            // a read only file fails the write, and the truncation after it.
+            let mut segments = store.segments.lock().unwrap();
+            let segment = segments.segments.get_mut(&0).unwrap();
+            segment.file = Arc::new(File::open(&segment.path)?);
+        }
+
+        assert!(store.write(value(b"aaaa".to_vec())).await.is_err());
+        assert_eq!(0, store.disk_usage());
+        // the segment has been sealed, the next write goes to a new one.
+        let b = store.write(value(b"bbbb".to_vec())).await?;
+        assert_eq!(b"bbbb".to_vec(), b.read().await?);
+        assert_eq!(4, store.disk_usage());
+
+        fs::remove_dir_all(dir)
+    }
+
+    #[tokio::test]
+    async fn test_extstore_chunked() -> Result<()> {
+        let dir = temp_dir("extstore-chunked");
+        let store = ExtStore::open(&dir, 4 * CHUNK_SIZE as u64)?;
+        let bytes: Vec<u8> = (0..2 * CHUNK_SIZE + 1).map(|i| i as u8).collect();
+        let chunked = Data::concat(
+            &bytes[..CHUNK_SIZE].to_vec().into(),
+            &bytes[CHUNK_SIZE..].to_vec().into(),
+        );
+
+        // the data is read back in chunks, never as a single allocation.
+        let extent = store.write(value(chunked)).await?;
+        let read = extent.read().await?;
+        assert!(matches!(&read, Data::Chunked(chunks) if chunks.len() == 3));
+        assert_eq!(bytes, read);
+
+        fs::remove_dir_all(dir)
+    }
+}
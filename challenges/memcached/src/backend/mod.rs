@@ -0,0 +1,221 @@
+use std::mem::size_of;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use crate::config::{BackendKind, Config, EvictionPolicy};
+use crate::lease::Leases;
+use crate::protocol::Value;
+use crate::watch::Watcher;
+
+mod extstore;
+mod sharded;
+mod tiny_lfu;
+
+pub(crate) use sharded::ShardedBackend;
+pub(crate) use tiny_lfu::MokaBackend;
+
+/// The outcome of a compare-and-swap against a backend.
+#[derive(Debug, PartialEq)]
+pub(crate) enum CasOutcome {
+    Stored,
+    /// The item exists but its cas unique did not match.
+    Exists,
+    NotFound,
+}
+
+/// The metadata of an item, as reported by a key dump.
+#[derive(Debug)]
+pub(crate) struct ItemMeta {
+    /// `None` if the item does not expire.
+    pub(crate) exp: Option<SystemTime>,
+    pub(crate) last_access: SystemTime,
+    pub(crate) cas: u64,
+    pub(crate) flags: u32,
+    /// The weight of the item.
+    pub(crate) size: u32,
+}
+
+/// Convert a monotonic deadline to wall clock time for reporting.
+pub(crate) fn to_system_time(at: Instant) -> SystemTime {
+    let now = Instant::now();
+    if at >= now {
+        SystemTime::now() + (at - now)
+    } else {
+        SystemTime::now() - (now - at)
+    }
+}
+
+/// A storage backend holds the items of the cache. The `StoreProcessor` implements the memcached
+/// command semantics on top of it.
+///
+/// Mutating operations are issued while the processor holds the write lock for the key, so
+/// implementations only have to guarantee that each individual call is atomic.
+pub(crate) trait StorageBackend {
+    async fn get(&self, key: &str) -> Option<Arc<Value>>;
+
+    async fn insert(&self, key: String, value: Arc<Value>);
+
+    /// Replace the value of `key` only if its current cas unique is `cas`.
+    async fn compare_and_swap(&self, key: String, cas: u64, value: Arc<Value>) -> CasOutcome;
+
+    /// Returns true if the key existed.
+    async fn delete(&self, key: &str) -> bool;
+
+    /// Reset the expiry of an existing key. Returns true if the key existed.
+    async fn touch(&self, key: &str, exp_time: u32) -> bool;
+
+    /// The metadata of a live item, the data is not read.
+    async fn meta(&self, key: &str) -> Option<ItemMeta>;
+
+    /// Iterate over the metadata of the live items, the data is not read. The iteration is weakly
+    /// consistent, concurrent mutations may or may not be observed.
+    fn iter(&self) -> impl Iterator<Item = (Arc<String>, ItemMeta)> + Send + '_;
+
+    /// The number of items, this may be an estimate.
+    fn item_count(&self) -> u64;
+
+    /// The number of bytes currently charged against the memory limit.
+    fn memory_usage(&self) -> u64;
+
+    /// The number of items evicted to stay within the memory limit.
+    fn evictions(&self) -> u64;
+}
+
+/// The fixed memory overhead of an item: the `Value` with its `Arc` reference counts, and the
+/// header of the key `String`.
+const ITEM_OVERHEAD: usize = size_of::<Value>() + 2 * size_of::<usize>() + size_of::<String>();
+
+/// The weight of an item, charged against the memory limit of the backend. This is the full
+/// in-memory size of the item, not just its data, so the limit holds for small items too.
+#[inline]
+pub(crate) fn weigh(key: &str, value: &Value) -> u32 {
+    let size = ITEM_OVERHEAD + key.len() + value.data.capacity();
+    size.try_into().unwrap_or(u32::MAX)
+}
+
+/// The backends that can be selected via configuration. Dispatch is static, each variant
+/// forwards to its implementation.
+pub(crate) enum Backend {
+    Moka(MokaBackend),
+    Sharded(ShardedBackend),
+}
+
+impl Backend {
+    /// Evictions are published to `watcher`, and expired values are kept as stale values by
+    /// `leases`. Fails if the backend does not support the eviction policy of `config`.
+    pub(crate) fn new(
+        config: &Config,
+        watcher: Watcher,
+        leases: Option<Arc<Leases>>,
+    ) -> std::io::Result<Backend> {
+        Ok(match config.backend {
+            BackendKind::Moka => Backend::Moka(MokaBackend::new(config, watcher, leases)?),
+            BackendKind::Sharded => Backend::Sharded(ShardedBackend::new(
+                config.memory_limit,
+                config.eviction.unwrap_or(EvictionPolicy::Lru),
+                watcher,
+                leases,
+            )?),
+        })
+    }
+
+    /// The eviction policy in effect, the evictions are reported per policy.
+    pub(crate) fn policy(&self) -> EvictionPolicy {
+        match self {
+            Backend::Moka(b) => b.policy(),
+            Backend::Sharded(b) => b.policy(),
+        }
+    }
+
+    /// Change the memory limit without dropping the items which fit in the new limit. Writes
+    /// must be held off until it completes.
+    pub(crate) async fn resize(&self, memory_limit: u64) {
+        match self {
+            Backend::Moka(b) => b.resize(memory_limit).await,
+            Backend::Sharded(b) => b.resize(memory_limit),
+        }
+    }
+
+    /// The bytes used by the extstore segment files, if the backend has an extstore.
+    pub(crate) fn ext_bytes_used(&self) -> Option<u64> {
+        match self {
+            Backend::Moka(b) => b.ext_bytes_used(),
+            Backend::Sharded(_) => None,
+        }
+    }
+}
+
+impl StorageBackend for Backend {
+    async fn get(&self, key: &str) -> Option<Arc<Value>> {
+        match self {
+            Backend::Moka(b) => b.get(key).await,
+            Backend::Sharded(b) => b.get(key).await,
+        }
+    }
+
+    async fn insert(&self, key: String, value: Arc<Value>) {
+        match self {
+            Backend::Moka(b) => b.insert(key, value).await,
+            Backend::Sharded(b) => b.insert(key, value).await,
+        }
+    }
+
+    async fn compare_and_swap(&self, key: String, cas: u64, value: Arc<Value>) -> CasOutcome {
+        match self {
+            Backend::Moka(b) => b.compare_and_swap(key, cas, value).await,
+            Backend::Sharded(b) => b.compare_and_swap(key, cas, value).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> bool {
+        match self {
+            Backend::Moka(b) => b.delete(key).await,
+            Backend::Sharded(b) => b.delete(key).await,
+        }
+    }
+
+    async fn touch(&self, key: &str, exp_time: u32) -> bool {
+        match self {
+            Backend::Moka(b) => b.touch(key, exp_time).await,
+            Backend::Sharded(b) => b.touch(key, exp_time).await,
+        }
+    }
+
+    async fn meta(&self, key: &str) -> Option<ItemMeta> {
+        match self {
+            Backend::Moka(b) => b.meta(key).await,
+            Backend::Sharded(b) => b.meta(key).await,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Arc<String>, ItemMeta)> + Send + '_ {
+        let (moka, sharded) = match self {
+            Backend::Moka(b) => (Some(b.iter()), None),
+            Backend::Sharded(b) => (None, Some(b.iter())),
+        };
+        moka.into_iter()
+            .flatten()
+            .chain(sharded.into_iter().flatten())
+    }
+
+    fn item_count(&self) -> u64 {
+        match self {
+            Backend::Moka(b) => b.item_count(),
+            Backend::Sharded(b) => b.item_count(),
+        }
+    }
+
+    fn memory_usage(&self) -> u64 {
+        match self {
+            Backend::Moka(b) => b.memory_usage(),
+            Backend::Sharded(b) => b.memory_usage(),
+        }
+    }
+
+    fn evictions(&self) -> u64 {
+        match self {
+            Backend::Moka(b) => b.evictions(),
+            Backend::Sharded(b) => b.evictions(),
+        }
+    }
+}
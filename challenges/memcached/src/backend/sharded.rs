@@ -0,0 +1,457 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use super::{CasOutcome, ItemMeta, StorageBackend, to_system_time, weigh};
+use crate::config::EvictionPolicy;
+use crate::lease::Leases;
+use crate::protocol::Value;
+use crate::watch::{EventKind, InvalidationReason, WatchEvent, Watcher};
+
+struct Entry {
+    value: Arc<Value>,
+    expires_at: Option<Instant>,
+    last_access: SystemTime,
+    /// Position of the entry in the eviction order of the shard.
+    tick: u64,
+    weight: u64,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    fn meta(&self) -> ItemMeta {
+        ItemMeta {
+            exp: self.expires_at.map(to_system_time),
+            last_access: self.last_access,
+            cas: self.value.cas,
+            flags: self.value.flags,
+            size: self.weight as u32,
+        }
+    }
+}
+
+/// A shard is a plain `HashMap` with an eviction order. The order is kept in a `BTreeMap` keyed by
+/// a monotonically increasing tick, the next entry to evict is the first one. With LRU an entry is
+/// moved to the back of the order on access, with FIFO it keeps its insertion position.
+struct Shard {
+    items: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    used: u64,
+    capacity: u64,
+    promote_on_access: bool,
+    watcher: Watcher,
+    /// Keeps the expired values as stale values, when leases are enabled.
+    leases: Option<Arc<Leases>>,
+}
+
+impl Shard {
+    fn new(
+        capacity: u64,
+        promote_on_access: bool,
+        watcher: Watcher,
+        leases: Option<Arc<Leases>>,
+    ) -> Shard {
+        Shard {
+            items: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            used: 0,
+            capacity,
+            promote_on_access,
+            watcher,
+            leases,
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Look up a live entry, marking it as the most recently used under LRU. Expired entries are
+    /// removed lazily when they are encountered, which is when their expiry is published.
+    fn get(&mut self, key: &str) -> Option<&mut Entry> {
+        let now = Instant::now();
+        if self.items.get(key)?.is_expired(now) {
+            if let Some(entry) = self.remove(key)
+                && let Some(leases) = &self.leases
+            {
+                leases.expired(key, entry.value);
+            }
+            self.watcher.invalidate(key, InvalidationReason::Expired);
+            self.watcher
+                .publish(|| WatchEvent::new(EventKind::Expiration, "ttl", key, "expired"));
+            return None;
+        }
+        if !self.promote_on_access {
+            let entry = self.items.get_mut(key)?;
+            entry.last_access = SystemTime::now();
+            return Some(entry);
+        }
+        let tick = self.next_tick();
+        let entry = self.items.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.order.insert(tick, key.to_string());
+        entry.tick = tick;
+        entry.last_access = SystemTime::now();
+        Some(entry)
+    }
+
+    /// Insert an entry, returning the keys evicted to make room for it.
+    fn insert(&mut self, key: String, value: Arc<Value>) -> Vec<String> {
+        self.remove(&key);
+        let weight = weigh(&key, &value) as u64;
+        let tick = self.next_tick();
+        let expires_at = value.ttl().map(|ttl| Instant::now() + ttl);
+        self.order.insert(tick, key.clone());
+        self.items.insert(
+            key,
+            Entry {
+                value,
+                expires_at,
+                last_access: SystemTime::now(),
+                tick,
+                weight,
+            },
+        );
+        self.used += weight;
+        self.evict()
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.items.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.used -= entry.weight;
+        Some(entry)
+    }
+
+    /// Evict entries from the front of the order until the shard is within its capacity.
+    fn evict(&mut self) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.used > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.items.remove(&key) {
+                self.used -= entry.weight;
+                evicted.push(key);
+            }
+        }
+        evicted
+    }
+}
+
+/// A sharded `HashMap` backend with strict LRU or FIFO eviction per shard. Unlike moka, eviction
+/// and weighting happen synchronously on insert, which makes its behaviour deterministic in tests.
+pub(crate) struct ShardedBackend {
+    shards: Vec<Mutex<Shard>>,
+    policy: EvictionPolicy,
+    evictions: AtomicU64,
+    watcher: Watcher,
+}
+
+impl ShardedBackend {
+    pub(crate) fn new(
+        memory_limit: u64,
+        policy: EvictionPolicy,
+        watcher: Watcher,
+        leases: Option<Arc<Leases>>,
+    ) -> Result<ShardedBackend> {
+        Self::with_shards(memory_limit, policy, num_cpus::get(), watcher, leases)
+    }
+
+    /// Fails if the backend does not support `policy`.
+    pub(crate) fn with_shards(
+        memory_limit: u64,
+        policy: EvictionPolicy,
+        shards: usize,
+        watcher: Watcher,
+        leases: Option<Arc<Leases>>,
+    ) -> Result<ShardedBackend> {
+        let promote_on_access = match policy {
+            EvictionPolicy::Lru => true,
+            EvictionPolicy::Fifo => false,
+            EvictionPolicy::TinyLfu => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "the sharded backend does not support TinyLFU eviction",
+                ));
+            }
+        };
+        let capacity = memory_limit / shards as u64;
+        let shards = (0..shards)
+            .map(|_| {
+                let shard =
+                    Shard::new(capacity, promote_on_access, watcher.clone(), leases.clone());
+                Mutex::new(shard)
+            })
+            .collect();
+        Ok(ShardedBackend {
+            shards,
+            policy,
+            evictions: AtomicU64::new(0),
+            watcher,
+        })
+    }
+
+    pub(crate) fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Change the memory limit, evicting the items over the new capacity of each shard.
+    pub(crate) fn resize(&self, memory_limit: u64) {
+        let capacity = memory_limit / self.shards.len() as u64;
+        for shard in &self.shards {
+            let evicted = {
+                let mut shard = shard.lock().unwrap();
+                shard.capacity = capacity;
+                shard.evict()
+            };
+            self.record_evictions(evicted);
+        }
+    }
+
+    fn record_evictions(&self, evicted: Vec<String>) {
+        if evicted.is_empty() {
+            return;
+        }
+        self.evictions
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        for key in evicted {
+            self.watcher.invalidate(&key, InvalidationReason::Evicted);
+            self.watcher
+                .publish(|| WatchEvent::new(EventKind::Eviction, "size", &key, "evicted"));
+        }
+    }
+
+    #[inline]
+    fn shard(&self, key: &str) -> std::sync::MutexGuard<'_, Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let slot = (hasher.finish() % self.shards.len() as u64) as usize;
+        self.shards[slot].lock().unwrap()
+    }
+}
+
+impl StorageBackend for ShardedBackend {
+    async fn get(&self, key: &str) -> Option<Arc<Value>> {
+        self.shard(key).get(key).map(|e| e.value.clone())
+    }
+
+    async fn insert(&self, key: String, value: Arc<Value>) {
+        let evicted = self.shard(&key).insert(key, value);
+        self.record_evictions(evicted);
+    }
+
+    async fn compare_and_swap(&self, key: String, cas: u64, value: Arc<Value>) -> CasOutcome {
+        let mut shard = self.shard(&key);
+        match shard.get(&key) {
+            None => CasOutcome::NotFound,
+            Some(current) if current.value.cas != cas => CasOutcome::Exists,
+            Some(_) => {
+                let evicted = shard.insert(key, value);
+                drop(shard);
+                self.record_evictions(evicted);
+                CasOutcome::Stored
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> bool {
+        let mut shard = self.shard(key);
+        // go through `get` so an expired entry reports as missing.
+        shard.get(key).is_some() && shard.remove(key).is_some()
+    }
+
+    async fn touch(&self, key: &str, exp_time: u32) -> bool {
+        let mut shard = self.shard(key);
+        match shard.get(key) {
+            Some(entry) => {
+                let mut value = entry.value.as_ref().clone();
+                value.exp_time = exp_time;
+                entry.expires_at = value.ttl().map(|ttl| Instant::now() + ttl);
+                entry.value = Arc::new(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn meta(&self, key: &str) -> Option<ItemMeta> {
+        self.shard(key).get(key).map(|e| e.meta())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Arc<String>, ItemMeta)> + Send + '_ {
+        // A shard is snapshotted when the iteration reaches it so the lock is not held while the
+        // caller consumes the items.
+        self.shards.iter().flat_map(|shard| {
+            let now = Instant::now();
+            let shard = shard.lock().unwrap();
+            shard
+                .items
+                .iter()
+                .filter(|(_, e)| !e.is_expired(now))
+                .map(|(k, e)| (Arc::new(k.clone()), e.meta()))
+                .collect::<Vec<_>>()
+        })
+    }
+
+    fn item_count(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().items.len() as u64)
+            .sum()
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.shards.iter().map(|s| s.lock().unwrap().used).sum()
+    }
+
+    fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(data: &[u8], cas: u64) -> Arc<Value> {
+        Arc::new(Value {
+            flags: 0,
+            exp_time: 0,
+            cas,
+            data: data.to_vec().into(),
+            compressed: None,
+        })
+    }
+
+    /// A single shard with room for two items of the given data size.
+    fn two_item_backend(policy: EvictionPolicy) -> ShardedBackend {
+        let item = weigh("a", &value(b"aaaa", 0)) as u64;
+        ShardedBackend::with_shards(item * 2 + 1, policy, 1, Watcher::new(), None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lru_evicts_least_recently_used() {
+        let backend = two_item_backend(EvictionPolicy::Lru);
+        backend.insert("a".to_string(), value(b"aaaa", 1)).await;
+        backend.insert("b".to_string(), value(b"bbbb", 2)).await;
+        // touch `a` so `b` becomes the least recently used.
+        assert!(backend.get("a").await.is_some());
+        backend.insert("c".to_string(), value(b"cccc", 3)).await;
+
+        assert!(backend.get("a").await.is_some());
+        assert!(backend.get("b").await.is_none());
+        assert!(backend.get("c").await.is_some());
+        assert_eq!(
+            2 * weigh("a", &value(b"aaaa", 0)) as u64,
+            backend.memory_usage()
+        );
+        assert_eq!(2, backend.iter().count());
+        assert_eq!(1, backend.evictions());
+    }
+
+    #[tokio::test]
+    async fn test_eviction_events() {
+        let watcher = Watcher::new();
+        let mut events = watcher.subscribe();
+        let mut invalidations = watcher.invalidations();
+        let item = weigh("a", &value(b"aaaa", 0)) as u64;
+        let backend =
+            ShardedBackend::with_shards(item, EvictionPolicy::Lru, 1, watcher, None).unwrap();
+        backend.insert("a".to_string(), value(b"aaaa", 1)).await;
+        backend.insert("b".to_string(), value(b"bbbb", 2)).await;
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(EventKind::Eviction, event.kind);
+        assert_eq!("a", event.key);
+        let invalidation = invalidations.try_recv().unwrap();
+        assert_eq!(
+            ("a", InvalidationReason::Evicted),
+            (invalidation.key.as_str(), invalidation.reason)
+        );
+        let (key, meta) = backend.iter().next().unwrap();
+        assert_eq!("b", key.as_str());
+        assert_eq!(2, meta.cas);
+        assert!(meta.exp.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_resize() {
+        let backend = two_item_backend(EvictionPolicy::Lru);
+        backend.insert("a".to_string(), value(b"aaaa", 1)).await;
+        backend.insert("b".to_string(), value(b"bbbb", 2)).await;
+        assert!(backend.get("a").await.is_some());
+
+        // growing keeps every item, shrinking evicts the least recently used.
+        let item = weigh("a", &value(b"aaaa", 0)) as u64;
+        backend.resize(item * 3);
+        backend.insert("c".to_string(), value(b"cccc", 3)).await;
+        assert_eq!(3, backend.item_count());
+        backend.resize(item);
+        assert_eq!(1, backend.item_count());
+        assert!(backend.get("c").await.is_some());
+        assert_eq!(2, backend.evictions());
+    }
+
+    #[tokio::test]
+    async fn test_fifo_evicts_first_inserted() {
+        let backend = two_item_backend(EvictionPolicy::Fifo);
+        backend.insert("a".to_string(), value(b"aaaa", 1)).await;
+        backend.insert("b".to_string(), value(b"bbbb", 2)).await;
+        // accessing `a` does not protect it from eviction.
+        assert!(backend.get("a").await.is_some());
+        backend.insert("c".to_string(), value(b"cccc", 3)).await;
+
+        assert!(backend.get("a").await.is_none());
+        assert!(backend.get("b").await.is_some());
+        assert!(backend.get("c").await.is_some());
+        assert_eq!(1, backend.evictions());
+    }
+
+    #[test]
+    fn test_unsupported_policy() {
+        let backend =
+            ShardedBackend::with_shards(1024, EvictionPolicy::TinyLfu, 1, Watcher::new(), None);
+        assert!(backend.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lru_compare_and_swap_delete() {
+        let backend =
+            ShardedBackend::with_shards(1024, EvictionPolicy::Lru, 4, Watcher::new(), None)
+                .unwrap();
+        assert_eq!(
+            CasOutcome::NotFound,
+            backend
+                .compare_and_swap("a".to_string(), 1, value(b"x", 2))
+                .await
+        );
+        backend.insert("a".to_string(), value(b"a", 1)).await;
+        assert_eq!(
+            CasOutcome::Exists,
+            backend
+                .compare_and_swap("a".to_string(), 7, value(b"x", 2))
+                .await
+        );
+        assert_eq!(
+            CasOutcome::Stored,
+            backend
+                .compare_and_swap("a".to_string(), 1, value(b"b", 2))
+                .await
+        );
+        assert_eq!(b"b".to_vec(), backend.get("a").await.unwrap().data);
+
+        assert!(backend.delete("a").await);
+        assert!(!backend.delete("a").await);
+        assert_eq!(0, backend.memory_usage());
+    }
+}
@@ -0,0 +1,453 @@
+use std::io::{Error, ErrorKind, Result};
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use arc_swap::{ArcSwap, Guard};
+use moka::future::Cache;
+use moka::notification::RemovalCause;
+use tracing::error;
+
+use super::extstore::{ExtStore, Extent};
+use super::{CasOutcome, ItemMeta, StorageBackend, weigh};
+use crate::config::{Config, EvictionPolicy};
+use crate::data::Data;
+use crate::lease::Leases;
+use crate::protocol::Value;
+use crate::watch::{EventKind, InvalidationReason, WatchEvent, Watcher};
+
+/// The size of the extstore segment files.
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A cache entry. Items spilled to the extstore keep their metadata in `value`, with the data left
+/// empty, and the data is read back through `extent`.
+#[derive(Clone)]
+struct Item {
+    value: Arc<Value>,
+    extent: Option<Arc<Extent>>,
+    expires_at: Option<SystemTime>,
+    /// Seconds since the unix epoch, shared by the clones handed out by the cache.
+    last_access: Arc<AtomicU64>,
+}
+
+impl Item {
+    fn new(value: Arc<Value>, extent: Option<Arc<Extent>>) -> Item {
+        let now = SystemTime::now();
+        Item {
+            expires_at: value.ttl().map(|ttl| now + ttl),
+            value,
+            extent,
+            last_access: Arc::new(AtomicU64::new(unix_secs(now))),
+        }
+    }
+
+    fn record_access(&self) {
+        self.last_access
+            .store(unix_secs(SystemTime::now()), Ordering::Relaxed);
+    }
+
+    fn meta(&self, key: &str) -> ItemMeta {
+        let last_access = self.last_access.load(Ordering::Relaxed);
+        ItemMeta {
+            exp: self.expires_at,
+            last_access: UNIX_EPOCH + Duration::from_secs(last_access),
+            cas: self.value.cas,
+            flags: self.value.flags,
+            size: self.weigh(key),
+        }
+    }
+
+    fn time_to_live(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|at| at.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+
+    fn weigh(&self, key: &str) -> u32 {
+        let extent = match self.extent {
+            Some(_) => size_of::<Extent>() + 2 * size_of::<usize>(),
+            None => 0,
+        };
+        weigh(key, &self.value).saturating_add(extent as u32)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+struct Expiry;
+
+/// expiry is derived from the ttl provided by the user on update and create. It is counted from
+/// when the item was created rather than when it entered the cache, so an item copied into a
+/// resized cache keeps what is left of its ttl.
+impl moka::Expiry<String, Item> for Expiry {
+    fn expire_after_create(&self, _: &String, item: &Item, _: Instant) -> Option<Duration> {
+        item.time_to_live()
+    }
+
+    fn expire_after_update(
+        &self,
+        _: &String,
+        item: &Item,
+        _: Instant,
+        _: Option<Duration>,
+    ) -> Option<Duration> {
+        item.time_to_live()
+    }
+}
+
+/// The default backend. A moka cache which evicts using TinyLFU or LRU, optionally with an
+/// extstore flash tier for large values.
+pub(crate) struct MokaBackend {
+    /// The capacity of a moka cache is fixed, `resize` swaps in a copy of the cache. Loading
+    /// the current cache takes neither a lock nor, usually, a reference count.
+    cache: ArcSwap<Cache<String, Item>>,
+    policy: EvictionPolicy,
+    /// `policy` as configured on the moka caches.
+    moka_policy: moka::policy::EvictionPolicy,
+    watcher: Watcher,
+    leases: Option<Arc<Leases>>,
+    ext: Option<(ExtStore, usize)>,
+    evictions: Arc<AtomicU64>,
+}
+
+impl MokaBackend {
+    /// Fails if the backend does not support the eviction policy, or the extstore cannot be
+    /// opened.
+    pub(crate) fn new(
+        config: &Config,
+        watcher: Watcher,
+        leases: Option<Arc<Leases>>,
+    ) -> Result<MokaBackend> {
+        let policy = config.eviction.unwrap_or(EvictionPolicy::TinyLfu);
+        let moka_policy = match policy {
+            EvictionPolicy::TinyLfu => moka::policy::EvictionPolicy::tiny_lfu(),
+            EvictionPolicy::Lru => moka::policy::EvictionPolicy::lru(),
+            EvictionPolicy::Fifo => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "the moka backend does not support FIFO eviction",
+                ));
+            }
+        };
+        let evictions = Arc::new(AtomicU64::new(0));
+        let cache = Self::build(
+            config.memory_limit,
+            &moka_policy,
+            &watcher,
+            &leases,
+            &evictions,
+        );
+        let ext = match &config.ext_path {
+            Some(path) => Some((ExtStore::open(path, SEGMENT_SIZE)?, config.ext_item_size)),
+            None => None,
+        };
+        Ok(MokaBackend {
+            cache: ArcSwap::from_pointee(cache),
+            policy,
+            moka_policy,
+            watcher,
+            leases,
+            ext,
+            evictions,
+        })
+    }
+
+    fn build(
+        memory_limit: u64,
+        policy: &moka::policy::EvictionPolicy,
+        watcher: &Watcher,
+        leases: &Option<Arc<Leases>>,
+        evictions: &Arc<AtomicU64>,
+    ) -> Cache<String, Item> {
+        let watcher = watcher.clone();
+        let leases = leases.clone();
+        let evictions = evictions.clone();
+        Cache::builder()
+            // Configure the cache with an upper bound as the total byte count of all the items.
+            // The `weighted_size` is updated on a maintenance task which is 100ms by default. Only
+            // the metadata of items in the extstore is held in memory and charged here.
+            .weigher(|key: &String, item: &Item| item.weigh(key))
+            .max_capacity(memory_limit)
+            .eviction_policy(policy.clone())
+            // Provide a strategy for extracting the TTL from the value. TTL is reset on updates.
+            .expire_after(Expiry {})
+            .eviction_listener(move |key, item, cause| match cause {
+                RemovalCause::Size => {
+                    evictions.fetch_add(1, Ordering::Relaxed);
+                    watcher.invalidate(&key, InvalidationReason::Evicted);
+                    watcher
+                        .publish(|| WatchEvent::new(EventKind::Eviction, "size", &key, "evicted"));
+                }
+                RemovalCause::Expired => {
+                    // the data of the items in the extstore is not read back to keep it stale.
+                    if let Some(leases) = &leases
+                        && item.extent.is_none()
+                    {
+                        leases.expired(&key, item.value);
+                    }
+                    watcher.invalidate(&key, InvalidationReason::Expired);
+                    watcher
+                        .publish(|| WatchEvent::new(EventKind::Expiration, "ttl", &key, "expired"))
+                }
+                RemovalCause::Explicit | RemovalCause::Replaced => {}
+            })
+            .build()
+    }
+
+    #[inline]
+    fn cache(&self) -> Guard<Arc<Cache<String, Item>>> {
+        self.cache.load()
+    }
+
+    /// Replace the cache with one of the given capacity holding the same items. When shrinking,
+    /// the items over the new capacity are evicted by the eviction policy. The caller must hold
+    /// off the writes during the copy, or they may be lost.
+    pub(crate) async fn resize(&self, memory_limit: u64) {
+        let old = self.cache();
+        let cache = Self::build(
+            memory_limit,
+            &self.moka_policy,
+            &self.watcher,
+            &self.leases,
+            &self.evictions,
+        );
+        for (key, item) in old.iter() {
+            cache.insert(key.as_ref().clone(), item).await;
+        }
+        cache.run_pending_tasks().await;
+        self.cache.store(Arc::new(cache));
+    }
+
+    pub(crate) fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    pub(crate) fn ext_bytes_used(&self) -> Option<u64> {
+        self.ext.as_ref().map(|(store, _)| store.disk_usage())
+    }
+
+    /// Build the cache entry for a value, spilling the data to the extstore if it is above the
+    /// size threshold. If the write fails the value is kept in memory.
+    async fn to_item(&self, value: Arc<Value>) -> Item {
+        if let Some((store, item_size)) = &self.ext
+            && value.data.len() > *item_size
+        {
+            match store.write(value.clone()).await {
+                Ok(extent) => {
+                    let mut meta = value.as_ref().clone();
+                    meta.data = Data::default();
+                    return Item::new(Arc::new(meta), Some(Arc::new(extent)));
+                }
+                Err(err) => error!("extstore write failed: {:?}", err),
+            }
+        }
+        Item::new(value, None)
+    }
+
+    /// Materialise the value of an entry, reading its data from the extstore if required.
+    async fn to_value(item: Item) -> Result<Arc<Value>> {
+        match item.extent {
+            None => Ok(item.value),
+            Some(extent) => {
+                let mut value = item.value.as_ref().clone();
+                value.data = extent.read().await?;
+                Ok(Arc::new(value))
+            }
+        }
+    }
+}
+
+impl StorageBackend for MokaBackend {
+    async fn get(&self, key: &str) -> Option<Arc<Value>> {
+        let item = self.cache().get(key).await?;
+        item.record_access();
+        match Self::to_value(item).await {
+            Ok(value) => Some(value),
+            Err(err) => {
+                error!("extstore read failed: {:?}", err);
+                self.cache().invalidate(key).await;
+                None
+            }
+        }
+    }
+
+    async fn insert(&self, key: String, value: Arc<Value>) {
+        let item = self.to_item(value).await;
+        self.cache().insert(key, item).await
+    }
+
+    async fn compare_and_swap(&self, key: String, cas: u64, value: Arc<Value>) -> CasOutcome {
+        match self.cache().get(&key).await {
+            None => CasOutcome::NotFound,
+            Some(current) if current.value.cas != cas => CasOutcome::Exists,
+            Some(_) => {
+                self.insert(key, value).await;
+                CasOutcome::Stored
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> bool {
+        self.cache().remove(key).await.is_some()
+    }
+
+    async fn touch(&self, key: &str, exp_time: u32) -> bool {
+        match self.cache().get(key).await {
+            Some(current) => {
+                // the extent is shared by the new entry, so the data is not rewritten.
+                let mut value = current.value.as_ref().clone();
+                value.exp_time = exp_time;
+                let item = Item {
+                    last_access: current.last_access.clone(),
+                    ..Item::new(Arc::new(value), current.extent)
+                };
+                self.cache().insert(key.to_string(), item).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn meta(&self, key: &str) -> Option<ItemMeta> {
+        self.cache().get(key).await.map(|item| item.meta(key))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Arc<String>, ItemMeta)> + Send + '_ {
+        // the items are collected as the cache can be replaced by a resize during the iteration.
+        let items: Vec<_> = self
+            .cache()
+            .iter()
+            .map(|(key, item)| {
+                let meta = item.meta(&key);
+                (key, meta)
+            })
+            .collect();
+        items.into_iter()
+    }
+
+    fn item_count(&self) -> u64 {
+        self.cache().entry_count()
+    }
+
+    fn memory_usage(&self) -> u64 {
+        self.cache().weighted_size()
+    }
+
+    fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_moka_lru_evictions() {
+        let value = Arc::new(Value {
+            flags: 0,
+            exp_time: 0,
+            cas: 1,
+            data: b"value".to_vec().into(),
+            compressed: None,
+        });
+        let config = Config {
+            memory_limit: weigh("k0", &value) as u64 * 2,
+            eviction: Some(EvictionPolicy::Lru),
+            ..Config::default()
+        };
+        let backend = MokaBackend::new(&config, Watcher::new(), None).unwrap();
+        for i in 0..4 {
+            backend.insert(format!("k{}", i), value.clone()).await;
+            backend.cache().run_pending_tasks().await;
+        }
+        assert_eq!(2, backend.evictions());
+        assert!(backend.get("k0").await.is_none());
+        assert!(backend.get("k3").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_moka_resize() {
+        let value = Arc::new(Value {
+            flags: 0,
+            exp_time: 60,
+            cas: 1,
+            data: b"value".to_vec().into(),
+            compressed: None,
+        });
+        let item = weigh("k0", &value) as u64;
+        let config = Config {
+            memory_limit: item * 4,
+            eviction: Some(EvictionPolicy::Lru),
+            ..Config::default()
+        };
+        let backend = MokaBackend::new(&config, Watcher::new(), None).unwrap();
+        for i in 0..4 {
+            backend.insert(format!("k{}", i), value.clone()).await;
+        }
+        backend.cache().run_pending_tasks().await;
+
+        backend.resize(item * 8).await;
+        assert_eq!(4, backend.item_count());
+        let meta = backend.meta("k0").await.unwrap();
+        assert!(meta.exp.is_some());
+        backend.resize(item * 2).await;
+        assert_eq!(2, backend.item_count());
+        assert_eq!(2, backend.evictions());
+    }
+
+    #[tokio::test]
+    async fn test_moka_extstore_spill() {
+        let dir = std::env::temp_dir().join(format!("memcached-moka-ext-{}", std::process::id()));
+        let config = Config {
+            ext_path: Some(dir.clone()),
+            ext_item_size: 8,
+            ..Config::default()
+        };
+        let backend = MokaBackend::new(&config, Watcher::new(), None).unwrap();
+        let value = |data: &[u8]| {
+            Arc::new(Value {
+                flags: 3,
+                exp_time: 0,
+                cas: 1,
+                data: data.to_vec().into(),
+                compressed: None,
+            })
+        };
+        backend.insert("small".to_string(), value(b"small")).await;
+        backend
+            .insert("large".to_string(), value(b"a large value"))
+            .await;
+        backend.cache().run_pending_tasks().await;
+
+        // only the small value, and the metadata of the large one, is held in memory.
+        let small = backend.cache().get("small").await.unwrap();
+        let large = backend.cache().get("large").await.unwrap();
+        assert_eq!(0, large.value.data.len());
+        assert_eq!(
+            (small.weigh("small") + large.weigh("large")) as u64,
+            backend.memory_usage()
+        );
+        assert_eq!(Some(13), backend.ext_bytes_used());
+
+        let large = backend.get("large").await.unwrap();
+        assert_eq!(b"a large value".to_vec(), large.data);
+        assert_eq!(3, large.flags);
+
+        // the data survives a touch, and is released on delete.
+        assert!(backend.touch("large", 60).await);
+        assert_eq!(
+            b"a large value".to_vec(),
+            backend.get("large").await.unwrap().data
+        );
+        assert!(backend.delete("large").await);
+        backend.cache().run_pending_tasks().await;
+        assert!(backend.get("large").await.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,21 +1,325 @@
-use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use memcached::config::{
+    BackendKind, Config, ConfigFile, EvictionPolicy, NamespaceConfig, PoolConfig, RouteConfig,
+};
+use memcached::dump::DumpFormat;
+use memcached::server::Frontend;
 use tokio::net::TcpListener;
 use tokio::signal;
+use tokio::signal::unix::SignalKind;
+use tokio::sync::mpsc;
+use tracing::error;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{EnvFilter, fmt, reload};
 
 #[derive(Parser, Debug)]
 #[clap(name = "memcached")]
 struct Cli {
+    /// Run a tool against a running server instead of serving.
+    #[clap(subcommand)]
+    tool: Option<Tool>,
+
     #[clap(short = 'p', default_value = "9999")]
     port: u16,
+
+    /// Also serve the store over the Redis protocol on this port.
+    #[clap(long)]
+    resp_port: Option<u16>,
+
+    /// Also serve the store over the HTTP/JSON gateway on this port.
+    #[clap(long)]
+    http_port: Option<u16>,
+
+    /// Memory limit of the cache in megabytes, 1024 by default.
+    #[clap(short = 'm')]
+    memory_limit: Option<u64>,
+
+    /// The storage backend.
+    #[clap(long, value_enum, default_value_t = BackendKind::Moka)]
+    backend: BackendKind,
+
+    /// The eviction policy, defaults to TinyLFU for moka and LRU for the sharded backend.
+    #[clap(long, value_enum)]
+    eviction: Option<EvictionPolicy>,
+
+    /// Directory for the extstore, values larger than `--ext-item-size` are kept on disk.
+    #[clap(long)]
+    ext_path: Option<PathBuf>,
+
+    /// Size in bytes above which values are written to the extstore.
+    #[clap(long, default_value = "65536")]
+    ext_item_size: usize,
+
+    /// Issue leases on `gets` misses, valid for the given number of seconds.
+    #[clap(long)]
+    lease_ttl: Option<u64>,
+
+    /// Sample one in this many key accesses for `stats hotkeys`, zero disables it.
+    #[clap(long, default_value = "100")]
+    hotkey_sample_rate: u32,
+
+    /// Store values of at least this many bytes compressed.
+    #[clap(long)]
+    compress_threshold: Option<usize>,
+
+    /// A namespace as `name:quota_mb[:max_ttl_secs]`, may be repeated. Keys prefixed with `name:`
+    /// belong to the namespace.
+    #[clap(long = "namespace", value_parser = parse_namespace)]
+    namespaces: Vec<NamespaceConfig>,
+
+    /// The largest value accepted in bytes, e.g. 134217728 for 128MB. 1MB by default.
+    #[clap(short = 'I', long)]
+    max_item_size: Option<usize>,
+
+    /// A pool of backend servers as `name=host:port[,host:port...]`, may be repeated.
+    #[clap(long = "pool", value_parser = parse_pool)]
+    pools: Vec<PoolConfig>,
+
+    /// Run as a proxy, forwarding the keys starting with `prefix` to `pool` and a copy of the
+    /// traffic to `shadow_pool`. Given as `prefix=pool[,shadow_pool]` with `*` as the prefix of
+    /// the catch-all route, may be repeated.
+    #[clap(long = "route", value_parser = parse_route)]
+    routes: Vec<RouteConfig>,
+
+    /// Log the commands taking at least this many milliseconds, including writing the reply.
+    #[clap(long)]
+    slow_command_ms: Option<u64>,
+
+    /// The format of the logs, filtered with `RUST_LOG`.
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// A TOML file with `memory_limit_mb`, `max_item_size`, `slow_command_ms` and the `log`
+    /// filter, the flags override its settings. It is read again on SIGHUP and applied without
+    /// dropping the connections.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Seconds given to the connections to complete their commands on shutdown.
+    #[clap(long, default_value = "10")]
+    drain_timeout_secs: u64,
+
+    /// Allow the `shutdown` command.
+    #[clap(short = 'A', long)]
+    enable_shutdown: bool,
+
+    /// Require `auth <token>` on a connection before accepting the admin commands,
+    /// `cache_memlimit`, `item_size_max` and `shutdown`.
+    #[clap(long)]
+    admin_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    Text,
+    /// One JSON object per line, with the fields of the connection and command spans.
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Tool {
+    /// Export every item of a server to a file.
+    Dump {
+        /// The address of the server.
+        #[clap(long, default_value = "127.0.0.1:9999")]
+        server: String,
+        #[clap(long, value_enum, default_value_t = DumpFormat::Json)]
+        format: DumpFormat,
+        file: PathBuf,
+    },
+    /// Store the items of a dump in a server.
+    Load {
+        /// The address of the server.
+        #[clap(long, default_value = "127.0.0.1:9999")]
+        server: String,
+        #[clap(long, value_enum, default_value_t = DumpFormat::Json)]
+        format: DumpFormat,
+        file: PathBuf,
+    },
+}
+
+fn parse_namespace(s: &str) -> Result<NamespaceConfig, String> {
+    let mut parts = s.split(':');
+    let name = parts.next().unwrap_or_default().to_string();
+    let quota: u64 = parts
+        .next()
+        .and_then(|q| q.parse().ok())
+        .ok_or("expected name:quota_mb[:max_ttl_secs]")?;
+    let max_ttl = match parts.next() {
+        None => None,
+        Some(ttl) => Some(ttl.parse().map_err(|_| "invalid max ttl")?),
+    };
+    if parts.next().is_some() {
+        return Err("expected name:quota_mb[:max_ttl_secs]".to_string());
+    }
+    Ok(NamespaceConfig {
+        name,
+        memory_limit: quota * 1024 * 1024,
+        max_ttl,
+    })
+}
+
+fn parse_pool(s: &str) -> Result<PoolConfig, String> {
+    let (name, servers) = s
+        .split_once('=')
+        .ok_or("expected name=host:port[,host:port...]")?;
+    Ok(PoolConfig {
+        name: name.to_string(),
+        servers: servers.split(',').map(str::to_string).collect(),
+    })
+}
+
+fn parse_route(s: &str) -> Result<RouteConfig, String> {
+    let (prefix, pools) = s
+        .split_once('=')
+        .ok_or("expected prefix=pool[,shadow_pool]")?;
+    let (pool, shadow) = match pools.split_once(',') {
+        Some((pool, shadow)) => (pool, Some(shadow.to_string())),
+        None => (pools, None),
+    };
+    Ok(RouteConfig {
+        prefix: if prefix == "*" { "" } else { prefix }.to_string(),
+        pool: pool.to_string(),
+        shadow,
+    })
+}
+
+/// The log filter given in the config file, or else the one of the RUST_LOG env var.
+fn log_filter(directives: Option<&str>) -> EnvFilter {
+    let builder = EnvFilter::builder().with_default_directive(LevelFilter::INFO.into());
+    match directives {
+        Some(directives) => builder.parse_lossy(directives),
+        None => builder.from_env_lossy(),
+    }
+}
+
+/// Apply the config file to `base`, and the settings of the flags on top.
+fn apply_config(base: &Config, file: &ConfigFile, flags: &ConfigFile) -> std::io::Result<Config> {
+    let mut config = base.clone();
+    file.apply(&mut config);
+    flags.apply(&mut config);
+    config.validate()?;
+    Ok(config)
+}
+
+/// Read the config file again and apply it like at startup.
+async fn reload_config(
+    path: &Path,
+    base: &Config,
+    flags: &ConfigFile,
+) -> std::io::Result<(Config, EnvFilter)> {
+    let file = ConfigFile::read(path).await?;
+    let config = apply_config(base, &file, flags)?;
+    Ok((config, log_filter(file.log.as_deref())))
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    // install global collector configured based on RUST_LOG env var.
-    tracing_subscriber::fmt::init();
-
     let args = Cli::parse();
-    let listener = TcpListener::bind(&format!("127.0.0.1:{}", args.port)).await?;
-    memcached::server::run(listener, signal::ctrl_c()).await;
+    let file = match &args.config {
+        Some(path) => ConfigFile::read(path).await?,
+        None => ConfigFile::default(),
+    };
+
+    // install global collector, the filter is swapped when the config file is reloaded.
+    let (filter, filter_handle) = reload::Layer::new(log_filter(file.log.as_deref()));
+    let logs = match args.log_format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(logs)
+        .init();
+
+    match args.tool {
+        Some(Tool::Dump {
+            server,
+            format,
+            file,
+        }) => {
+            let count = memcached::dump::dump(&server, &file, format).await?;
+            println!("dumped {} items to {}", count, file.display());
+            return Ok(());
+        }
+        Some(Tool::Load {
+            server,
+            format,
+            file,
+        }) => {
+            let loaded = memcached::dump::load(&server, &file, format).await?;
+            println!(
+                "loaded {} items from {}, skipped {}",
+                loaded.stored,
+                file.display(),
+                loaded.skipped
+            );
+            return Ok(());
+        }
+        None => {}
+    }
+    // the settings which may be set in the config file, the flags take precedence.
+    let flags = ConfigFile {
+        memory_limit_mb: args.memory_limit,
+        max_item_size: args.max_item_size,
+        slow_command_ms: args.slow_command_ms,
+        log: None,
+    };
+    let base = Config {
+        backend: args.backend,
+        eviction: args.eviction,
+        ext_path: args.ext_path,
+        ext_item_size: args.ext_item_size,
+        lease_ttl: args.lease_ttl.map(Duration::from_secs),
+        hotkey_sample_rate: args.hotkey_sample_rate,
+        compress_threshold: args.compress_threshold,
+        namespaces: args.namespaces,
+        pools: args.pools,
+        routes: args.routes,
+        drain_timeout: Duration::from_secs(args.drain_timeout_secs),
+        enable_shutdown: args.enable_shutdown,
+        admin_token: args.admin_token,
+        ..Config::default()
+    };
+    let config = apply_config(&base, &file, &flags)?;
+
+    let (reload_tx, reload_rx) = mpsc::channel(1);
+    if let Some(path) = args.config {
+        let mut hangup = signal::unix::signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match reload_config(&path, &base, &flags).await {
+                    Ok((config, filter)) => {
+                        if let Err(err) = filter_handle.reload(filter) {
+                            error!(cause = %err, "failed to reload the log filter");
+                        }
+                        if reload_tx.send(config).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        error!(cause = %err, "invalid configuration, keeping the current one")
+                    }
+                }
+            }
+        });
+    }
+    let mut listeners = vec![(
+        Frontend::Memcached,
+        TcpListener::bind(&format!("127.0.0.1:{}", args.port)).await?,
+    )];
+    if let Some(port) = args.resp_port {
+        let listener = TcpListener::bind(&format!("127.0.0.1:{}", port)).await?;
+        listeners.push((Frontend::Resp, listener));
+    }
+    if let Some(port) = args.http_port {
+        let listener = TcpListener::bind(&format!("127.0.0.1:{}", port)).await?;
+        listeners.push((Frontend::Http, listener));
+    }
+    memcached::server::run(listeners, config, signal::ctrl_c(), reload_rx).await?;
     Ok(())
 }
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::error;
+
+use crate::data::Data;
+use crate::protocol::Value;
+
+#[derive(Debug, Default)]
+struct Stats {
+    /// The uncompressed size of the live compressed values.
+    bytes_in: AtomicU64,
+    /// The compressed size of the live compressed values.
+    bytes_out: AtomicU64,
+}
+
+/// Marks a value as compressed. Its sizes are charged to the stats of the compressor until the
+/// last copy of the value is dropped, so the stats follow deletes, overwrites and evictions.
+#[derive(Debug)]
+pub(crate) struct Compressed {
+    stats: Arc<Stats>,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl Drop for Compressed {
+    fn drop(&mut self) {
+        self.stats
+            .bytes_in
+            .fetch_sub(self.bytes_in, Ordering::Relaxed);
+        self.stats
+            .bytes_out
+            .fetch_sub(self.bytes_out, Ordering::Relaxed);
+    }
+}
+
+/// The values compare equal whichever compressor accounts for them.
+impl PartialEq for Compressed {
+    fn eq(&self, other: &Compressed) -> bool {
+        self.bytes_in == other.bytes_in && self.bytes_out == other.bytes_out
+    }
+}
+
+/// Compresses values larger than `threshold` with LZ4. The data is only kept compressed when it
+/// shrinks, so the cost of incompressible values is limited to a wasted compression pass.
+pub(crate) struct Compressor {
+    threshold: usize,
+    stats: Arc<Stats>,
+}
+
+impl Compressor {
+    pub(crate) fn new(threshold: usize) -> Compressor {
+        Compressor {
+            threshold,
+            stats: Arc::default(),
+        }
+    }
+
+    /// Compress the data of `value` in place if it is over the threshold. Chunked values are left
+    /// as they are, compressing them would need the contiguous allocation chunking avoids.
+    pub(crate) fn compress(&self, value: &mut Value) {
+        let Data::Contiguous(data) = &value.data else {
+            return;
+        };
+        if value.compressed.is_some() || data.len() < self.threshold {
+            return;
+        }
+        let mut compressed = lz4_flex::compress_prepend_size(data);
+        if compressed.len() >= data.len() {
+            return;
+        }
+        compressed.shrink_to_fit();
+        let (bytes_in, bytes_out) = (data.len() as u64, compressed.len() as u64);
+        self.stats.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.stats.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+        value.data = compressed.into();
+        value.compressed = Some(Arc::new(Compressed {
+            stats: self.stats.clone(),
+            bytes_in,
+            bytes_out,
+        }));
+    }
+
+    /// The value as the client stored it. A value which fails to decompress is treated as a miss.
+    pub(crate) fn decompress(&self, key: &str, value: Arc<Value>) -> Option<Arc<Value>> {
+        if value.compressed.is_none() {
+            return Some(value);
+        }
+        match lz4_flex::decompress_size_prepended(&value.data.contiguous()) {
+            Ok(data) => Some(Arc::new(Value {
+                flags: value.flags,
+                exp_time: value.exp_time,
+                cas: value.cas,
+                data: data.into(),
+                compressed: None,
+            })),
+            Err(err) => {
+                error!("could not decompress {}: {:?}", key, err);
+                None
+            }
+        }
+    }
+
+    /// The uncompressed and compressed size of the live values stored compressed.
+    pub(crate) fn bytes(&self) -> (u64, u64) {
+        (
+            self.stats.bytes_in.load(Ordering::Relaxed),
+            self.stats.bytes_out.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(data: &[u8]) -> Value {
+        Value {
+            flags: 7,
+            exp_time: 0,
+            cas: 1,
+            data: data.to_vec().into(),
+            compressed: None,
+        }
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let compressor = Compressor::new(64);
+        let data = br#"{"name":"value","tags":["a","b"]}"#.repeat(32);
+        let mut val = value(&data);
+        compressor.compress(&mut val);
+        assert!(val.compressed.is_some());
+        assert!(val.data.len() < data.len());
+        assert_eq!(
+            (data.len() as u64, val.data.len() as u64),
+            compressor.bytes()
+        );
+
+        let stored = Arc::new(val);
+        let val = compressor.decompress("key", stored.clone()).unwrap();
+        assert!(val.compressed.is_none());
+        assert_eq!(7, val.flags);
+        assert_eq!(data, val.data);
+
+        // the sizes are released with the last copy of the compressed value.
+        let copy = stored.as_ref().clone();
+        drop(stored);
+        assert_eq!(data.len() as u64, compressor.bytes().0);
+        drop(copy);
+        assert_eq!((0, 0), compressor.bytes());
+    }
+
+    #[test]
+    fn test_compress_skips_small_and_incompressible() {
+        let compressor = Compressor::new(64);
+        let mut small = value(&b"a".repeat(63));
+        compressor.compress(&mut small);
+        assert!(small.compressed.is_none());
+
+        // a sequence without repeats does not shrink.
+        let noise: Vec<u8> = (0..=255u8).collect();
+        let mut incompressible = value(&noise);
+        compressor.compress(&mut incompressible);
+        assert!(incompressible.compressed.is_none());
+        assert_eq!(noise, incompressible.data);
+        assert_eq!((0, 0), compressor.bytes());
+    }
+
+    #[test]
+    fn test_decompress_corrupt_is_miss() {
+        let compressor = Compressor::new(64);
+        let mut val = value(b"garbage");
+        val.compressed = Some(Arc::new(Compressed {
+            stats: compressor.stats.clone(),
+            bytes_in: 0,
+            bytes_out: 0,
+        }));
+        assert_eq!(None, compressor.decompress("key", Arc::new(val)));
+    }
+}
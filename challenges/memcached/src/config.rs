@@ -0,0 +1,247 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::connection::MAX_DATA_SIZE;
+
+/// The storage backend used by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum BackendKind {
+    /// A moka cache, supports TinyLFU and LRU eviction.
+    #[default]
+    Moka,
+    /// A sharded `HashMap`, supports LRU and FIFO eviction. Eviction is deterministic which makes
+    /// it mostly useful for tests.
+    Sharded,
+}
+
+/// The policy used to select items for eviction when the memory limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EvictionPolicy {
+    /// Admission by frequency with LRU eviction, the moka default.
+    TinyLfu,
+    /// Evict the least recently used item.
+    Lru,
+    /// Evict the least recently inserted item.
+    Fifo,
+}
+
+/// The upper bound of `Config::max_item_size`.
+pub const MAX_ITEM_SIZE_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// The separator between the namespace and the rest of a key.
+pub const NAMESPACE_SEPARATOR: char = ':';
+
+/// A namespace owns the keys prefixed with its name and the separator, e.g. `team:key`. Each
+/// namespace is an isolated cache with its own memory quota, so filling one does not evict the
+/// items of another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamespaceConfig {
+    pub name: String,
+    /// The memory quota in bytes, taken out of the memory limit of the server.
+    pub memory_limit: u64,
+    /// The longest time to live in seconds an item of the namespace may have. Items stored
+    /// without an expiry are given this one.
+    pub max_ttl: Option<u32>,
+}
+
+/// A pool of memcached servers for the proxy, as `host:port` addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    pub name: String,
+    pub servers: Vec<String>,
+}
+
+/// A proxy route, the keys starting with `prefix` are forwarded to `pool`. An empty prefix
+/// matches every key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteConfig {
+    pub prefix: String,
+    pub pool: String,
+    /// A pool receiving a copy of the traffic of the route, e.g. to warm up a new cluster.
+    pub shadow: Option<String>,
+}
+
+/// Server configuration, populated from the command line by the binary.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub backend: BackendKind,
+    /// Upper bound in bytes of the data held by the cache.
+    pub memory_limit: u64,
+    /// The eviction policy, `None` selects the default of the backend.
+    pub eviction: Option<EvictionPolicy>,
+    /// Directory of the extstore segment files. When set, values larger than `ext_item_size`
+    /// are written to disk and only their metadata is kept in memory. Only supported by the moka
+    /// backend.
+    pub ext_path: Option<PathBuf>,
+    pub ext_item_size: usize,
+    /// When set, a `gets` miss issues a lease which lasts for this long. See `lease.rs`.
+    pub lease_ttl: Option<Duration>,
+    /// One in this many key accesses is sampled for hot key detection, zero disables it.
+    pub hotkey_sample_rate: u32,
+    /// When set, values of at least this many bytes are stored LZ4 compressed. See
+    /// `compression.rs`.
+    pub compress_threshold: Option<usize>,
+    /// Keys outside of a namespace share what is left of `memory_limit` after the quotas.
+    pub namespaces: Vec<NamespaceConfig>,
+    /// The largest value in bytes accepted by a storage command. Values over 512KiB are stored
+    /// in chunks.
+    pub max_item_size: usize,
+    /// The pools of backend servers of the proxy.
+    pub pools: Vec<PoolConfig>,
+    /// When set, the memcached frontend runs as a proxy, forwarding the commands to the pools
+    /// instead of serving them from the local store. See `proxy.rs`.
+    pub routes: Vec<RouteConfig>,
+    /// Commands taking at least this long are logged as slow, including the time to write the
+    /// reply.
+    pub slow_command: Option<Duration>,
+    /// How long the connections are given to complete their in-flight commands on shutdown.
+    pub drain_timeout: Duration,
+    /// Allow the `shutdown` command.
+    pub enable_shutdown: bool,
+    /// When set, the admin commands are only accepted after `auth <token>`.
+    pub admin_token: Option<String>,
+}
+
+/// The `--config` file. It is read again on `SIGHUP` and applied without a restart, the command
+/// line flags override its settings.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The memory limit in megabytes, including the quotas of the namespaces.
+    pub memory_limit_mb: Option<u64>,
+    pub max_item_size: Option<usize>,
+    pub slow_command_ms: Option<u64>,
+    /// A `RUST_LOG` style filter, e.g. `info,memcached::server=debug`.
+    pub log: Option<String>,
+}
+
+impl ConfigFile {
+    pub async fn read(path: &Path) -> std::io::Result<ConfigFile> {
+        let content = tokio::fs::read_to_string(path).await?;
+        toml::from_str(&content).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })
+    }
+
+    /// Override the settings of `config` set in the file.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(mb) = self.memory_limit_mb {
+            config.memory_limit = mb * 1024 * 1024;
+        }
+        if let Some(max_item_size) = self.max_item_size {
+            config.max_item_size = max_item_size;
+        }
+        if let Some(ms) = self.slow_command_ms {
+            config.slow_command = Some(Duration::from_millis(ms));
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            backend: BackendKind::default(),
+            memory_limit: 1024 * 1024 * 1024, // 1GB
+            eviction: None,
+            ext_path: None,
+            ext_item_size: 64 * 1024,
+            lease_ttl: None,
+            hotkey_sample_rate: 100,
+            compress_threshold: None,
+            namespaces: Vec::new(),
+            max_item_size: MAX_DATA_SIZE as usize,
+            pools: Vec::new(),
+            routes: Vec::new(),
+            slow_command: None,
+            drain_timeout: Duration::from_secs(10),
+            enable_shutdown: false,
+            admin_token: None,
+        }
+    }
+}
+
+impl Config {
+    /// Check that the options are supported by the selected backend.
+    pub fn validate(&self) -> std::io::Result<()> {
+        let supported = match (self.backend, self.eviction) {
+            (_, None) => true,
+            (BackendKind::Moka, Some(policy)) => policy != EvictionPolicy::Fifo,
+            (BackendKind::Sharded, Some(policy)) => policy != EvictionPolicy::TinyLfu,
+        };
+        if !supported {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "the {:?} backend does not support {:?} eviction",
+                    self.backend, self.eviction
+                ),
+            ));
+        }
+        if self.ext_path.is_some() && self.backend != BackendKind::Moka {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the extstore requires the moka backend",
+            ));
+        }
+        if self.max_item_size > MAX_ITEM_SIZE_LIMIT {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "the max item size is limited to {} bytes",
+                    MAX_ITEM_SIZE_LIMIT
+                ),
+            ));
+        }
+        let mut names = std::collections::HashSet::new();
+        for ns in &self.namespaces {
+            let valid_name = !ns.name.is_empty()
+                && !ns.name.contains(NAMESPACE_SEPARATOR)
+                && !ns.name.contains(char::is_whitespace);
+            if !valid_name || !names.insert(ns.name.as_str()) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid or duplicate namespace: {:?}", ns.name),
+                ));
+            }
+        }
+        if self.namespace_quotas() >= self.memory_limit {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the namespace quotas must be less than the memory limit",
+            ));
+        }
+        self.validate_proxy()
+    }
+
+    fn validate_proxy(&self) -> std::io::Result<()> {
+        let mut pools = std::collections::HashSet::new();
+        for pool in &self.pools {
+            if pool.name.is_empty() || pool.servers.is_empty() || !pools.insert(pool.name.as_str())
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid, empty or duplicate pool: {:?}", pool.name),
+                ));
+            }
+        }
+        for route in &self.routes {
+            for pool in std::iter::once(&route.pool).chain(&route.shadow) {
+                if !pools.contains(pool.as_str()) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("route {:?} to unknown pool {:?}", route.prefix, pool),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The sum of the memory quotas of the namespaces.
+    pub(crate) fn namespace_quotas(&self) -> u64 {
+        self.namespaces.iter().map(|ns| ns.memory_limit).sum()
+    }
+}
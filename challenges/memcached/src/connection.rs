@@ -1,5 +1,8 @@
+use std::fmt::Display;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use futures_util::FutureExt;
 use tokio::{
     io::{
         AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, Result,
@@ -10,7 +13,13 @@ use tokio::{
     },
 };
 
-use crate::protocol::{Command, RetrievalCommand, StorageCommand, StorageCommandType, Value};
+use crate::backend::ItemMeta;
+use crate::data::Data;
+use crate::lease::LeaseGrant;
+use crate::protocol::{
+    Command, CommandError, RetrievalCommand, StatsGroup, StorageCommand, StorageCommandType, Value,
+};
+use crate::watch::EventKind;
 
 // A buffered reader is used in combination with a Vec to make seeking the end of the command
 // precise/easier and enabling data to be read directly. We don't just save a copy, but by using
@@ -35,21 +44,115 @@ impl Connection {
         }
     }
 
-    pub(crate) async fn read_command(&mut self) -> Result<Command> {
-        read_command(&mut self.reader, &mut self.buffer).await
+    /// wait until data is received, false if the client closed the connection. Unlike
+    /// `read_command` this is cancel safe, so a command is never abandoned half read.
+    pub(crate) async fn readable(&mut self) -> Result<bool> {
+        Ok(!self.reader.fill_buf().await?.is_empty())
+    }
+
+    /// true if data has been received which has not been read as a command yet, including the
+    /// data waiting in the socket.
+    pub(crate) fn has_received(&mut self) -> Result<bool> {
+        match self.reader.fill_buf().now_or_never() {
+            Some(buf) => Ok(!buf?.is_empty()),
+            None => Ok(false),
+        }
+    }
+
+    /// read the next command, storage commands with more than `max_item_size` bytes of data are
+    /// rejected.
+    pub(crate) async fn read_command(
+        &mut self,
+        max_item_size: usize,
+    ) -> Result<std::result::Result<Command, CommandError>> {
+        read_command(&mut self.reader, &mut self.buffer, max_item_size).await
     }
 
-    pub(crate) async fn write_value(&mut self, key: &String, val: Arc<Value>) -> Result<()> {
+    /// write a value line and its data block, the cas unique is included for `gets`.
+    pub(crate) async fn write_value(
+        &mut self,
+        key: &str,
+        val: Arc<Value>,
+        with_cas: bool,
+    ) -> Result<()> {
         self.writer.write_all(b"VALUE ").await?;
         self.writer.write_all(key.as_bytes()).await?;
-        self.writer
-            .write_all(format!(" {} {}\r\n", val.flags, val.data.len()).as_bytes())
-            .await?;
-        self.writer.write_all(&val.data).await?;
+        let header = if with_cas {
+            format!(" {} {} {}\r\n", val.flags, val.data.len(), val.cas)
+        } else {
+            format!(" {} {}\r\n", val.flags, val.data.len())
+        };
+        self.writer.write_all(header.as_bytes()).await?;
+        for chunk in val.data.chunks() {
+            self.writer.write_all(chunk).await?;
+        }
         self.writer.write_all(b"\r\n").await?;
         Ok(())
     }
 
+    /// write the outcome of a lease request on a `gets` miss.
+    pub(crate) async fn write_lease(&mut self, key: &str, grant: LeaseGrant) -> Result<()> {
+        match grant {
+            LeaseGrant::Granted(token) => {
+                self.writer
+                    .write_all(format!("LEASE {} {}\r\n", key, token).as_bytes())
+                    .await
+            }
+            LeaseGrant::Wait => {
+                self.writer
+                    .write_all(format!("LEASE_WAIT {}\r\n", key).as_bytes())
+                    .await
+            }
+            LeaseGrant::Stale(val) => {
+                let header = format!(
+                    "VALUE {} {} {} {} STALE\r\n",
+                    key,
+                    val.flags,
+                    val.data.len(),
+                    val.cas
+                );
+                self.writer.write_all(header.as_bytes()).await?;
+                for chunk in val.data.chunks() {
+                    self.writer.write_all(chunk).await?;
+                }
+                self.writer.write_all(b"\r\n").await
+            }
+        }
+    }
+
+    /// write a `metadump` line. Keys are percent encoded as they may contain any printable
+    /// character.
+    pub(crate) async fn write_meta(&mut self, key: &str, meta: &ItemMeta) -> Result<()> {
+        let unix = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let exp = meta.exp.map_or(-1, |exp| unix(exp) as i64);
+        let line = format!(
+            "key={} exp={} la={} cas={} flags={} size={}\r\n",
+            percent_encode(key),
+            exp,
+            unix(meta.last_access),
+            meta.cas,
+            meta.flags,
+            meta.size
+        );
+        self.writer.write_all(line.as_bytes()).await
+    }
+
+    pub(crate) async fn write_stat(&mut self, name: &str, value: impl Display) -> Result<()> {
+        self.writer
+            .write_all(format!("STAT {} {}\r\n", name, value).as_bytes())
+            .await
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        self.writer.flush().await
+    }
+
+    /// write a reply as is, e.g. one relayed from another server.
+    pub(crate) async fn write_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        self.writer.write_all(bytes).await?;
+        self.writer.flush().await
+    }
+
     pub(crate) async fn write_response(&mut self, bytes: &[u8]) -> Result<()> {
         self.writer.write_all(bytes).await?;
         self.writer.write_all(b"\r\n").await?;
@@ -58,136 +161,282 @@ impl Connection {
     }
 }
 
-async fn read_command<R: AsyncBufRead + Unpin>(r: &mut R, buf: &mut Vec<u8>) -> Result<Command> {
+/// read a command and its data block. A malformed command or an item over `max_item_size` is
+/// returned as the error to reply with, its data block is skipped so the next command can be read.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+    buf: &mut Vec<u8>,
+    max_item_size: usize,
+) -> Result<std::result::Result<Command, CommandError>> {
     buf.clear();
     let len = r.read_until(b'\n', buf).await?;
+    if len == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
     let buf = &buf[..len];
-    if &buf[len - 2..] != b"\r\n" {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "command not terminated with CRLF",
-        ));
+    if !buf.ends_with(b"\r\n") {
+        let err = "command not terminated with CRLF".to_string();
+        return Ok(Err(CommandError::Client(err)));
     }
-    match parse_partial_command(&buf[..len - 2])? {
-        Command::Storage(mut com) => {
-            let mut data = vec![0; com.byte_count as usize];
-            r.read_exact(&mut data).await?;
-            let mut terminal = [0u8; 2];
-            r.read_exact(&mut terminal).await?;
-            if &terminal != b"\r\n" {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "data not terminated with CRLF",
-                ));
-            }
-            com.data = data;
-            Ok(Command::Storage(com))
+    let mut com = match parse_partial_command(&buf[..len - 2]) {
+        Ok(Command::Storage(com)) => com,
+        other => return Ok(other),
+    };
+    let byte_count = com.byte_count as usize;
+    if byte_count > max_item_size {
+        let mut data = (&mut *r).take(byte_count as u64 + 2);
+        tokio::io::copy(&mut data, &mut tokio::io::sink()).await?;
+        let err = "object too large for cache".to_string();
+        return Ok(Err(CommandError::Server(err)));
+    }
+    // large values are streamed into chunks rather than one allocation.
+    let data = Data::read_from(r, byte_count).await?;
+    let mut terminal = [0u8; 2];
+    r.read_exact(&mut terminal).await?;
+    if &terminal != b"\r\n" {
+        return Ok(Err(CommandError::Client("bad data chunk".to_string())));
+    }
+    com.data = data;
+    Ok(Ok(Command::Storage(com)))
+}
+
+fn percent_encode(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for b in key.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
         }
-        other => Ok(other),
     }
+    encoded
 }
 
-const MAX_DATA_SIZE: u32 = 1024 * 1024;
-const MAX_KEY_SIZE: usize = 250;
+/// The default item size limit.
+pub(crate) const MAX_DATA_SIZE: u32 = 1024 * 1024;
+pub(crate) const MAX_KEY_SIZE: usize = 250;
+
+/// Whether `key` is a valid key. Keys are written out in replies, watch events and stats lines
+/// where whitespace separates the fields, so whitespace and control characters are rejected
+/// whichever protocol the key arrived on.
+pub(crate) fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_SIZE
+        && !key.contains(|c: char| c.is_whitespace() || c.is_control())
+}
 
 /// parse a partial command,
-fn parse_partial_command(command_line: &[u8]) -> Result<Command> {
+fn parse_partial_command(command_line: &[u8]) -> std::result::Result<Command, CommandError> {
     let mut parts = command_line
         .split(|&b| b == b' ')
         .filter(|part| !part.is_empty());
 
-    let command = parts
-        .next()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "missing command"))?;
+    let command = parts.next().ok_or(CommandError::Unknown)?;
+
+    match command {
+        b"stats" => {
+            let group = match parts.next() {
+                None => StatsGroup::General,
+                Some(b"hotkeys") => StatsGroup::HotKeys,
+                Some(b"namespaces") => StatsGroup::Namespaces,
+                Some(_) => {
+                    return Err(CommandError::Client("unsupported stats group".to_string()));
+                }
+            };
+            if parts.next().is_some() {
+                return Err(CommandError::Client("malformed stats command".to_string()));
+            }
+            return Ok(Command::Stats(group));
+        }
+        b"lru_crawler" => {
+            let args: Vec<_> = parts.collect();
+            if args != [b"metadump".as_slice(), b"all".as_slice()] {
+                return Err(CommandError::Client(
+                    "only `lru_crawler metadump all` is supported".to_string(),
+                ));
+            }
+            return Ok(Command::MetaDump);
+        }
+        b"watch" => {
+            let kinds = parts
+                .map(|kind| {
+                    EventKind::from_bytes(kind).ok_or_else(|| {
+                        CommandError::Client(format!(
+                            "unknown watch type: {:?}",
+                            std::str::from_utf8(kind)
+                        ))
+                    })
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            return Ok(Command::Watch(kinds));
+        }
+        b"subscribe" => {
+            let prefixes = parts
+                .map(|prefix| {
+                    std::str::from_utf8(prefix)
+                        .ok()
+                        .filter(|p| p.len() <= MAX_KEY_SIZE)
+                        .map(str::to_string)
+                        .ok_or_else(|| CommandError::Client("malformed prefix".to_string()))
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            return Ok(Command::Subscribe(prefixes));
+        }
+        b"cache_memlimit" | b"item_size_max" => {
+            let value = read_int(&mut parts, "limit")?;
+            if parts.next().is_some() {
+                return Err(CommandError::Client("malformed limit command".to_string()));
+            }
+            return Ok(if command == b"cache_memlimit" {
+                Command::CacheMemLimit(value)
+            } else {
+                Command::ItemSizeMax(value as usize)
+            });
+        }
+        b"shutdown" => {
+            if parts.next().is_some() {
+                return Err(CommandError::Client(
+                    "malformed shutdown command".to_string(),
+                ));
+            }
+            return Ok(Command::Shutdown);
+        }
+        b"auth" => {
+            let token = parts.next().and_then(|t| std::str::from_utf8(t).ok());
+            let (Some(token), None) = (token, parts.next()) else {
+                return Err(CommandError::Client("malformed auth command".to_string()));
+            };
+            return Ok(Command::Auth(token.to_string()));
+        }
+        _ => {}
+    }
+
+    let keyed = matches!(
+        command,
+        b"get" | b"gets" | b"namespace" | b"delete" | b"touch"
+    );
+    if !keyed && StorageCommandType::from_bytes(command).is_none() {
+        return Err(CommandError::Unknown);
+    }
     let key = parts
         .next()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "missing key"))?;
+        .ok_or_else(|| CommandError::Client("missing key".to_string()))?;
     if key.len() > MAX_KEY_SIZE {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "key too long",
-        ));
+        return Err(CommandError::Client("key too long".to_string()));
     }
     let key = std::str::from_utf8(key)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed key"))?;
-
-    if command == b"get" {
-        if parts.next().is_some() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "malformed get command",
-            ));
-        }
-        return Ok(Command::Retrieval(RetrievalCommand::Get {
-            key: key.to_string(),
-        }));
-    }
-
-    let st_command_type = StorageCommandType::from_bytes(command).ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::InvalidData, "unrecognised command")
-    })?;
-
-    let mut read_int = |field_id: &str| -> std::io::Result<u32> {
-        let value = parts.next().ok_or_else(|| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("missing numeric field {}", field_id),
-            )
-        })?;
-        let value = std::str::from_utf8(value).map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("invalid numeric field {}", field_id),
-            )
-        })?;
-        value.parse().map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("invalid numeric field{}", field_id),
-            )
-        })
-    };
+        .ok()
+        .filter(|key| is_valid_key(key))
+        .ok_or_else(|| CommandError::Client("malformed key".to_string()))?
+        .to_string();
 
-    let flags = read_int("flags")?;
-    let exptime = read_int("exptime")?;
-    let byte_count = read_int("byte_count")?;
-
-    if byte_count > MAX_DATA_SIZE {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "data too large",
-        ));
+    match command {
+        b"get" | b"gets" => {
+            if parts.next().is_some() {
+                return Err(CommandError::Client("malformed get command".to_string()));
+            }
+            let cmd = if command == b"get" {
+                RetrievalCommand::Get { key }
+            } else {
+                RetrievalCommand::Gets { key }
+            };
+            return Ok(Command::Retrieval(cmd));
+        }
+        b"namespace" => {
+            if parts.next().is_some() {
+                return Err(CommandError::Client(
+                    "malformed namespace command".to_string(),
+                ));
+            }
+            return Ok(Command::Namespace(key));
+        }
+        b"delete" => {
+            let no_reply = read_no_reply(&mut parts)?;
+            return Ok(Command::Delete { key, no_reply });
+        }
+        b"touch" => {
+            let exp_time = read_int(&mut parts, "exptime")?;
+            let no_reply = read_no_reply(&mut parts)?;
+            return Ok(Command::Touch {
+                key,
+                exp_time,
+                no_reply,
+            });
+        }
+        _ => {}
     }
 
-    let no_reply: bool = match parts.next() {
-        Some(b"noreply") => true,
-        None => false,
-        Some(x) => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("malformed extra tag: {:?}", std::str::from_utf8(x)),
-            ));
-        }
+    let st_command_type = StorageCommandType::from_bytes(command).ok_or(CommandError::Unknown)?;
+
+    let flags = read_int(&mut parts, "flags")?;
+    let exptime = read_int(&mut parts, "exptime")?;
+    let byte_count: u32 = read_int(&mut parts, "byte_count")?;
+
+    let cas_unique = if st_command_type == StorageCommandType::Cas {
+        read_int(&mut parts, "cas_unique")?
+    } else {
+        0
     };
+
+    let no_reply = read_no_reply(&mut parts)?;
     Ok(Command::Storage(StorageCommand {
         command: st_command_type,
         no_reply,
         byte_count,
         flags,
-        key: key.to_string(),
+        key,
         exp_time: exptime,
-        data: Vec::new(),
+        cas_unique,
+        data: Data::default(),
     }))
 }
 
+fn read_int<'a, T: std::str::FromStr>(
+    parts: &mut impl Iterator<Item = &'a [u8]>,
+    field_id: &str,
+) -> std::result::Result<T, CommandError> {
+    let value = parts
+        .next()
+        .ok_or_else(|| CommandError::Client(format!("missing numeric field {}", field_id)))?;
+    let value = std::str::from_utf8(value)
+        .map_err(|_| CommandError::Client(format!("invalid numeric field {}", field_id)))?;
+    value
+        .parse()
+        .map_err(|_| CommandError::Client(format!("invalid numeric field {}", field_id)))
+}
+
+/// read the optional trailing `noreply` tag, it must be the last part of the command.
+fn read_no_reply<'a>(
+    parts: &mut impl Iterator<Item = &'a [u8]>,
+) -> std::result::Result<bool, CommandError> {
+    let no_reply = match parts.next() {
+        Some(b"noreply") => true,
+        None => false,
+        Some(x) => {
+            return Err(CommandError::Client(format!(
+                "malformed extra tag: {:?}",
+                std::str::from_utf8(x)
+            )));
+        }
+    };
+    if let Some(x) = parts.next() {
+        return Err(CommandError::Client(format!(
+            "malformed extra tag: {:?}",
+            std::str::from_utf8(x)
+        )));
+    }
+    Ok(no_reply)
+}
+
 #[cfg(test)]
 mod test {
     use std::io::Cursor;
 
     use tokio::io::BufReader;
 
-    use crate::connection::{parse_partial_command, read_command};
-    use crate::protocol::{Command, StorageCommandType};
+    use crate::connection::{MAX_DATA_SIZE, parse_partial_command, percent_encode, read_command};
+    use crate::protocol::{Command, CommandError, StatsGroup, StorageCommandType};
+    use crate::watch::EventKind;
 
     #[test]
     fn test_parse_partial_command() {
@@ -203,6 +452,93 @@ mod test {
             }
             _ => panic!(),
         }
+        assert!(parse_partial_command(b"get a\tb").is_err());
+        assert!(parse_partial_command(b"get a\x1b").is_err());
+    }
+
+    #[test]
+    fn test_parse_admin_commands() {
+        assert!(matches!(
+            parse_partial_command(b"cache_memlimit 512").unwrap(),
+            Command::CacheMemLimit(512)
+        ));
+        assert!(matches!(
+            parse_partial_command(b"item_size_max 2097152").unwrap(),
+            Command::ItemSizeMax(2097152)
+        ));
+        assert!(parse_partial_command(b"shutdown").unwrap().is_admin());
+        match parse_partial_command(b"auth secret").unwrap() {
+            Command::Auth(token) => assert_eq!("secret", token),
+            _ => panic!(),
+        }
+        assert!(parse_partial_command(b"cache_memlimit").is_err());
+        assert!(parse_partial_command(b"cache_memlimit 1 2").is_err());
+        assert!(parse_partial_command(b"auth").is_err());
+    }
+
+    #[test]
+    fn test_parse_partial_command_cas_delete_touch() {
+        match parse_partial_command(b"cas key 1 60 4 42 noreply").unwrap() {
+            Command::Storage(com) => {
+                assert_eq!(com.command, StorageCommandType::Cas);
+                assert_eq!(com.cas_unique, 42);
+                assert!(com.no_reply);
+            }
+            _ => panic!(),
+        }
+        match parse_partial_command(b"delete key").unwrap() {
+            Command::Delete { key, no_reply } => {
+                assert_eq!(key, "key");
+                assert!(!no_reply);
+            }
+            _ => panic!(),
+        }
+        match parse_partial_command(b"touch key 10 noreply").unwrap() {
+            Command::Touch {
+                key,
+                exp_time,
+                no_reply,
+            } => {
+                assert_eq!(key, "key");
+                assert_eq!(exp_time, 10);
+                assert!(no_reply);
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(
+            parse_partial_command(b"stats").unwrap(),
+            Command::Stats(StatsGroup::General)
+        ));
+        assert!(matches!(
+            parse_partial_command(b"stats hotkeys").unwrap(),
+            Command::Stats(StatsGroup::HotKeys)
+        ));
+        assert!(matches!(
+            parse_partial_command(b"stats namespaces").unwrap(),
+            Command::Stats(StatsGroup::Namespaces)
+        ));
+        match parse_partial_command(b"namespace team").unwrap() {
+            Command::Namespace(name) => assert_eq!(name, "team"),
+            _ => panic!(),
+        }
+        assert!(matches!(
+            parse_partial_command(b"lru_crawler metadump all").unwrap(),
+            Command::MetaDump
+        ));
+        match parse_partial_command(b"watch fetchers evictions").unwrap() {
+            Command::Watch(kinds) => {
+                assert_eq!(kinds, vec![EventKind::Fetch, EventKind::Eviction])
+            }
+            _ => panic!(),
+        }
+        assert!(parse_partial_command(b"watch everything").is_err());
+        let touch = parse_partial_command(b"touch key 10").unwrap();
+        assert_eq!(("touch", Some("key")), (touch.name(), touch.key()));
+        match parse_partial_command(b"subscribe user: session:").unwrap() {
+            Command::Subscribe(prefixes) => assert_eq!(prefixes, vec!["user:", "session:"]),
+            _ => panic!(),
+        }
+        assert_eq!("a%20b%2Fc", percent_encode("a b/c"));
     }
 
     #[tokio::test]
@@ -210,8 +546,32 @@ mod test {
         let cursor = Cursor::new(b"set key 0 60 5\r\nvalue\r\n");
         let mut br = BufReader::new(cursor);
         let mut vec = Vec::new();
-        let res = read_command(&mut br, &mut vec).await?;
-        println!("{:?}", res);
+        match read_command(&mut br, &mut vec, MAX_DATA_SIZE as usize).await? {
+            Ok(Command::Storage(com)) => assert_eq!(b"value", &*com.data.contiguous()),
+            other => panic!("unexpected {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_command_errors() -> std::io::Result<()> {
+        let input = b"set big 0 0 6\r\ntoobig\r\nfoo bar\r\nget\r\nset key 0 0 2\r\nabc\r\n\
+            delete key\r\n";
+        let mut br = BufReader::new(Cursor::new(input));
+        let mut vec = Vec::new();
+        let mut next = async || read_command(&mut br, &mut vec, 4).await;
+        // the data block of an item over the max item size is skipped.
+        let too_large = CommandError::Server("object too large for cache".to_string());
+        assert_eq!(Err(too_large), next().await?.map(|_| ()));
+        assert_eq!(Err(CommandError::Unknown), next().await?.map(|_| ()));
+        let missing_key = CommandError::Client("missing key".to_string());
+        assert_eq!(Err(missing_key), next().await?.map(|_| ()));
+        // a data block longer than announced is out of sync, its remains are read as a command.
+        let bad_chunk = CommandError::Client("bad data chunk".to_string());
+        assert_eq!(Err(bad_chunk), next().await?.map(|_| ()));
+        let no_crlf = CommandError::Client("command not terminated with CRLF".to_string());
+        assert_eq!(Err(no_crlf), next().await?.map(|_| ()));
+        assert!(matches!(next().await?, Ok(Command::Delete { .. })));
         Ok(())
     }
 }
@@ -0,0 +1,176 @@
+use std::borrow::Cow;
+use std::mem::size_of;
+
+use tokio::io::{AsyncRead, AsyncReadExt, Result};
+
+/// The size of the chunks of a chunked value.
+pub(crate) const CHUNK_SIZE: usize = 512 * 1024;
+
+/// The data of a value. Values larger than `CHUNK_SIZE` are held as a list of chunks, so a large
+/// item never needs a single contiguous allocation, neither when it is read from the socket nor
+/// while it is stored.
+#[derive(Debug, Clone)]
+pub(crate) enum Data {
+    Contiguous(Vec<u8>),
+    /// Every chunk but the last is `CHUNK_SIZE` bytes.
+    Chunked(Vec<Vec<u8>>),
+}
+
+impl Data {
+    /// Read `len` bytes, chunked if they are over `CHUNK_SIZE`.
+    pub(crate) async fn read_from<R: AsyncRead + Unpin>(r: &mut R, len: usize) -> Result<Data> {
+        if len <= CHUNK_SIZE {
+            let mut data = vec![0; len];
+            r.read_exact(&mut data).await?;
+            return Ok(Data::Contiguous(data));
+        }
+        let mut chunks = Vec::with_capacity(len.div_ceil(CHUNK_SIZE));
+        let mut remaining = len;
+        while remaining > 0 {
+            let mut chunk = vec![0; remaining.min(CHUNK_SIZE)];
+            r.read_exact(&mut chunk).await?;
+            remaining -= chunk.len();
+            chunks.push(chunk);
+        }
+        Ok(Data::Chunked(chunks))
+    }
+
+    /// Concatenate `a` and `b`, chunking the result if it is over `CHUNK_SIZE`.
+    pub(crate) fn concat(a: &Data, b: &Data) -> Data {
+        let len = a.len() + b.len();
+        let slices = a.chunks().chain(b.chunks());
+        if len <= CHUNK_SIZE {
+            let mut data = Vec::with_capacity(len);
+            slices.for_each(|s| data.extend_from_slice(s));
+            return Data::Contiguous(data);
+        }
+
+        let mut chunks = Vec::with_capacity(len.div_ceil(CHUNK_SIZE));
+        let mut remaining = len;
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        for mut s in slices {
+            while !s.is_empty() {
+                let n = (CHUNK_SIZE - chunk.len()).min(s.len());
+                chunk.extend_from_slice(&s[..n]);
+                s = &s[n..];
+                remaining -= n;
+                if chunk.len() == CHUNK_SIZE {
+                    let next = Vec::with_capacity(remaining.min(CHUNK_SIZE));
+                    chunks.push(std::mem::replace(&mut chunk, next));
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+        Data::Chunked(chunks)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Data::Contiguous(data) => data.len(),
+            Data::Chunked(chunks) => chunks.iter().map(Vec::len).sum(),
+        }
+    }
+
+    /// The bytes allocated for the data, including the chunk list.
+    pub(crate) fn capacity(&self) -> usize {
+        match self {
+            Data::Contiguous(data) => data.capacity(),
+            Data::Chunked(chunks) => {
+                let list = chunks.capacity() * size_of::<Vec<u8>>();
+                list + chunks.iter().map(Vec::capacity).sum::<usize>()
+            }
+        }
+    }
+
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        let (contiguous, chunked) = match self {
+            Data::Contiguous(data) => (Some(data.as_slice()), None),
+            Data::Chunked(chunks) => (None, Some(chunks.iter().map(Vec::as_slice))),
+        };
+        contiguous.into_iter().chain(chunked.into_iter().flatten())
+    }
+
+    /// The data as a single slice, chunked data is copied.
+    pub(crate) fn contiguous(&self) -> Cow<'_, [u8]> {
+        match self {
+            Data::Contiguous(data) => Cow::Borrowed(data),
+            Data::Chunked(chunks) => Cow::Owned(chunks.concat()),
+        }
+    }
+}
+
+impl Default for Data {
+    fn default() -> Self {
+        Data::Contiguous(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(data: Vec<u8>) -> Self {
+        Data::Contiguous(data)
+    }
+}
+
+/// Data is equal if the bytes are equal, regardless of how they are chunked.
+impl PartialEq for Data {
+    fn eq(&self, other: &Data) -> bool {
+        self.len() == other.len() && self.chunks().flatten().eq(other.chunks().flatten())
+    }
+}
+
+impl PartialEq<Data> for Vec<u8> {
+    fn eq(&self, other: &Data) -> bool {
+        self.len() == other.len() && self.iter().eq(other.chunks().flatten())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_chunked() -> Result<()> {
+        let input = bytes(2 * CHUNK_SIZE + 10);
+        let data = Data::read_from(&mut Cursor::new(input.clone()), input.len()).await?;
+        match &data {
+            Data::Chunked(chunks) => {
+                let lens: Vec<_> = chunks.iter().map(Vec::len).collect();
+                assert_eq!(vec![CHUNK_SIZE, CHUNK_SIZE, 10], lens);
+            }
+            Data::Contiguous(_) => panic!("expected chunks"),
+        }
+        assert_eq!(input, data);
+        assert_eq!(input, data.contiguous().into_owned());
+
+        let small = Data::read_from(&mut Cursor::new(bytes(10)), 10).await?;
+        assert!(matches!(small, Data::Contiguous(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_concat() {
+        let a: Data = bytes(CHUNK_SIZE - 1).into();
+        let b: Data = bytes(CHUNK_SIZE + 2).into();
+        let joined = Data::concat(&a, &b);
+        match &joined {
+            Data::Chunked(chunks) => {
+                let lens: Vec<_> = chunks.iter().map(Vec::len).collect();
+                assert_eq!(vec![CHUNK_SIZE, CHUNK_SIZE, 1], lens);
+            }
+            Data::Contiguous(_) => panic!("expected chunks"),
+        }
+        let expected = [bytes(CHUNK_SIZE - 1), bytes(CHUNK_SIZE + 2)].concat();
+        assert_eq!(expected, joined);
+
+        let small = Data::concat(&b"a ".to_vec().into(), &b"b".to_vec().into());
+        assert_eq!(Data::Contiguous(b"a b".to_vec()), small);
+    }
+}
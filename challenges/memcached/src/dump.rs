@@ -0,0 +1,447 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufStream,
+    BufWriter, Result,
+};
+use tokio::net::TcpStream;
+
+use crate::connection::is_valid_key;
+
+/// The number of commands sent before their replies are read.
+const PIPELINE_DEPTH: usize = 128;
+
+/// The first bytes of a binary dump, the last one is the version of the format.
+const MAGIC: &[u8; 8] = b"MCDUMP\x00\x01";
+
+/// The file format of `dump` and `load`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DumpFormat {
+    /// One JSON object per line, with the value as a string if it is UTF-8 and as an array of
+    /// bytes otherwise, as in the HTTP gateway.
+    #[default]
+    Json,
+    /// Length prefixed records: the key, flags, expiry as a unix time (zero for none) and value.
+    Binary,
+}
+
+/// An item of a dump. The expiry is kept as a unix time, so the time between a dump and its
+/// load counts against the TTL of the items.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Record {
+    key: String,
+    flags: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bytes: Option<Vec<u8>>,
+}
+
+impl Record {
+    fn new(key: String, flags: u32, exp: Option<u64>, data: Vec<u8>) -> Record {
+        let (value, bytes) = match String::from_utf8(data) {
+            Ok(s) => (Some(s), None),
+            Err(err) => (None, Some(err.into_bytes())),
+        };
+        Record {
+            key,
+            flags,
+            exp,
+            value,
+            bytes,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match (&self.value, &self.bytes) {
+            (Some(value), _) => value.as_bytes(),
+            (None, Some(bytes)) => bytes,
+            (None, None) => &[],
+        }
+    }
+}
+
+/// The outcome of a `load`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Loaded {
+    pub stored: u64,
+    /// Items which had expired, or which the server did not store.
+    pub skipped: u64,
+}
+
+/// Export every item of the server at `addr` to `path`. The keys are listed with `lru_crawler
+/// metadump all` and fetched with pipelined `get`s, items which expire in the meantime are left
+/// out. Returns the number of items written.
+pub async fn dump(addr: &str, path: &Path, format: DumpFormat) -> Result<u64> {
+    let mut conn = BufStream::new(TcpStream::connect(addr).await?);
+    let keys = metadump(&mut conn).await?;
+    let mut out = BufWriter::new(File::create(path).await?);
+    if format == DumpFormat::Binary {
+        out.write_all(MAGIC).await?;
+    }
+    let mut count = 0;
+    for batch in keys.chunks(PIPELINE_DEPTH) {
+        for (key, _) in batch {
+            conn.write_all(format!("get {}\r\n", key).as_bytes())
+                .await?;
+        }
+        conn.flush().await?;
+        for (key, exp) in batch {
+            if let Some((flags, data)) = read_value(&mut conn).await? {
+                let record = Record::new(key.clone(), flags, *exp, data);
+                write_record(&mut out, format, &record).await?;
+                count += 1;
+            }
+        }
+    }
+    out.flush().await?;
+    Ok(count)
+}
+
+/// Store the items of the dump at `path` in the server at `addr` with pipelined `set`s, keeping
+/// their flags and the remainder of their TTL.
+pub async fn load(addr: &str, path: &Path, format: DumpFormat) -> Result<Loaded> {
+    let mut conn = BufStream::new(TcpStream::connect(addr).await?);
+    let mut input = BufReader::new(File::open(path).await?);
+    if format == DumpFormat::Binary {
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a binary dump"));
+        }
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut loaded = Loaded::default();
+    let mut done = false;
+    while !done {
+        let mut pending = 0;
+        while pending < PIPELINE_DEPTH {
+            let Some(record) = read_record(&mut input, format).await? else {
+                done = true;
+                break;
+            };
+            let ttl = match record.exp {
+                None => 0,
+                Some(exp) if exp > now => exp - now,
+                Some(_) => {
+                    loaded.skipped += 1;
+                    continue;
+                }
+            };
+            let data = record.data();
+            let line = format!(
+                "set {} {} {} {}\r\n",
+                record.key,
+                record.flags,
+                ttl,
+                data.len()
+            );
+            conn.write_all(line.as_bytes()).await?;
+            conn.write_all(data).await?;
+            conn.write_all(b"\r\n").await?;
+            pending += 1;
+        }
+        conn.flush().await?;
+        read_replies(&mut conn, pending, &mut loaded).await?;
+    }
+    Ok(loaded)
+}
+
+/// Count the replies to `pending` `set`s. An item the server refused, such as one over its max
+/// item size, is skipped rather than failing the whole load.
+async fn read_replies<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+    pending: usize,
+    loaded: &mut Loaded,
+) -> Result<()> {
+    for _ in 0..pending {
+        match read_line(r).await {
+            Ok(line) if line == "STORED" => loaded.stored += 1,
+            Ok(_) => loaded.skipped += 1,
+            Err(err) if is_item_error(&err) => loaded.skipped += 1,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Whether the error is a `CLIENT_ERROR` or `SERVER_ERROR` reply, which are about a single item.
+fn is_item_error(err: &std::io::Error) -> bool {
+    let message = err.to_string();
+    message.starts_with("CLIENT_ERROR") || message.starts_with("SERVER_ERROR")
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Read a reply line without its CRLF, error replies are returned as errors.
+async fn read_line<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<String> {
+    let mut line = String::new();
+    if r.read_line(&mut line).await? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end_matches("\r\n").to_string();
+    if ["ERROR", "CLIENT_ERROR", "SERVER_ERROR"]
+        .iter()
+        .any(|e| line.starts_with(e))
+    {
+        return Err(std::io::Error::other(line));
+    }
+    Ok(line)
+}
+
+/// The keys of the server and their expiry as a unix time.
+async fn metadump<R: AsyncBufRead + AsyncWrite + Unpin>(
+    conn: &mut R,
+) -> Result<Vec<(String, Option<u64>)>> {
+    conn.write_all(b"lru_crawler metadump all\r\n").await?;
+    conn.flush().await?;
+    let mut keys = Vec::new();
+    loop {
+        let line = read_line(conn).await?;
+        if line == "END" {
+            return Ok(keys);
+        }
+        let (mut key, mut exp) = (None, None);
+        for field in line.split(' ') {
+            match field.split_once('=') {
+                Some(("key", k)) => key = Some(percent_decode(k)?),
+                // -1 is no expiry
+                Some(("exp", e)) => exp = e.parse::<u64>().ok(),
+                _ => {}
+            }
+        }
+        let key = key.ok_or_else(|| invalid_data("metadump line without key"))?;
+        // the key is sent back in a `get`, it must not be able to smuggle in another command.
+        if !is_valid_key(&key) {
+            return Err(invalid_data(&format!("invalid key: {:?}", key)));
+        }
+        keys.push((key, exp));
+    }
+}
+
+/// Read the reply to a `get`, the flags and data of the value or `None` on a miss.
+async fn read_value<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<Option<(u32, Vec<u8>)>> {
+    let line = read_line(r).await?;
+    if line == "END" {
+        return Ok(None);
+    }
+    // VALUE <key> <flags> <bytes>
+    let mut parts = line.split(' ').skip(2);
+    let mut next = || parts.next().and_then(|p| p.parse::<u64>().ok());
+    let (Some(flags), Some(len)) = (next(), next()) else {
+        return Err(invalid_data("malformed VALUE line"));
+    };
+    let mut data = vec![0; len as usize + 2];
+    r.read_exact(&mut data).await?;
+    data.truncate(len as usize);
+    if read_line(r).await? != "END" {
+        return Err(invalid_data("expected END"));
+    }
+    Ok(Some((flags as u32, data)))
+}
+
+async fn write_record<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    format: DumpFormat,
+    record: &Record,
+) -> Result<()> {
+    match format {
+        DumpFormat::Json => {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            w.write_all(&line).await
+        }
+        DumpFormat::Binary => {
+            let data = record.data();
+            w.write_u16(record.key.len() as u16).await?;
+            w.write_all(record.key.as_bytes()).await?;
+            w.write_u32(record.flags).await?;
+            w.write_u64(record.exp.unwrap_or(0)).await?;
+            w.write_u32(data.len() as u32).await?;
+            w.write_all(data).await
+        }
+    }
+}
+
+/// Read the next record, `None` at the end of the dump. Keys are checked, as they are sent to
+/// the server as is.
+async fn read_record<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+    format: DumpFormat,
+) -> Result<Option<Record>> {
+    let record = match format {
+        DumpFormat::Json => {
+            let mut line = String::new();
+            loop {
+                if r.read_line(&mut line).await? == 0 {
+                    return Ok(None);
+                }
+                if !line.trim().is_empty() {
+                    break;
+                }
+                line.clear();
+            }
+            serde_json::from_str(&line)?
+        }
+        DumpFormat::Binary => {
+            let key_len = match r.read_u16().await {
+                Ok(len) => len,
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            let mut key = vec![0; key_len as usize];
+            r.read_exact(&mut key).await?;
+            let key = String::from_utf8(key).map_err(|_| invalid_data("malformed key"))?;
+            let flags = r.read_u32().await?;
+            let exp = Some(r.read_u64().await?).filter(|exp| *exp != 0);
+            let mut data = vec![0; r.read_u32().await? as usize];
+            r.read_exact(&mut data).await?;
+            Record::new(key, flags, exp, data)
+        }
+    };
+    if !is_valid_key(&record.key) {
+        return Err(invalid_data(&format!("invalid key: {:?}", record.key)));
+    }
+    Ok(Some(record))
+}
+
+/// Decode a key of `metadump`, see `connection::percent_encode`.
+fn percent_decode(s: &str) -> Result<String> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let hex = [bytes.next(), bytes.next()];
+        let byte = match hex {
+            [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo])
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        };
+        decoded.push(byte.ok_or_else(|| invalid_data("malformed percent encoding"))?);
+    }
+    String::from_utf8(decoded).map_err(|_| invalid_data("malformed key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::server::Frontend;
+    use crate::server::tests::start_server;
+
+    async fn send(addr: &str, request: &[u8]) -> Result<String> {
+        let mut conn = BufStream::new(TcpStream::connect(addr).await?);
+        conn.write_all(request).await?;
+        conn.flush().await?;
+        read_line(&mut conn).await
+    }
+
+    #[tokio::test]
+    async fn test_records() -> Result<()> {
+        let records = [
+            Record::new(
+                "text".to_string(),
+                3,
+                Some(1_900_000_000),
+                b"value".to_vec(),
+            ),
+            Record::new("binary".to_string(), 0, None, vec![0xff, 0, 1]),
+        ];
+        for format in [DumpFormat::Json, DumpFormat::Binary] {
+            let mut buf = Vec::new();
+            for record in &records {
+                write_record(&mut buf, format, record).await?;
+            }
+            let mut r = buf.as_slice();
+            for record in &records {
+                assert_eq!(Some(record), read_record(&mut r, format).await?.as_ref());
+            }
+            assert_eq!(None, read_record(&mut r, format).await?);
+        }
+        assert_eq!("a b/c", percent_decode("a%20b%2Fc")?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_replies() -> Result<()> {
+        let mut r: &[u8] = b"STORED\r\nSERVER_ERROR object too large for cache\r\n\
+            NOT_STORED\r\nCLIENT_ERROR bad data chunk\r\nSTORED\r\n";
+        let mut loaded = Loaded::default();
+        read_replies(&mut r, 5, &mut loaded).await?;
+        let expected = Loaded {
+            stored: 2,
+            skipped: 3,
+        };
+        assert_eq!(expected, loaded);
+
+        // an unknown command is not about the item, the server does not speak the protocol.
+        let mut r: &[u8] = b"ERROR\r\n";
+        assert!(read_replies(&mut r, 1, &mut loaded).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dump_load() -> Result<()> {
+        let (from, _from) = start_server(Frontend::Memcached, Config::default()).await;
+        let (to, _to) = start_server(Frontend::Memcached, Config::default()).await;
+        let (from, to) = (from.to_string(), to.to_string());
+        assert_eq!(
+            "STORED",
+            send(&from, b"set plain 7 0 5\r\nvalue\r\n").await?
+        );
+        assert_eq!(
+            "STORED",
+            send(&from, b"set some/key 0 600 2\r\n\xff\x00\r\n").await?
+        );
+
+        let dir = std::env::temp_dir().join(format!("memcached-dump-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        for format in [DumpFormat::Json, DumpFormat::Binary] {
+            let path = dir.join(format!("{:?}", format));
+            assert_eq!(2, dump(&from, &path, format).await?);
+            let loaded = load(&to, &path, format).await?;
+            assert_eq!(
+                Loaded {
+                    stored: 2,
+                    skipped: 0
+                },
+                loaded
+            );
+        }
+        std::fs::remove_dir_all(&dir)?;
+
+        let mut conn = BufStream::new(TcpStream::connect(&to).await?);
+        conn.write_all(b"get plain\r\nget some/key\r\n").await?;
+        conn.flush().await?;
+        assert_eq!(Some((7, b"value".to_vec())), read_value(&mut conn).await?);
+        assert_eq!(Some((0, vec![0xff, 0])), read_value(&mut conn).await?);
+
+        // the loaded items keep their expiry, less the time the dump and load took.
+        let mut keys = metadump(&mut conn).await?;
+        keys.sort();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!("plain", keys[0].0);
+        assert_eq!(None, keys[0].1);
+        assert_eq!("some/key", keys[1].0);
+        let exp = keys[1].1.unwrap();
+        assert!((now + 595..=now + 600).contains(&exp), "{} {}", now, exp);
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The number of hot keys reported.
+const TOP_K: usize = 10;
+const SKETCH_WIDTH: usize = 2048;
+const SKETCH_DEPTH: usize = 4;
+/// Counts are halved after this many samples so keys which are no longer hot age out.
+const DECAY_INTERVAL: u64 = (SKETCH_WIDTH * 8) as u64;
+
+/// A count-min sketch estimates the frequency of a key in fixed memory. Each row hashes the key to
+/// a counter, the estimate is the minimum of the counters and never undercounts.
+struct CountMinSketch {
+    rows: Vec<Vec<u32>>,
+}
+
+impl CountMinSketch {
+    fn new() -> CountMinSketch {
+        CountMinSketch {
+            rows: vec![vec![0; SKETCH_WIDTH]; SKETCH_DEPTH],
+        }
+    }
+
+    #[inline]
+    fn index(row: usize, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        (hasher.finish() % SKETCH_WIDTH as u64) as usize
+    }
+
+    /// Count an occurrence of `key`, returning its new estimate.
+    fn increment(&mut self, key: &str) -> u32 {
+        let mut estimate = u32::MAX;
+        for (row, counters) in self.rows.iter_mut().enumerate() {
+            let counter = &mut counters[Self::index(row, key)];
+            *counter = counter.saturating_add(1);
+            estimate = estimate.min(*counter);
+        }
+        estimate
+    }
+
+    fn halve(&mut self) {
+        for counter in self.rows.iter_mut().flatten() {
+            *counter /= 2;
+        }
+    }
+}
+
+struct TopK {
+    sketch: CountMinSketch,
+    /// The current top keys with their estimates. `TOP_K` is small so the minimum is found with
+    /// a scan rather than by maintaining a heap.
+    top: HashMap<String, u32>,
+    samples: u64,
+}
+
+impl TopK {
+    fn record(&mut self, key: &str) {
+        self.samples += 1;
+        if self.samples.is_multiple_of(DECAY_INTERVAL) {
+            self.sketch.halve();
+            self.top.values_mut().for_each(|count| *count /= 2);
+        }
+
+        let estimate = self.sketch.increment(key);
+        if let Some(count) = self.top.get_mut(key) {
+            *count = estimate;
+            return;
+        }
+        if self.top.len() < TOP_K {
+            self.top.insert(key.to_string(), estimate);
+            return;
+        }
+        let (coldest, min) = self
+            .top
+            .iter()
+            .min_by_key(|(_, count)| **count)
+            .map(|(k, c)| (k.clone(), *c))
+            .unwrap();
+        if estimate > min {
+            self.top.remove(&coldest);
+            self.top.insert(key.to_string(), estimate);
+        }
+    }
+}
+
+/// Streaming detection of the most accessed keys. Only one in `sample_rate` accesses is counted to
+/// keep the shared lock off the hot path.
+pub(crate) struct HotKeys {
+    sample_rate: u64,
+    accesses: AtomicU64,
+    top: Mutex<TopK>,
+}
+
+impl HotKeys {
+    pub(crate) fn new(sample_rate: u32) -> HotKeys {
+        HotKeys {
+            sample_rate: sample_rate.max(1) as u64,
+            accesses: AtomicU64::new(0),
+            top: Mutex::new(TopK {
+                sketch: CountMinSketch::new(),
+                top: HashMap::with_capacity(TOP_K + 1),
+                samples: 0,
+            }),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn record(&self, key: &str) {
+        let n = self.accesses.fetch_add(1, Ordering::Relaxed);
+        if n.is_multiple_of(self.sample_rate) {
+            self.top.lock().unwrap().record(key);
+        }
+    }
+
+    /// The hot keys, hottest first, with their estimated number of accesses.
+    pub(crate) fn top(&self) -> Vec<(String, u64)> {
+        let top = self.top.lock().unwrap();
+        let mut keys: Vec<_> = top
+            .top
+            .iter()
+            .map(|(k, count)| (k.clone(), *count as u64 * self.sample_rate))
+            .collect();
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hotkeys_top_k() {
+        let hotkeys = HotKeys::new(1);
+        for i in 0..1000 {
+            hotkeys.record("viral");
+            if i % 2 == 0 {
+                hotkeys.record("warm");
+            }
+            // a long tail of keys accessed once
+            hotkeys.record(&format!("cold-{}", i));
+        }
+        let top = hotkeys.top();
+        assert_eq!(TOP_K, top.len());
+        assert_eq!(("viral".to_string(), 1000), top[0]);
+        assert_eq!(("warm".to_string(), 500), top[1]);
+    }
+
+    #[test]
+    fn test_hotkeys_sampling() {
+        let hotkeys = HotKeys::new(10);
+        for _ in 0..100 {
+            hotkeys.record("key");
+        }
+        assert_eq!(vec![("key".to_string(), 100)], hotkeys.top());
+    }
+}
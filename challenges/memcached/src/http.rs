@@ -0,0 +1,421 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Request, State};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::stream;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value as JsonValue};
+use tracing::Instrument;
+
+use crate::connection::is_valid_key;
+use crate::data::Data;
+use crate::protocol::{StorageCommand, StorageCommandType, Value};
+use crate::server::{CommandSpan, Settings};
+use crate::store::StoreProcessor;
+
+/// The time to live of a `PUT` in seconds, zero or absent for no expiry.
+const TTL_HEADER: &str = "x-ttl";
+const FLAGS_HEADER: &str = "x-flags";
+const CAS_HEADER: &str = "x-cas";
+
+/// The HTTP gateway to the store, for clients that cannot speak the text protocol. Values are
+/// transferred as raw bodies, with their metadata in headers.
+pub(crate) fn router(processor: Arc<StoreProcessor>, settings: Arc<Settings>) -> Router {
+    Router::new()
+        .route("/keys/_mget", post(mget))
+        .route("/keys/{key}", get(get_key).put(put_key).delete(delete_key))
+        .route("/stats", get(stats))
+        .with_state(processor)
+        .layer(middleware::from_fn_with_state(settings, instrument))
+}
+
+/// Trace a request in a command span, named after the memcached command it stands for, like the
+/// commands of the TCP frontends.
+async fn instrument(State(settings): State<Arc<Settings>>, req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let (command, key) = match (req.method(), path.strip_prefix("/keys/")) {
+        (&Method::POST, Some("_mget")) => ("mget", None),
+        (&Method::GET, Some(key)) => ("get", Some(key)),
+        (&Method::PUT, Some(key)) => ("set", Some(key)),
+        (&Method::DELETE, Some(key)) => ("delete", Some(key)),
+        _ if path == "/stats" => ("stats", None),
+        _ => ("http", None),
+    };
+    let span = CommandSpan::new(command, key, settings.slow_command());
+    let res = next.run(req).instrument(span.span().clone()).await;
+    span.finish();
+    res
+}
+
+/// An error response with a plain text body.
+type HttpError = (StatusCode, String);
+
+fn error(status: StatusCode, message: &str) -> HttpError {
+    (status, message.to_string())
+}
+
+fn check_key(key: &str) -> Result<(), HttpError> {
+    if !is_valid_key(key) {
+        return Err(error(StatusCode::BAD_REQUEST, "invalid key"));
+    }
+    Ok(())
+}
+
+/// Read an optional numeric header, absent headers read as zero.
+fn read_header<T: std::str::FromStr + Default>(
+    headers: &HeaderMap,
+    name: &str,
+) -> Result<T, HttpError> {
+    match headers.get(name) {
+        None => Ok(T::default()),
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(|| error(StatusCode::BAD_REQUEST, &format!("invalid {} header", name))),
+    }
+}
+
+async fn get_key(
+    State(processor): State<Arc<StoreProcessor>>,
+    Path(key): Path<String>,
+) -> Result<Response, HttpError> {
+    check_key(&key)?;
+    let Some(val) = processor.get(&key).await else {
+        return Err(error(StatusCode::NOT_FOUND, "not found"));
+    };
+    let headers = [
+        (
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        ),
+        (
+            header::HeaderName::from_static(FLAGS_HEADER),
+            val.flags.into(),
+        ),
+        (header::HeaderName::from_static(CAS_HEADER), val.cas.into()),
+        (header::CONTENT_LENGTH, val.data.len().into()),
+    ];
+    // the chunks of the value are copied into the body one at a time.
+    let chunks = val.data.chunks().count();
+    let body = stream::iter((0..chunks).map(move |i| {
+        let chunk = val.data.chunks().nth(i).unwrap_or_default();
+        Ok::<_, Infallible>(Bytes::copy_from_slice(chunk))
+    }));
+    Ok((headers, Body::from_stream(body)).into_response())
+}
+
+async fn put_key(
+    State(processor): State<Arc<StoreProcessor>>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, HttpError> {
+    check_key(&key)?;
+    if body.len() > processor.max_item_size() {
+        return Err(error(StatusCode::PAYLOAD_TOO_LARGE, "value too large"));
+    }
+    let exp_time = read_header(&headers, TTL_HEADER)?;
+    let flags = read_header(&headers, FLAGS_HEADER)?;
+    let cmd = StorageCommand {
+        command: StorageCommandType::Set,
+        key,
+        flags,
+        exp_time,
+        no_reply: false,
+        byte_count: body.len() as u32,
+        cas_unique: 0,
+        data: body.to_vec().into(),
+    };
+    match processor.execute_storage_command(cmd).await {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err(error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())),
+    }
+}
+
+async fn delete_key(
+    State(processor): State<Arc<StoreProcessor>>,
+    Path(key): Path<String>,
+) -> Result<StatusCode, HttpError> {
+    check_key(&key)?;
+    if processor.delete(&key).await {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(error(StatusCode::NOT_FOUND, "not found"))
+    }
+}
+
+#[derive(Deserialize)]
+struct MGetRequest {
+    keys: Vec<String>,
+}
+
+/// Fetch several keys at once. Every requested key is present in the response, missing keys map
+/// to `null`. The response is written a piece at a time, so a large value is never made
+/// contiguous.
+async fn mget(
+    State(processor): State<Arc<StoreProcessor>>,
+    Json(req): Json<MGetRequest>,
+) -> Result<Response, HttpError> {
+    for key in &req.keys {
+        check_key(key)?;
+    }
+    let mut seen = HashSet::with_capacity(req.keys.len());
+    let mut body = vec![Bytes::from_static(b"{")];
+    for key in req.keys {
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+        let separator = if seen.len() > 1 { "," } else { "" };
+        body.push(format!("{}{}:", separator, json_string(&key)).into());
+        match processor.get(&key).await {
+            Some(val) => write_value(&mut body, &val),
+            None => body.push(Bytes::from_static(b"null")),
+        }
+    }
+    body.push(Bytes::from_static(b"}"));
+    let headers = [(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    )];
+    let body = stream::iter(body.into_iter().map(Ok::<_, Infallible>));
+    Ok((headers, Body::from_stream(body)).into_response())
+}
+
+/// Write a value of an `mget` response. JSON strings can't hold arbitrary bytes, so values which
+/// are not UTF-8 are written as an array of bytes instead.
+fn write_value(body: &mut Vec<Bytes>, val: &Value) {
+    match utf8_pieces(&val.data) {
+        Some(pieces) => {
+            body.push(Bytes::from_static(b"{\"value\":\""));
+            for piece in pieces {
+                let escaped = json_string(&piece);
+                body.push(escaped[1..escaped.len() - 1].to_string().into());
+            }
+            body.push(Bytes::from_static(b"\""));
+        }
+        None => {
+            body.push(Bytes::from_static(b"{\"bytes\":["));
+            for (i, chunk) in val.data.chunks().enumerate() {
+                let mut bytes = String::with_capacity(chunk.len() * 4);
+                for (j, b) in chunk.iter().enumerate() {
+                    if i > 0 || j > 0 {
+                        bytes.push(',');
+                    }
+                    bytes.push_str(&b.to_string());
+                }
+                body.push(bytes.into());
+            }
+            body.push(Bytes::from_static(b"]"));
+        }
+    }
+    body.push(format!(",\"flags\":{},\"cas\":{}}}", val.flags, val.cas).into());
+}
+
+fn json_string(s: &str) -> String {
+    JsonValue::from(s).to_string()
+}
+
+/// The data as string slices, `None` if it is not UTF-8. A character split between two chunks is
+/// copied into a piece of its own, the rest of the data is borrowed.
+fn utf8_pieces(data: &Data) -> Option<Vec<Cow<'_, str>>> {
+    let mut pieces = Vec::new();
+    let mut split = Vec::new();
+    for chunk in data.chunks() {
+        // the continuation bytes at the start of a chunk end the character split from the last.
+        let continued = chunk
+            .iter()
+            .take(3)
+            .take_while(|b| *b & 0xc0 == 0x80)
+            .count();
+        if continued > 0 || !split.is_empty() {
+            split.extend_from_slice(&chunk[..continued]);
+            pieces.push(Cow::Owned(
+                String::from_utf8(std::mem::take(&mut split)).ok()?,
+            ));
+        }
+        let rest = &chunk[continued..];
+        match std::str::from_utf8(rest) {
+            Ok(s) => pieces.push(Cow::Borrowed(s)),
+            // the chunk ends in the middle of a character.
+            Err(err) if err.error_len().is_none() => {
+                let (valid, tail) = rest.split_at(err.valid_up_to());
+                pieces.push(Cow::Borrowed(std::str::from_utf8(valid).ok()?));
+                split.extend_from_slice(tail);
+            }
+            Err(_) => return None,
+        }
+    }
+    split.is_empty().then_some(pieces)
+}
+
+/// The `stats` of the store and the hot keys as a JSON object.
+async fn stats(State(processor): State<Arc<StoreProcessor>>) -> Json<JsonValue> {
+    let mut stats = Map::new();
+    for (name, value) in processor.stats() {
+        // the stats are preformatted for the text protocol, numbers are restored.
+        let value = match value.parse::<u64>() {
+            Ok(n) => JsonValue::from(n),
+            Err(_) => value
+                .parse::<f64>()
+                .ok()
+                .and_then(Number::from_f64)
+                .map_or(JsonValue::String(value), JsonValue::Number),
+        };
+        stats.insert(name.to_string(), value);
+    }
+    let hotkeys: Map<_, _> = processor
+        .hotkeys()
+        .into_iter()
+        .map(|(key, accesses)| (key, JsonValue::from(accesses)))
+        .collect();
+    stats.insert("hotkeys".to_string(), JsonValue::Object(hotkeys));
+    let namespaces: Map<_, _> = processor
+        .namespace_stats()
+        .into_iter()
+        .map(|(name, value)| (name, JsonValue::from(value)))
+        .collect();
+    stats.insert("namespaces".to_string(), JsonValue::Object(namespaces));
+    Json(JsonValue::Object(stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{Body, to_bytes};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::data::CHUNK_SIZE;
+
+    fn app(config: &Config) -> Router {
+        let processor = Arc::new(StoreProcessor::new(config).unwrap());
+        router(processor, Arc::new(Settings::new(config)))
+    }
+
+    async fn send(app: &Router, req: Request<Body>) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let res = app.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, headers, body.to_vec())
+    }
+
+    fn request(method: &str, uri: &str, body: impl Into<Body>) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_http_keys() {
+        let app = app(&Config::default());
+        let put = Request::put("/keys/key")
+            .header(TTL_HEADER, "60")
+            .header(FLAGS_HEADER, "7")
+            .body(Body::from("value"))
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, send(&app, put).await.0);
+
+        let (status, headers, body) = send(&app, request("GET", "/keys/key", "")).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("7", headers[FLAGS_HEADER]);
+        assert_eq!(b"value".to_vec(), body);
+
+        let mget = request("POST", "/keys/_mget", r#"{"keys":["key","missing"]}"#);
+        let (status, _, body) = send(&app, mget).await;
+        assert_eq!(StatusCode::OK, status);
+        let body: JsonValue = serde_json::from_slice(&body).unwrap();
+        assert_eq!("value", body["key"]["value"]);
+        assert_eq!(7, body["key"]["flags"]);
+        assert!(body["missing"].is_null());
+
+        let (status, _, _) = send(&app, request("DELETE", "/keys/key", "")).await;
+        assert_eq!(StatusCode::NO_CONTENT, status);
+        let (status, _, _) = send(&app, request("GET", "/keys/key", "")).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        let bad_ttl = Request::put("/keys/key")
+            .header(TTL_HEADER, "soon")
+            .body(Body::from("value"))
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, send(&app, bad_ttl).await.0);
+
+        // a key with whitespace or control characters could not be read by the text protocol.
+        let spaced = Request::put("/keys/a%20key")
+            .body(Body::from("value"))
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, send(&app, spaced).await.0);
+        let mget = request("POST", "/keys/_mget", r#"{"keys":["key\u0000"]}"#);
+        assert_eq!(StatusCode::BAD_REQUEST, send(&app, mget).await.0);
+    }
+
+    #[test]
+    fn test_utf8_pieces() {
+        // a three byte character is split between the chunks.
+        let text = "€".repeat(CHUNK_SIZE / 2);
+        let bytes = text.as_bytes();
+        let data = Data::concat(
+            &bytes[..CHUNK_SIZE].to_vec().into(),
+            &bytes[CHUNK_SIZE..].to_vec().into(),
+        );
+        let pieces = utf8_pieces(&data).unwrap();
+        assert_eq!(3, pieces.len());
+        assert!(matches!(pieces[1], Cow::Owned(_)));
+        assert_eq!(text, pieces.concat());
+
+        let invalid = Data::concat(&bytes[..CHUNK_SIZE].to_vec().into(), &b"x".to_vec().into());
+        assert!(utf8_pieces(&invalid).is_none());
+        assert!(utf8_pieces(&vec![0x80, b'a'].into()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_http_large_values() {
+        let config = Config {
+            max_item_size: 4 * CHUNK_SIZE,
+            ..Config::default()
+        };
+        let app = app(&config);
+        let large: Vec<u8> = (0..2 * CHUNK_SIZE + 1).map(|i| i as u8).collect();
+        let put = Request::put("/keys/large")
+            .body(Body::from(large.clone()))
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, send(&app, put).await.0);
+
+        let (status, headers, body) = send(&app, request("GET", "/keys/large", "")).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(large.len().to_string(), headers[header::CONTENT_LENGTH]);
+        assert_eq!(large, body);
+
+        // the value is not UTF-8, so it is returned as bytes.
+        let mget = request("POST", "/keys/_mget", r#"{"keys":["large","large"]}"#);
+        let (_, _, body) = send(&app, mget).await;
+        let body: JsonValue = serde_json::from_slice(&body).unwrap();
+        let bytes: Vec<u8> = serde_json::from_value(body["large"]["bytes"].clone()).unwrap();
+        assert_eq!(large, bytes);
+    }
+
+    #[tokio::test]
+    async fn test_http_stats() {
+        let app = app(&Config::default());
+        let put = Request::put("/keys/key").body(Body::from("value")).unwrap();
+        send(&app, put).await;
+        let (status, _, body) = send(&app, request("GET", "/stats", "")).await;
+        assert_eq!(StatusCode::OK, status);
+        let body: JsonValue = serde_json::from_slice(&body).unwrap();
+        assert!(body["curr_items"].is_u64());
+        assert!(body["hotkeys"].is_object());
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::backend::weigh;
+use crate::protocol::Value;
+
+/// The outcome of asking for a lease on a missing key.
+#[derive(Debug, PartialEq)]
+pub(crate) enum LeaseGrant {
+    /// The caller holds the lease and is expected to fill the key with a `cas` presenting the
+    /// token.
+    Granted(u64),
+    /// Another client holds the lease, the value it replaced is still available.
+    Stale(Arc<Value>),
+    /// Another client holds the lease and there is no stale value, the caller should retry.
+    Wait,
+}
+
+struct Lease {
+    token: u64,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+struct LeaseTable {
+    leases: HashMap<String, Lease>,
+    /// Values removed by a `delete` or expired, served to clients waiting on a lease.
+    stale: HashMap<String, (Arc<Value>, Instant)>,
+    /// The stale keys by expiry. Every stale value lives for the same ttl, so this is also the
+    /// insertion order. Entries replaced or removed from `stale` are skipped when popped.
+    stale_order: VecDeque<(String, Instant)>,
+    /// The weight of the stale values, bounded by `Leases::max_stale_bytes`.
+    stale_bytes: u64,
+}
+
+impl LeaseTable {
+    fn remove_stale(&mut self, key: &str) {
+        if let Some((value, _)) = self.stale.remove(key) {
+            self.stale_bytes -= weigh(key, &value) as u64;
+        }
+    }
+
+    /// Keep `value` as the stale value of `key`. The expired values are dropped first, then the
+    /// oldest ones until the new value fits in `max_bytes`.
+    fn insert_stale(&mut self, key: &str, value: Arc<Value>, expires_at: Instant, max_bytes: u64) {
+        self.remove_stale(key);
+        let weight = weigh(key, &value) as u64;
+        if weight > max_bytes {
+            return;
+        }
+        let now = Instant::now();
+        while let Some((oldest, oldest_expiry)) = self.stale_order.front() {
+            if *oldest_expiry > now && self.stale_bytes + weight <= max_bytes {
+                break;
+            }
+            let (oldest, oldest_expiry) = (oldest.clone(), *oldest_expiry);
+            self.stale_order.pop_front();
+            if self
+                .stale
+                .get(&oldest)
+                .is_some_and(|(_, at)| *at == oldest_expiry)
+            {
+                self.remove_stale(&oldest);
+            }
+        }
+        self.stale_order.push_back((key.to_string(), expires_at));
+        self.stale.insert(key.to_string(), (value, expires_at));
+        self.stale_bytes += weight;
+    }
+}
+
+/// Leases protect the backing store from a stampede when a hot key goes missing. Only the first
+/// client to miss is granted a lease and may fill the key, the other clients wait or are served the
+/// stale value. Leases and stale values expire after `ttl`, so a lease holder that never fills the
+/// key only delays the other clients. The stale values are held outside of the backend, so their
+/// total weight is capped at `max_stale_bytes`.
+pub(crate) struct Leases {
+    ttl: Duration,
+    max_stale_bytes: u64,
+    table: Mutex<LeaseTable>,
+}
+
+impl Leases {
+    pub(crate) fn new(ttl: Duration, max_stale_bytes: u64) -> Leases {
+        Leases {
+            ttl,
+            max_stale_bytes,
+            table: Mutex::new(LeaseTable::default()),
+        }
+    }
+
+    /// Acquire the lease for `key` if it is not held, `next_token` provides the token.
+    pub(crate) fn acquire(&self, key: &str, next_token: impl FnOnce() -> u64) -> LeaseGrant {
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        if table.leases.get(key).is_some_and(|l| l.expires_at > now) {
+            return match table.stale.get(key) {
+                Some((value, expires_at)) if *expires_at > now => LeaseGrant::Stale(value.clone()),
+                _ => LeaseGrant::Wait,
+            };
+        }
+        // drop expired leases before the table grows, rather than on a timer. The stale values
+        // are pruned as they are inserted.
+        if table.leases.len() >= table.leases.capacity() {
+            table.leases.retain(|_, l| l.expires_at > now);
+        }
+        let token = next_token();
+        table.leases.insert(
+            key.to_string(),
+            Lease {
+                token,
+                expires_at: now + self.ttl,
+            },
+        );
+        LeaseGrant::Granted(token)
+    }
+
+    /// Consume the lease for `key` if `token` matches a lease that has not expired.
+    pub(crate) fn fill(&self, key: &str, token: u64) -> bool {
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        match table.leases.get(key) {
+            Some(lease) if lease.token == token && lease.expires_at > now => {
+                table.leases.remove(key);
+                table.remove_stale(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Invalidate any outstanding lease for `key`, a later fill with its token fails. If the key
+    /// was deleted its last value is kept as the stale value.
+    pub(crate) fn invalidate(&self, key: &str, stale: Option<Arc<Value>>) {
+        let mut table = self.table.lock().unwrap();
+        table.leases.remove(key);
+        match stale {
+            Some(value) => {
+                let expires_at = Instant::now() + self.ttl;
+                table.insert_stale(key, value, expires_at, self.max_stale_bytes);
+            }
+            None => table.remove_stale(key),
+        }
+    }
+
+    /// Keep the value of an expired key as its stale value, the lease of the key is unaffected.
+    /// Called by the backends as they drop the expired items.
+    pub(crate) fn expired(&self, key: &str, value: Arc<Value>) {
+        let expires_at = Instant::now() + self.ttl;
+        let mut table = self.table.lock().unwrap();
+        table.insert_stale(key, value, expires_at, self.max_stale_bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leases() {
+        let leases = Leases::new(Duration::from_secs(10), 1024);
+        assert_eq!(LeaseGrant::Granted(1), leases.acquire("key", || 1));
+        assert_eq!(LeaseGrant::Wait, leases.acquire("key", || 2));
+        assert!(!leases.fill("key", 2));
+        assert!(leases.fill("key", 1));
+        // the lease is consumed by the fill.
+        assert!(!leases.fill("key", 1));
+
+        // a delete invalidates the lease and leaves a stale value.
+        let value = Arc::new(Value {
+            flags: 0,
+            exp_time: 0,
+            cas: 3,
+            data: b"stale".to_vec().into(),
+            compressed: None,
+        });
+        assert_eq!(LeaseGrant::Granted(4), leases.acquire("key", || 4));
+        leases.invalidate("key", Some(value.clone()));
+        assert!(!leases.fill("key", 4));
+        assert_eq!(LeaseGrant::Granted(5), leases.acquire("key", || 5));
+        match leases.acquire("key", || 6) {
+            LeaseGrant::Stale(stale) => assert_eq!(b"stale".to_vec(), stale.data),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lease_expires() {
+        let leases = Leases::new(Duration::ZERO, 1024);
+        assert_eq!(LeaseGrant::Granted(1), leases.acquire("key", || 1));
+        assert_eq!(LeaseGrant::Granted(2), leases.acquire("key", || 2));
+        assert!(!leases.fill("key", 2));
+    }
+
+    #[test]
+    fn test_stale_values_are_capped() {
+        let value = |data: &[u8]| {
+            Arc::new(Value {
+                flags: 0,
+                exp_time: 0,
+                cas: 1,
+                data: data.to_vec().into(),
+                compressed: None,
+            })
+        };
+        let item = weigh("a", &value(b"aaaa")) as u64;
+        let leases = Leases::new(Duration::from_secs(10), item * 2);
+        leases.invalidate("a", Some(value(b"aaaa")));
+        leases.invalidate("b", Some(value(b"bbbb")));
+        leases.expired("c", value(b"cccc"));
+        // the oldest stale value made room for the last one.
+        let table = leases.table.lock().unwrap();
+        assert_eq!(item * 2, table.stale_bytes);
+        assert!(!table.stale.contains_key("a"));
+        assert!(table.stale.contains_key("b") && table.stale.contains_key("c"));
+        drop(table);
+
+        // a value over the cap is not kept at all.
+        leases.invalidate("d", Some(value(&[0; 1024])));
+        assert!(!leases.table.lock().unwrap().stale.contains_key("d"));
+        leases.invalidate("b", None);
+        assert_eq!(item, leases.table.lock().unwrap().stale_bytes);
+    }
+}
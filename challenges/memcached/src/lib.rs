@@ -1,5 +1,16 @@
+pub mod config;
+pub mod dump;
 pub mod server;
 
+mod backend;
+mod compression;
 mod connection;
+mod data;
+mod hotkeys;
+mod http;
+mod lease;
 mod protocol;
+mod proxy;
+mod resp;
 mod store;
+mod watch;
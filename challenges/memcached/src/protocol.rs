@@ -1,3 +1,10 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::compression::Compressed;
+use crate::data::Data;
+use crate::watch::EventKind;
+
 #[derive(Debug, PartialEq)]
 pub(crate) enum StorageCommandType {
     Set,
@@ -5,6 +12,7 @@ pub(crate) enum StorageCommandType {
     Replace,
     Append,
     Prepend,
+    Cas,
 }
 
 impl StorageCommandType {
@@ -15,9 +23,21 @@ impl StorageCommandType {
             b"replace" => Some(StorageCommandType::Replace),
             b"append" => Some(StorageCommandType::Append),
             b"prepend" => Some(StorageCommandType::Prepend),
+            b"cas" => Some(StorageCommandType::Cas),
             _ => None,
         }
     }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            StorageCommandType::Set => "set",
+            StorageCommandType::Add => "add",
+            StorageCommandType::Replace => "replace",
+            StorageCommandType::Append => "append",
+            StorageCommandType::Prepend => "prepend",
+            StorageCommandType::Cas => "cas",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -28,40 +48,162 @@ pub(crate) struct StorageCommand {
     pub(crate) exp_time: u32,
     pub(crate) no_reply: bool,
     pub(crate) byte_count: u32,
-    pub(crate) data: Vec<u8>,
+    /// The cas unique presented by a `cas` command, zero for the other commands.
+    pub(crate) cas_unique: u64,
+    pub(crate) data: Data,
 }
 
 #[derive(Debug)]
 pub(crate) enum RetrievalCommand {
     Get { key: String },
+    Gets { key: String },
 }
 
 #[derive(Debug)]
 pub(crate) enum Command {
     Storage(StorageCommand),
     Retrieval(RetrievalCommand),
+    Delete {
+        key: String,
+        no_reply: bool,
+    },
+    Touch {
+        key: String,
+        exp_time: u32,
+        no_reply: bool,
+    },
+    Stats(StatsGroup),
+    /// `lru_crawler metadump all`
+    MetaDump,
+    /// Stream live events of the given kinds, all kinds if empty.
+    Watch(Vec<EventKind>),
+    /// Stream invalidations of the keys starting with any of the prefixes, all keys if empty.
+    Subscribe(Vec<String>),
+    /// Scope the keys of the following commands on the connection to a namespace.
+    Namespace(String),
+    /// `cache_memlimit <megabytes>`, resize the cache without dropping the items.
+    CacheMemLimit(u64),
+    /// `item_size_max <bytes>`, change the largest value accepted.
+    ItemSizeMax(usize),
+    /// Stop the server, draining the connections.
+    Shutdown,
+    /// `auth <token>`, allows the admin commands on the connection when an admin token is set.
+    Auth(String),
+}
+
+impl Command {
+    /// The name of the command, for logs.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Command::Storage(cmd) => cmd.command.name(),
+            Command::Retrieval(RetrievalCommand::Get { .. }) => "get",
+            Command::Retrieval(RetrievalCommand::Gets { .. }) => "gets",
+            Command::Delete { .. } => "delete",
+            Command::Touch { .. } => "touch",
+            Command::Stats(_) => "stats",
+            Command::MetaDump => "metadump",
+            Command::Watch(_) => "watch",
+            Command::Subscribe(_) => "subscribe",
+            Command::Namespace(_) => "namespace",
+            Command::CacheMemLimit(_) => "cache_memlimit",
+            Command::ItemSizeMax(_) => "item_size_max",
+            Command::Shutdown => "shutdown",
+            Command::Auth(_) => "auth",
+        }
+    }
+
+    /// Admin commands change the server for every client.
+    pub(crate) fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Command::CacheMemLimit(_) | Command::ItemSizeMax(_) | Command::Shutdown
+        )
+    }
+
+    /// The key of the command, if it has one.
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            Command::Storage(cmd) => Some(&cmd.key),
+            Command::Retrieval(RetrievalCommand::Get { key })
+            | Command::Retrieval(RetrievalCommand::Gets { key })
+            | Command::Delete { key, .. }
+            | Command::Touch { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// The argument of the `stats` command.
+#[derive(Debug, PartialEq)]
+pub(crate) enum StatsGroup {
+    General,
+    HotKeys,
+    Namespaces,
+}
+
+/// A command the server refused to execute, the error is the reply and the connection stays
+/// open.
+#[derive(Debug, PartialEq)]
+pub(crate) enum CommandError {
+    /// `ERROR`, the command is not known.
+    Unknown,
+    /// `CLIENT_ERROR <message>`, the command line or its data block is malformed.
+    Client(String),
+    /// `SERVER_ERROR <message>`, e.g. the data block is over the max item size.
+    Server(String),
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Unknown => write!(f, "ERROR"),
+            CommandError::Client(message) => write!(f, "CLIENT_ERROR {}", message),
+            CommandError::Server(message) => write!(f, "SERVER_ERROR {}", message),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum StorageCommandResponse {
     Stored,
     NotStored,
+    /// A `cas` found the item modified since it was last fetched.
+    Exists,
+    /// A `cas` against a key that does not exist.
+    NotFound,
 }
 
 impl StorageCommandResponse {
     pub(crate) fn to_kw_bytes(&self) -> &'static [u8] {
+        self.to_kw_str().as_bytes()
+    }
+
+    pub(crate) fn to_kw_str(&self) -> &'static str {
         match self {
-            StorageCommandResponse::Stored => b"STORED",
-            StorageCommandResponse::NotStored => b"NOT_STORED",
+            StorageCommandResponse::Stored => "STORED",
+            StorageCommandResponse::NotStored => "NOT_STORED",
+            StorageCommandResponse::Exists => "EXISTS",
+            StorageCommandResponse::NotFound => "NOT_FOUND",
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct Value {
     pub(crate) flags: u32,
     pub(crate) exp_time: u32,
-    #[allow(dead_code)] // TODO implement cas support
     pub(crate) cas: u64,
-    pub(crate) data: Vec<u8>,
+    pub(crate) data: Data,
+    /// Set if the data is LZ4 compressed, see `compression.rs`.
+    pub(crate) compressed: Option<Arc<Compressed>>,
+}
+
+impl Value {
+    /// The time to live of the item, an `exp_time` of zero means the item never expires.
+    pub(crate) fn ttl(&self) -> Option<Duration> {
+        match self.exp_time {
+            0 => None,
+            secs => Some(Duration::from_secs(secs as u64)),
+        }
+    }
 }
@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream, Result};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::data::Data;
+use crate::protocol::{Command, RetrievalCommand, StorageCommandType};
+
+/// How long a backend server has to connect and answer a request.
+const BACKEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The number of idle connections kept open to each backend server.
+const MAX_IDLE: usize = 16;
+
+/// The FNV-1a offset basis and prime.
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Hash a key with FNV-1a. Unlike `DefaultHasher` the hash is fixed across builds and processes,
+/// so every proxy in front of a pool sends a key to the same server.
+fn key_hash(key: &str) -> u64 {
+    key.bytes().fold(FNV_OFFSET, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// A command forwarded to the backends, encoded in the text protocol. `noreply` is never
+/// forwarded, the proxy needs the replies of the backends to detect failures.
+#[derive(Debug)]
+pub(crate) struct Request {
+    key: String,
+    line: String,
+    data: Option<Data>,
+    pub(crate) no_reply: bool,
+    /// Writes are replicated to every server of the pool, reads are sent to one of them.
+    write: bool,
+    /// Only sent to the server the key hashes to. The cas unique of an item differs on each
+    /// server, so `gets` and `cas` must not be replicated or failed over.
+    primary_only: bool,
+}
+
+impl TryFrom<Command> for Request {
+    type Error = Command;
+
+    /// The commands on a single key are forwarded, the others are handed back.
+    fn try_from(cmd: Command) -> std::result::Result<Request, Command> {
+        let req = match cmd {
+            Command::Storage(cmd) => {
+                let mut line = format!(
+                    "{} {} {} {} {}",
+                    cmd.command.name(),
+                    cmd.key,
+                    cmd.flags,
+                    cmd.exp_time,
+                    cmd.data.len()
+                );
+                let cas = cmd.command == StorageCommandType::Cas;
+                if cas {
+                    line.push_str(&format!(" {}", cmd.cas_unique));
+                }
+                Request {
+                    key: cmd.key,
+                    line,
+                    data: Some(cmd.data),
+                    no_reply: cmd.no_reply,
+                    write: true,
+                    primary_only: cas,
+                }
+            }
+            Command::Retrieval(RetrievalCommand::Get { key }) => Request::read("get", key),
+            Command::Retrieval(RetrievalCommand::Gets { key }) => Request {
+                primary_only: true,
+                ..Request::read("gets", key)
+            },
+            Command::Delete { key, no_reply } => Request {
+                line: format!("delete {}", key),
+                key,
+                data: None,
+                no_reply,
+                write: true,
+                primary_only: false,
+            },
+            Command::Touch {
+                key,
+                exp_time,
+                no_reply,
+            } => Request {
+                line: format!("touch {} {}", key, exp_time),
+                key,
+                data: None,
+                no_reply,
+                write: true,
+                primary_only: false,
+            },
+            cmd => return Err(cmd),
+        };
+        Ok(req)
+    }
+}
+
+impl Request {
+    fn read(command: &str, key: String) -> Request {
+        Request {
+            line: format!("{} {}", command, key),
+            key,
+            data: None,
+            no_reply: false,
+            write: false,
+            primary_only: false,
+        }
+    }
+}
+
+/// A backend server and its idle connections.
+struct Server {
+    addr: String,
+    idle: Mutex<Vec<BufStream<TcpStream>>>,
+}
+
+impl Server {
+    /// Send the request and return the raw reply. A pooled connection the server has closed in
+    /// the meantime is replaced by a new one.
+    async fn call(&self, req: &Request) -> Result<Vec<u8>> {
+        let pooled = self.idle.lock().unwrap().pop();
+        if let Some(mut conn) = pooled {
+            match timeout(BACKEND_TIMEOUT, exchange(&mut conn, req)).await {
+                Ok(Ok(reply)) => {
+                    self.release(conn);
+                    return Ok(reply);
+                }
+                Ok(Err(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {}
+                Ok(Err(err)) => return Err(err),
+                Err(_) => return Err(timed_out(&self.addr)),
+            }
+        }
+        let mut conn = match timeout(BACKEND_TIMEOUT, TcpStream::connect(&self.addr)).await {
+            Ok(stream) => BufStream::new(stream?),
+            Err(_) => return Err(timed_out(&self.addr)),
+        };
+        match timeout(BACKEND_TIMEOUT, exchange(&mut conn, req)).await {
+            Ok(reply) => {
+                let reply = reply?;
+                self.release(conn);
+                Ok(reply)
+            }
+            Err(_) => Err(timed_out(&self.addr)),
+        }
+    }
+
+    fn release(&self, conn: BufStream<TcpStream>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push(conn);
+        }
+    }
+}
+
+fn timed_out(addr: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, format!("{} timed out", addr))
+}
+
+/// Write the request and read its reply: a single line, or the values up to `END` for a read.
+async fn exchange(conn: &mut BufStream<TcpStream>, req: &Request) -> Result<Vec<u8>> {
+    conn.write_all(req.line.as_bytes()).await?;
+    conn.write_all(b"\r\n").await?;
+    if let Some(data) = &req.data {
+        for chunk in data.chunks() {
+            conn.write_all(chunk).await?;
+        }
+        conn.write_all(b"\r\n").await?;
+    }
+    conn.flush().await?;
+
+    let mut reply = Vec::new();
+    loop {
+        let start = reply.len();
+        if conn.read_until(b'\n', &mut reply).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = &reply[start..];
+        if req.write || line == b"END\r\n" || line.ends_with(b"ERROR\r\n") {
+            return Ok(reply);
+        }
+        if line.starts_with(b"VALUE ") {
+            // VALUE <key> <flags> <bytes> [<cas>]
+            let len = std::str::from_utf8(line)
+                .ok()
+                .and_then(|line| line.split_whitespace().nth(3))
+                .and_then(|len| len.parse::<usize>().ok())
+                .ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed VALUE line")
+                })?;
+            let start = reply.len();
+            reply.resize(start + len + 2, 0);
+            conn.read_exact(&mut reply[start..]).await?;
+        }
+    }
+}
+
+/// The reply to the client and the number of servers which failed to answer.
+struct Reply {
+    bytes: Vec<u8>,
+    failed: u64,
+}
+
+struct Pool {
+    servers: Vec<Arc<Server>>,
+}
+
+impl Pool {
+    /// Send a write to every server, replying with the answer of the first server which did.
+    /// Send a read to the server the key hashes to, failing over to the next servers in turn.
+    /// `gets` and `cas` are only sent to the server the key hashes to.
+    async fn send(&self, req: Arc<Request>) -> Result<Reply> {
+        let primary = (key_hash(&req.key) % self.servers.len() as u64) as usize;
+        if req.primary_only {
+            let bytes = self.servers[primary].call(&req).await?;
+            return Ok(Reply { bytes, failed: 0 });
+        }
+        if req.write {
+            let mut calls = JoinSet::new();
+            for (i, server) in self.servers.iter().enumerate() {
+                let (server, req) = (server.clone(), req.clone());
+                calls.spawn(async move { (i, server.call(&req).await) });
+            }
+            let mut replies: Vec<_> = calls.join_all().await;
+            replies.sort_by_key(|(i, _)| *i);
+            let (mut reply, mut failed, mut last_err) = (None, 0, None);
+            for (i, res) in replies {
+                match res {
+                    Ok(bytes) => {
+                        reply.get_or_insert(bytes);
+                    }
+                    Err(err) => {
+                        warn!("write to {} failed: {}", self.servers[i].addr, err);
+                        failed += 1;
+                        last_err = Some(err);
+                    }
+                }
+            }
+            return match reply {
+                Some(bytes) => Ok(Reply { bytes, failed }),
+                None => Err(last_err.unwrap()),
+            };
+        }
+
+        let mut last_err = None;
+        for attempt in 0..self.servers.len() {
+            let server = &self.servers[(primary + attempt) % self.servers.len()];
+            match server.call(&req).await {
+                Ok(bytes) => {
+                    return Ok(Reply {
+                        bytes,
+                        failed: attempt as u64,
+                    });
+                }
+                Err(err) => {
+                    warn!("read from {} failed: {}", server.addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+}
+
+#[derive(Default)]
+struct RouteStats {
+    requests: AtomicU64,
+    /// Requests no server of the pool answered.
+    errors: AtomicU64,
+    /// Reads answered by another server than the one the key hashes to.
+    failovers: AtomicU64,
+    /// Writes which failed on some, but not all, of the servers of the pool.
+    partial_writes: AtomicU64,
+    shadowed: AtomicU64,
+}
+
+struct Route {
+    prefix: String,
+    pool: Arc<Pool>,
+    /// Receives a copy of the requests of the route, its replies are discarded.
+    shadow: Option<Arc<Pool>>,
+    stats: RouteStats,
+}
+
+/// Forwards commands to pools of memcached servers, like a local mcrouter. A key is routed by
+/// the longest route prefix it starts with.
+pub(crate) struct Proxy {
+    routes: Vec<Route>,
+}
+
+impl Proxy {
+    pub(crate) fn new(config: &Config) -> Result<Proxy> {
+        let pools: HashMap<_, _> = config
+            .pools
+            .iter()
+            .map(|pool| {
+                let servers = pool
+                    .servers
+                    .iter()
+                    .map(|addr| {
+                        Arc::new(Server {
+                            addr: addr.clone(),
+                            idle: Mutex::new(Vec::new()),
+                        })
+                    })
+                    .collect();
+                (pool.name.as_str(), Arc::new(Pool { servers }))
+            })
+            .collect();
+        let pool = |name: &str| {
+            pools.get(name).cloned().ok_or_else(|| {
+                let msg = format!("route to unknown pool {:?}", name);
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+            })
+        };
+        let mut routes = Vec::new();
+        for route in &config.routes {
+            routes.push(Route {
+                prefix: route.prefix.clone(),
+                pool: pool(&route.pool)?,
+                shadow: route.shadow.as_deref().map(pool).transpose()?,
+                stats: RouteStats::default(),
+            });
+        }
+        routes.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+        Ok(Proxy { routes })
+    }
+
+    /// Forward the request to the pool of its route and return the raw reply for the client.
+    pub(crate) async fn forward(&self, req: Request) -> Vec<u8> {
+        let Some(route) = self.routes.iter().find(|r| req.key.starts_with(&r.prefix)) else {
+            return b"SERVER_ERROR no route for key\r\n".to_vec();
+        };
+        route.stats.requests.fetch_add(1, Ordering::Relaxed);
+        let req = Arc::new(req);
+        if let Some(shadow) = &route.shadow {
+            route.stats.shadowed.fetch_add(1, Ordering::Relaxed);
+            let (shadow, req) = (shadow.clone(), req.clone());
+            tokio::spawn(async move {
+                let _ = shadow.send(req).await;
+            });
+        }
+        let write = req.write;
+        match route.pool.send(req).await {
+            Ok(reply) => {
+                if reply.failed > 0 {
+                    let stat = if write {
+                        &route.stats.partial_writes
+                    } else {
+                        &route.stats.failovers
+                    };
+                    stat.fetch_add(1, Ordering::Relaxed);
+                }
+                reply.bytes
+            }
+            Err(err) => {
+                route.stats.errors.fetch_add(1, Ordering::Relaxed);
+                format!("SERVER_ERROR {}\r\n", err).into_bytes()
+            }
+        }
+    }
+
+    /// The counters of every route, named `route:<prefix>:<counter>` with `*` for the catch-all
+    /// route.
+    pub(crate) fn stats(&self) -> Vec<(String, u64)> {
+        let mut stats = Vec::new();
+        for route in &self.routes {
+            let prefix = if route.prefix.is_empty() {
+                "*"
+            } else {
+                &route.prefix
+            };
+            let counters = [
+                ("requests", &route.stats.requests),
+                ("errors", &route.stats.errors),
+                ("failovers", &route.stats.failovers),
+                ("partial_writes", &route.stats.partial_writes),
+                ("shadowed", &route.stats.shadowed),
+            ];
+            for (name, counter) in counters {
+                let name = format!("route:{}:{}", prefix, name);
+                stats.push((name, counter.load(Ordering::Relaxed)));
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::{PoolConfig, RouteConfig};
+    use crate::protocol::StorageCommand;
+    use crate::server::Frontend;
+    use crate::server::tests::start_server;
+
+    /// An address nothing listens on.
+    async fn dead_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn storage(command: StorageCommandType, key: &str, data: &[u8], cas_unique: u64) -> Request {
+        Request::try_from(Command::Storage(StorageCommand {
+            command,
+            key: key.to_string(),
+            flags: 0,
+            exp_time: 0,
+            no_reply: false,
+            byte_count: data.len() as u32,
+            cas_unique,
+            data: data.to_vec().into(),
+        }))
+        .unwrap()
+    }
+
+    fn set(key: &str, data: &[u8]) -> Request {
+        storage(StorageCommandType::Set, key, data, 0)
+    }
+
+    fn gets(key: &str) -> Request {
+        Request::try_from(Command::Retrieval(RetrievalCommand::Gets {
+            key: key.to_string(),
+        }))
+        .unwrap()
+    }
+
+    /// A key under `prefix` which hashes to the server `index` of a pool of `len` servers.
+    fn key_on(prefix: &str, index: u64, len: u64) -> String {
+        (0..)
+            .map(|i| format!("{}{}", prefix, i))
+            .find(|key| key_hash(key) % len == index)
+            .unwrap()
+    }
+
+    /// Call a server directly, bypassing the proxy.
+    async fn call(addr: &str, req: &Request) -> Result<Vec<u8>> {
+        let server = Server {
+            addr: addr.to_string(),
+            idle: Mutex::new(Vec::new()),
+        };
+        server.call(req).await
+    }
+
+    fn get(key: &str) -> Request {
+        Request::read("get", key.to_string())
+    }
+
+    fn pool(name: &str, servers: &[&String]) -> PoolConfig {
+        PoolConfig {
+            name: name.to_string(),
+            servers: servers.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn stat(proxy: &Proxy, name: &str) -> u64 {
+        let stats = proxy.stats();
+        stats.iter().find(|(n, _)| n == name).unwrap().1
+    }
+
+    #[test]
+    fn test_key_hash() {
+        // the hash must not change, or the keys move to other servers.
+        assert_eq!(0xcbf29ce484222325, key_hash(""));
+        assert_eq!(0xaf63dc4c8601ec8c, key_hash("a"));
+        assert_eq!(0x85944171f73967e8, key_hash("foobar"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_routes() -> Result<()> {
+        let (a, _a) = start_server(Frontend::Memcached, Config::default()).await;
+        let (b, _b) = start_server(Frontend::Memcached, Config::default()).await;
+        let (shadow, _shadow) = start_server(Frontend::Memcached, Config::default()).await;
+        let (a, b, shadow) = (a.to_string(), b.to_string(), shadow.to_string());
+        let dead = dead_addr().await;
+        let config = Config {
+            pools: vec![
+                pool("main", &[&a, &b]),
+                pool("shadow", &[&shadow]),
+                pool("flaky", &[&dead, &a]),
+            ],
+            routes: vec![
+                RouteConfig {
+                    prefix: String::new(),
+                    pool: "main".to_string(),
+                    shadow: Some("shadow".to_string()),
+                },
+                RouteConfig {
+                    prefix: "flaky:".to_string(),
+                    pool: "flaky".to_string(),
+                    shadow: None,
+                },
+            ],
+            ..Config::default()
+        };
+        config.validate()?;
+        let proxy = Proxy::new(&config)?;
+
+        // writes are replicated to every server of the pool and shadowed.
+        assert_eq!(
+            b"STORED\r\n".to_vec(),
+            proxy.forward(set("key", b"value")).await
+        );
+        let value = b"VALUE key 0 5\r\nvalue\r\nEND\r\n".to_vec();
+        assert_eq!(value, proxy.forward(get("key")).await);
+        for addr in [&a, &b] {
+            assert_eq!(value, call(addr, &get("key")).await?);
+        }
+        // the shadow write is not awaited by the proxy.
+        let mut shadowed = Vec::new();
+        for _ in 0..100 {
+            shadowed = call(&shadow, &get("key")).await?;
+            if shadowed != b"END\r\n" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(value, shadowed);
+        assert_eq!(2, stat(&proxy, "route:*:requests"));
+        assert_eq!(2, stat(&proxy, "route:*:shadowed"));
+
+        // a dead server fails writes partially, and reads over to the live one.
+        let key = key_on("flaky:", 0, 2);
+        let reply = proxy.forward(set(&key, b"value")).await;
+        assert_eq!(b"STORED\r\n".to_vec(), reply);
+        assert_eq!(1, stat(&proxy, "route:flaky::partial_writes"));
+        let reply = proxy.forward(get(&key)).await;
+        assert!(reply.starts_with(format!("VALUE {} ", key).as_bytes()));
+        assert_eq!(1, stat(&proxy, "route:flaky::failovers"));
+        assert_eq!(0, stat(&proxy, "route:flaky::errors"));
+
+        // gets is not failed over, its cas unique would belong to another server.
+        let reply = proxy.forward(gets(&key)).await;
+        assert!(reply.starts_with(b"SERVER_ERROR "));
+        assert_eq!(1, stat(&proxy, "route:flaky::errors"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_cas() -> Result<()> {
+        let (a, _a) = start_server(Frontend::Memcached, Config::default()).await;
+        let (b, _b) = start_server(Frontend::Memcached, Config::default()).await;
+        let (a, b) = (a.to_string(), b.to_string());
+        let config = Config {
+            pools: vec![pool("main", &[&a, &b])],
+            routes: vec![RouteConfig {
+                prefix: String::new(),
+                pool: "main".to_string(),
+                shadow: None,
+            }],
+            ..Config::default()
+        };
+        let proxy = Proxy::new(&config)?;
+        let key = key_on("", 1, 2);
+        proxy.forward(set(&key, b"value")).await;
+
+        // the cas unique comes from the server the key hashes to, and only it is updated.
+        let reply = String::from_utf8(proxy.forward(gets(&key)).await).unwrap();
+        let cas_unique = reply.split_whitespace().nth(4).unwrap().parse().unwrap();
+        let cas = storage(StorageCommandType::Cas, &key, b"other", cas_unique);
+        assert_eq!(b"STORED\r\n".to_vec(), proxy.forward(cas).await);
+        let value = |data: &str| format!("VALUE {} 0 {}\r\n{}\r\nEND\r\n", key, data.len(), data);
+        assert_eq!(value("other").into_bytes(), call(&b, &get(&key)).await?);
+        assert_eq!(value("value").into_bytes(), call(&a, &get(&key)).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_proxy_unknown_pool() {
+        let config = Config {
+            routes: vec![RouteConfig {
+                prefix: String::new(),
+                pool: "main".to_string(),
+                shadow: None,
+            }],
+            ..Config::default()
+        };
+        assert!(Proxy::new(&config).is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, Result,
+};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::connection::{MAX_KEY_SIZE, is_valid_key};
+use crate::data::Data;
+use crate::protocol::StorageCommandType;
+
+/// The maximum number of arguments of a request, bounds the allocation made for a request header.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// The subset of the Redis commands served from the store. Strings map onto values with zero
+/// flags, so keys can be shared with memcached clients.
+#[derive(Debug, PartialEq)]
+pub(crate) enum RespCommand {
+    Ping(Option<Data>),
+    Get(String),
+    MGet(Vec<String>),
+    Set {
+        key: String,
+        data: Data,
+        /// Zero when the key does not expire.
+        exp_time: u32,
+        /// `Add` for `NX` and `Replace` for `XX`.
+        command: StorageCommandType,
+    },
+    Del(Vec<String>),
+    /// `INCR`, `INCRBY`, `DECR` and `DECRBY`.
+    IncrBy(String, i64),
+    Expire(String, i64),
+    Ttl(String),
+    Append(String, Data),
+}
+
+impl RespCommand {
+    /// The name of the command, for logs.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            RespCommand::Ping(_) => "ping",
+            RespCommand::Get(_) => "get",
+            RespCommand::MGet(_) => "mget",
+            RespCommand::Set { .. } => "set",
+            RespCommand::Del(_) => "del",
+            RespCommand::IncrBy(..) => "incrby",
+            RespCommand::Expire(..) => "expire",
+            RespCommand::Ttl(_) => "ttl",
+            RespCommand::Append(..) => "append",
+        }
+    }
+
+    /// The key of the command, the first one for the commands taking several.
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            RespCommand::Ping(_) => None,
+            RespCommand::MGet(keys) | RespCommand::Del(keys) => keys.first().map(String::as_str),
+            RespCommand::Get(key)
+            | RespCommand::Set { key, .. }
+            | RespCommand::IncrBy(key, _)
+            | RespCommand::Expire(key, _)
+            | RespCommand::Ttl(key)
+            | RespCommand::Append(key, _) => Some(key),
+        }
+    }
+
+    /// Parse a request, the error message is returned to the client as is.
+    pub(crate) fn parse(args: Vec<Data>) -> Result<RespCommand> {
+        let mut args = args.into_iter();
+        let name = args
+            .next()
+            .ok_or_else(|| invalid("empty command".to_string()))?
+            .contiguous()
+            .to_ascii_lowercase();
+        let name = String::from_utf8_lossy(&name).into_owned();
+        let args: Vec<_> = args.collect();
+        let arity = |min: usize, max: usize| {
+            if args.len() < min || args.len() > max {
+                Err(invalid(format!(
+                    "wrong number of arguments for '{}' command",
+                    name
+                )))
+            } else {
+                Ok(())
+            }
+        };
+
+        match name.as_str() {
+            "ping" => {
+                arity(0, 1)?;
+                Ok(RespCommand::Ping(args.into_iter().next()))
+            }
+            "get" => {
+                arity(1, 1)?;
+                Ok(RespCommand::Get(to_key(&args[0])?))
+            }
+            "mget" => {
+                arity(1, usize::MAX)?;
+                Ok(RespCommand::MGet(to_keys(&args)?))
+            }
+            "del" => {
+                arity(1, usize::MAX)?;
+                Ok(RespCommand::Del(to_keys(&args)?))
+            }
+            "incr" | "decr" => {
+                arity(1, 1)?;
+                let delta = if name == "incr" { 1 } else { -1 };
+                Ok(RespCommand::IncrBy(to_key(&args[0])?, delta))
+            }
+            "incrby" | "decrby" => {
+                arity(2, 2)?;
+                let delta: i64 = to_int(&args[1])?;
+                let delta = if name == "incrby" {
+                    Some(delta)
+                } else {
+                    delta.checked_neg()
+                };
+                let delta = delta.ok_or_else(|| invalid("decrement would overflow".to_string()))?;
+                Ok(RespCommand::IncrBy(to_key(&args[0])?, delta))
+            }
+            "expire" => {
+                arity(2, 2)?;
+                Ok(RespCommand::Expire(to_key(&args[0])?, to_int(&args[1])?))
+            }
+            "ttl" => {
+                arity(1, 1)?;
+                Ok(RespCommand::Ttl(to_key(&args[0])?))
+            }
+            "append" => {
+                arity(2, 2)?;
+                let key = to_key(&args[0])?;
+                Ok(RespCommand::Append(key, args.into_iter().nth(1).unwrap()))
+            }
+            "set" => {
+                arity(2, usize::MAX)?;
+                parse_set(args)
+            }
+            _ => Err(invalid(format!("unknown command '{}'", name))),
+        }
+    }
+}
+
+/// Parse `SET key value [NX | XX] [EX seconds | PX milliseconds]`.
+fn parse_set(args: Vec<Data>) -> Result<RespCommand> {
+    let mut args = args.into_iter();
+    let key = to_key(&args.next().unwrap())?;
+    let data = args.next().unwrap();
+    let mut command = StorageCommandType::Set;
+    let mut exp_time = None;
+    while let Some(option) = args.next() {
+        match option.contiguous().to_ascii_uppercase().as_slice() {
+            b"NX" if command == StorageCommandType::Set => command = StorageCommandType::Add,
+            b"XX" if command == StorageCommandType::Set => command = StorageCommandType::Replace,
+            unit @ (b"EX" | b"PX") if exp_time.is_none() => {
+                let n: u64 = args
+                    .next()
+                    .map(|n| to_int(&n))
+                    .ok_or_else(|| invalid("syntax error".to_string()))??;
+                // milliseconds are rounded up to the whole seconds the store supports.
+                let secs = if unit == b"EX" { n } else { n.div_ceil(1000) };
+                if secs == 0 || secs > u32::MAX as u64 {
+                    return Err(invalid("invalid expire time in 'set' command".to_string()));
+                }
+                exp_time = Some(secs as u32);
+            }
+            _ => return Err(invalid("syntax error".to_string())),
+        }
+    }
+    Ok(RespCommand::Set {
+        key,
+        data,
+        exp_time: exp_time.unwrap_or(0),
+        command,
+    })
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn to_key(arg: &Data) -> Result<String> {
+    if arg.len() > MAX_KEY_SIZE {
+        return Err(invalid("key too long".to_string()));
+    }
+    std::str::from_utf8(&arg.contiguous())
+        .ok()
+        .filter(|key| is_valid_key(key))
+        .map(str::to_string)
+        .ok_or_else(|| invalid("malformed key".to_string()))
+}
+
+fn to_keys(args: &[Data]) -> Result<Vec<String>> {
+    args.iter().map(to_key).collect()
+}
+
+/// The length of the longest integer argument, a `u64` or `i64`.
+const MAX_INT_LEN: usize = 20;
+
+fn to_int<T: std::str::FromStr>(arg: &Data) -> Result<T> {
+    Some(arg)
+        .filter(|arg| arg.len() <= MAX_INT_LEN)
+        .and_then(|arg| std::str::from_utf8(&arg.contiguous()).ok()?.parse().ok())
+        .ok_or_else(|| invalid("value is not an integer or out of range".to_string()))
+}
+
+/// A connection speaking RESP2, the framing used by Redis clients.
+pub(crate) struct RespConnection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    buffer: Vec<u8>,
+}
+
+impl RespConnection {
+    pub(crate) fn new(stream: TcpStream) -> RespConnection {
+        let (reader, writer) = stream.into_split();
+        RespConnection {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            buffer: Vec::with_capacity(512),
+        }
+    }
+
+    /// Wait until data is received, false if the client closed the connection. Cancel safe, unlike
+    /// `read_request`.
+    pub(crate) async fn readable(&mut self) -> Result<bool> {
+        Ok(!self.reader.fill_buf().await?.is_empty())
+    }
+
+    /// True if data has been received which has not been read as a request yet.
+    pub(crate) fn has_buffered(&self) -> bool {
+        !self.reader.buffer().is_empty()
+    }
+
+    /// Read the arguments of the next request, `None` if the client closed the connection. Bulk
+    /// strings over `max_size` are refused.
+    pub(crate) async fn read_request(&mut self, max_size: usize) -> Result<Option<Vec<Data>>> {
+        read_request(&mut self.reader, &mut self.buffer, max_size).await
+    }
+
+    pub(crate) async fn write_simple(&mut self, s: &str) -> Result<()> {
+        self.write_line(b'+', s.as_bytes()).await
+    }
+
+    pub(crate) async fn write_error(&mut self, message: &str) -> Result<()> {
+        self.write_line(b'-', format!("ERR {}", message).as_bytes())
+            .await
+    }
+
+    pub(crate) async fn write_integer(&mut self, n: i64) -> Result<()> {
+        self.write_line(b':', n.to_string().as_bytes()).await
+    }
+
+    pub(crate) async fn write_array_len(&mut self, len: usize) -> Result<()> {
+        self.write_line(b'*', len.to_string().as_bytes()).await
+    }
+
+    /// write a bulk string, `None` is written as the null bulk string.
+    pub(crate) async fn write_bulk(&mut self, data: Option<&Data>) -> Result<()> {
+        match data {
+            None => self.write_line(b'$', b"-1").await,
+            Some(data) => {
+                self.write_line(b'$', data.len().to_string().as_bytes())
+                    .await?;
+                for chunk in data.chunks() {
+                    self.writer.write_all(chunk).await?;
+                }
+                self.writer.write_all(b"\r\n").await
+            }
+        }
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        self.writer.flush().await
+    }
+
+    async fn write_line(&mut self, prefix: u8, line: &[u8]) -> Result<()> {
+        self.writer.write_u8(prefix).await?;
+        self.writer.write_all(line).await?;
+        self.writer.write_all(b"\r\n").await
+    }
+}
+
+/// Read a CRLF terminated line into `buf`, returning it without the terminator. `None` on a clean
+/// end of stream.
+async fn read_line<'a, R: AsyncBufRead + Unpin>(
+    r: &mut R,
+    buf: &'a mut Vec<u8>,
+) -> Result<Option<&'a [u8]>> {
+    buf.clear();
+    let len = r.read_until(b'\n', buf).await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if !buf.ends_with(b"\r\n") {
+        return Err(invalid("line not terminated with CRLF".to_string()));
+    }
+    Ok(Some(&buf[..len - 2]))
+}
+
+/// Read a request, either an array of bulk strings or an inline command as sent by telnet. Bulk
+/// strings are read in chunks like the data of the text protocol, and refused over `max_size`.
+async fn read_request<R: AsyncBufRead + Unpin>(
+    r: &mut R,
+    buf: &mut Vec<u8>,
+    max_size: usize,
+) -> Result<Option<Vec<Data>>> {
+    let Some(line) = read_line(r, buf).await? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec().into())
+            .collect();
+        return Ok(Some(args));
+    };
+    let count: i64 = to_int(&count.to_vec().into())?;
+    if count > MAX_ARGS as i64 {
+        return Err(invalid("too many arguments".to_string()));
+    }
+
+    let mut args = Vec::with_capacity(count.clamp(0, 16) as usize);
+    for _ in 0..count {
+        let len = match read_line(r, buf).await? {
+            Some(line) if line.starts_with(b"$") => to_int::<usize>(&line[1..].to_vec().into())?,
+            Some(_) => return Err(invalid("expected a bulk string".to_string())),
+            None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+        };
+        if len > max_size {
+            return Err(invalid("bulk string too large".to_string()));
+        }
+        let arg = Data::read_from(r, len).await?;
+        let mut crlf = [0; 2];
+        r.read_exact(&mut crlf).await?;
+        if &crlf != b"\r\n" {
+            return Err(invalid("bulk string not terminated with CRLF".to_string()));
+        }
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::BufReader;
+
+    use super::*;
+    use crate::data::CHUNK_SIZE;
+
+    fn args(s: &str) -> Vec<Data> {
+        s.split(' ')
+            .map(|arg| arg.as_bytes().to_vec().into())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let input = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$7\r\nva\r\nlue\r\nGET key\r\n";
+        let mut reader = BufReader::new(Cursor::new(input.to_vec()));
+        let mut buf = Vec::new();
+        let request = read_request(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(
+            Some(vec![
+                b"SET".to_vec().into(),
+                b"key".to_vec().into(),
+                b"va\r\nlue".to_vec().into()
+            ]),
+            request
+        );
+        let request = read_request(&mut reader, &mut buf, 1024).await.unwrap();
+        assert_eq!(Some(args("GET key")), request);
+        assert_eq!(
+            None,
+            read_request(&mut reader, &mut buf, 1024).await.unwrap()
+        );
+
+        let mut reader = BufReader::new(Cursor::new(b"*1\r\n$3\r\nGETX\r\n".to_vec()));
+        assert!(read_request(&mut reader, &mut buf, 1024).await.is_err());
+
+        // the size limit is the max item size of the store.
+        let mut reader = BufReader::new(Cursor::new(b"*1\r\n$5\r\nvalue\r\n".to_vec()));
+        assert!(read_request(&mut reader, &mut buf, 4).await.is_err());
+
+        // a large bulk string is read in chunks.
+        let len = CHUNK_SIZE + 1;
+        let input = [
+            format!("*1\r\n${}\r\n", len).as_bytes(),
+            &vec![b'v'; len],
+            b"\r\n",
+        ]
+        .concat();
+        let mut reader = BufReader::new(Cursor::new(input));
+        let request = read_request(&mut reader, &mut buf, len)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(&request[0], Data::Chunked(chunks) if chunks.len() == 2));
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            RespCommand::Set {
+                key: "key".to_string(),
+                data: b"value".to_vec().into(),
+                exp_time: 2,
+                command: StorageCommandType::Add,
+            },
+            RespCommand::parse(args("set key value nx px 1500")).unwrap()
+        );
+        assert_eq!(
+            RespCommand::IncrBy("key".to_string(), -5),
+            RespCommand::parse(args("DECRBY key 5")).unwrap()
+        );
+        assert_eq!(
+            RespCommand::MGet(vec!["a".to_string(), "b".to_string()]),
+            RespCommand::parse(args("MGET a b")).unwrap()
+        );
+        assert_eq!(
+            RespCommand::Ping(None),
+            RespCommand::parse(args("PING")).unwrap()
+        );
+
+        for bad in [
+            "set key value nx xx",
+            "set key value ex 0",
+            "get",
+            "incrby key x",
+            "flushall",
+        ] {
+            assert!(RespCommand::parse(args(bad)).is_err(), "{}", bad);
+        }
+        // keys are written out in watch events and INVALIDATE lines, they cannot hold whitespace.
+        for key in ["", "a b", "a\r\nflush_all", "a\x00"] {
+            let command = vec![b"GET".to_vec().into(), key.as_bytes().to_vec().into()];
+            assert!(RespCommand::parse(command).is_err(), "{:?}", key);
+        }
+        let err = RespCommand::parse(args("get a b")).unwrap_err();
+        assert_eq!(
+            "wrong number of arguments for 'get' command",
+            err.to_string()
+        );
+    }
+}
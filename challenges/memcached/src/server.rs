@@ -1,25 +1,66 @@
-use std::future::Future;
+use std::borrow::Cow;
+use std::future::{Future, poll_fn};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-use log::{error, info};
+use subtle::ConstantTimeEq;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::Receiver;
-use tokio::sync::mpsc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Notify, mpsc};
 use tokio::time;
+use tracing::{Instrument, Span, debug_span, error, field, info, info_span, warn};
 
+use crate::config::{Config, MAX_ITEM_SIZE_LIMIT, NAMESPACE_SEPARATOR};
 use crate::connection::Connection;
-use crate::protocol::{Command, RetrievalCommand};
-use crate::store::StoreProcessor;
+use crate::http;
+use crate::protocol::{
+    Command, RetrievalCommand, StatsGroup, StorageCommand, StorageCommandResponse,
+};
+use crate::proxy::{Proxy, Request};
+use crate::resp::{RespCommand, RespConnection};
+use crate::store::{Lookup, StoreProcessor};
+use crate::watch::{EventKind, Invalidation};
+
+/// The protocol spoken on a listening socket. All frontends serve the same store.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frontend {
+    Memcached,
+    /// RESP2, a subset of the Redis string commands.
+    Resp,
+    /// The HTTP/JSON gateway of `http.rs`.
+    Http,
+}
+
+/// The frontends served on the connections accepted by `Listener`, HTTP is served by axum.
+#[derive(Debug, Clone, Copy)]
+enum TcpFrontend {
+    Memcached,
+    Resp,
+}
 
 /// Server listener state. Created in the `run` call. It includes a `run` method
 /// which performs the TCP listening and initialization of per-connection state.
 struct Listener {
-    listener: TcpListener,
+    listeners: Vec<(TcpFrontend, TcpListener)>,
 
     processor: Arc<StoreProcessor>,
 
+    /// Set in proxy mode, memcached connections are forwarded to the backend pools.
+    proxy: Option<Arc<Proxy>>,
+
+    settings: Arc<Settings>,
+
+    /// Notified by the `shutdown` command.
+    shutdown_request: Arc<Notify>,
+
+    /// Identifies the connections in the logs.
+    next_connection_id: u64,
+
     /// Broadcasts a shutdown signal to all active connections.
     ///
     /// The initial `shutdown` trigger is provided by the `run` caller. The
@@ -49,18 +90,57 @@ impl Listener {
     pub async fn run(&mut self) -> std::io::Result<()> {
         info!("accepting inbound connections");
         loop {
-            let socket = self.accept().await?;
-            let mut handler = Handler {
-                con: Connection::new(socket),
-                processor: self.processor.clone(),
-                shutdown: self.notify_shutdown.subscribe(),
-                _shutdown_complete: self.shutdown_complete_tx.clone(),
-            };
-            tokio::spawn(async move {
-                if let Err(err) = handler.run().await {
-                    error!("connection error: {:?}", err);
+            let (frontend, socket, peer) = self.accept().await?;
+            self.next_connection_id += 1;
+            let span = info_span!(
+                "connection",
+                id = self.next_connection_id,
+                peer = %peer,
+                frontend = ?frontend
+            );
+            let processor = self.processor.clone();
+            let shutdown = self.notify_shutdown.subscribe();
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            match frontend {
+                TcpFrontend::Memcached => {
+                    let mut handler = Handler {
+                        con: Connection::new(socket),
+                        processor,
+                        proxy: self.proxy.clone(),
+                        settings: self.settings.clone(),
+                        namespace: None,
+                        authenticated: self.settings.admin_token.is_none(),
+                        shutdown_request: self.shutdown_request.clone(),
+                        shutdown,
+                        _shutdown_complete: shutdown_complete,
+                    };
+                    tokio::spawn(
+                        async move {
+                            if let Err(err) = handler.run().await {
+                                error!("connection error: {:?}", err);
+                            }
+                        }
+                        .instrument(span),
+                    );
                 }
-            });
+                TcpFrontend::Resp => {
+                    let mut handler = RespHandler {
+                        con: RespConnection::new(socket),
+                        processor,
+                        settings: self.settings.clone(),
+                        shutdown,
+                        _shutdown_complete: shutdown_complete,
+                    };
+                    tokio::spawn(
+                        async move {
+                            if let Err(err) = handler.run().await {
+                                error!("resp connection error: {:?}", err);
+                            }
+                        }
+                        .instrument(span),
+                    );
+                }
+            }
         }
     }
 
@@ -71,15 +151,15 @@ impl Listener {
     /// After the second failure, the task waits for 2 seconds. Each subsequent
     /// failure doubles the wait time. If accepting fails on the 6th try after
     /// waiting for 64 seconds, then this function returns with an error.
-    async fn accept(&mut self) -> std::io::Result<TcpStream> {
+    async fn accept(&mut self) -> std::io::Result<(TcpFrontend, TcpStream, SocketAddr)> {
         let mut backoff = 1;
 
         // Try to accept a few times
         loop {
             // Perform the accept operation. If a socket is successfully
             // accepted, return it. Otherwise, save the error.
-            match self.listener.accept().await {
-                Ok((socket, _)) => return Ok(socket),
+            match poll_fn(|cx| self.poll_accept(cx)).await {
+                Ok(accepted) => return Ok(accepted),
                 Err(err) => {
                     if backoff > 64 {
                         // Accept has failed too many times. Return the error.
@@ -95,11 +175,31 @@ impl Listener {
             backoff *= 2;
         }
     }
+
+    /// Poll the listening sockets in turn for an inbound connection.
+    fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<(TcpFrontend, TcpStream, SocketAddr)>> {
+        for (frontend, listener) in &self.listeners {
+            if let Poll::Ready(res) = listener.poll_accept(cx) {
+                return Poll::Ready(res.map(|(socket, peer)| (*frontend, socket, peer)));
+            }
+        }
+        Poll::Pending
+    }
 }
 
 struct Handler {
     con: Connection,
     processor: Arc<StoreProcessor>,
+    proxy: Option<Arc<Proxy>>,
+    settings: Arc<Settings>,
+    /// Set by the `namespace` command.
+    namespace: Option<String>,
+    /// The admin commands are accepted, set by `auth` when an admin token is configured.
+    authenticated: bool,
+    shutdown_request: Arc<Notify>,
     shutdown: Receiver<()>,
     /// Not used directly. Instead, when `Handler` is dropped
     _shutdown_complete: mpsc::Sender<()>,
@@ -108,29 +208,298 @@ struct Handler {
 impl Handler {
     async fn run(&mut self) -> std::io::Result<()> {
         loop {
+            // only waiting for data is cancelled by the shutdown, once a command has started to
+            // arrive it is read and executed in full.
             tokio::select! {
-                com = self.con.read_command() => {
-                    let com = com.expect("could not read command");
-                    match com {
-                        Command::Storage(cmd) => {
-                            let no_reply = cmd.no_reply;
-                            let res = self.processor.execute_storage_command(cmd).await?;
-                            if !no_reply {
-                                self.con.write_response(res.to_kw_bytes()).await?;
-                            }
+                biased;
+                _ = self.shutdown.recv() => {
+                    return self.drain().await;
+                }
+                readable = self.con.readable() => {
+                    if !readable? {
+                        return Ok(());
+                    }
+                    match self.con.read_command(self.processor.max_item_size()).await? {
+                        Ok(Command::Watch(kinds)) if self.proxy.is_none() => {
+                            // the connection is dedicated to the watch from here on.
+                            return self.watch(kinds).await;
                         }
-                        Command::Retrieval(cmd) => {
-                            match cmd {
-                                RetrievalCommand::Get { key } => {
-                                    if let Some(val) = self.processor.get(key.as_str()).await {
-                                        self.con.write_value(&key, val).await?;
-                                    }
-                                    self.con.write_response(b"END").await?;
-                                }
-                            }
+                        Ok(Command::Subscribe(prefixes)) if self.proxy.is_none() => {
+                            return self.subscribe(prefixes).await;
+                        }
+                        Ok(com) => self.execute(com).await?,
+                        Err(err) => self.con.write_response(err.to_string().as_bytes()).await?,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Execute the commands the client sent before the shutdown and flush their replies.
+    async fn drain(&mut self) -> std::io::Result<()> {
+        while self.con.has_received()? {
+            match self
+                .con
+                .read_command(self.processor.max_item_size())
+                .await?
+            {
+                Ok(Command::Watch(_) | Command::Subscribe(_)) if self.proxy.is_none() => break,
+                Ok(com) => self.execute(com).await?,
+                Err(err) => self.con.write_response(err.to_string().as_bytes()).await?,
+            }
+        }
+        self.con.flush().await
+    }
+
+    /// Execute a command in a span carrying its name and key, logging it if it is slow.
+    async fn execute(&mut self, com: Command) -> std::io::Result<()> {
+        let span = CommandSpan::new(com.name(), com.key(), self.settings.slow_command());
+        let res = self.dispatch(com).instrument(span.span().clone()).await;
+        span.finish();
+        res
+    }
+
+    async fn dispatch(&mut self, com: Command) -> std::io::Result<()> {
+        if com.is_admin() || matches!(com, Command::Auth(_)) {
+            return self.admin(com).await;
+        }
+        if let Some(proxy) = self.proxy.clone() {
+            return self.forward(&proxy, com).await;
+        }
+        match com {
+            Command::Storage(mut cmd) => {
+                let no_reply = cmd.no_reply;
+                cmd.key = self.scoped(&cmd.key).into_owned();
+                let res = self.processor.execute_storage_command(cmd).await?;
+                if !no_reply {
+                    self.con.write_response(res.to_kw_bytes()).await?;
+                }
+            }
+            Command::Retrieval(RetrievalCommand::Get { key }) => {
+                if let Some(val) = self.processor.get(&self.scoped(&key)).await {
+                    self.con.write_value(&key, val, false).await?;
+                }
+                self.con.write_response(b"END").await?;
+            }
+            Command::Retrieval(RetrievalCommand::Gets { key }) => {
+                match self.processor.gets(&self.scoped(&key)).await {
+                    Lookup::Hit(val) => self.con.write_value(&key, val, true).await?,
+                    Lookup::Miss => {}
+                    Lookup::Lease(grant) => self.con.write_lease(&key, grant).await?,
+                }
+                self.con.write_response(b"END").await?;
+            }
+            Command::Delete { key, no_reply } => {
+                let deleted = self.processor.delete(&self.scoped(&key)).await;
+                if !no_reply {
+                    let res: &[u8] = if deleted { b"DELETED" } else { b"NOT_FOUND" };
+                    self.con.write_response(res).await?;
+                }
+            }
+            Command::Touch {
+                key,
+                exp_time,
+                no_reply,
+            } => {
+                let touched = self.processor.touch(&self.scoped(&key), exp_time).await;
+                if !no_reply {
+                    let res: &[u8] = if touched { b"TOUCHED" } else { b"NOT_FOUND" };
+                    self.con.write_response(res).await?;
+                }
+            }
+            Command::Stats(StatsGroup::General) => {
+                for (name, value) in self.processor.stats() {
+                    self.con.write_stat(name, value).await?;
+                }
+                self.con.write_response(b"END").await?;
+            }
+            Command::Stats(StatsGroup::HotKeys) => {
+                for (key, accesses) in self.processor.hotkeys() {
+                    self.con.write_stat(&key, accesses).await?;
+                }
+                self.con.write_response(b"END").await?;
+            }
+            Command::Stats(StatsGroup::Namespaces) => {
+                for (name, value) in self.processor.namespace_stats() {
+                    self.con.write_stat(&name, value).await?;
+                }
+                self.con.write_response(b"END").await?;
+            }
+            Command::Namespace(name) => {
+                if self.processor.has_namespace(&name) {
+                    self.namespace = Some(name);
+                    self.con.write_response(b"OK").await?;
+                } else {
+                    self.con.write_response(b"NOT_FOUND").await?;
+                }
+            }
+            Command::MetaDump => {
+                for (key, meta) in self.processor.metadump() {
+                    self.con.write_meta(&key, &meta).await?;
+                }
+                self.con.write_response(b"END").await?;
+            }
+            Command::Watch(_) | Command::Subscribe(_) => unreachable!("served by `run`"),
+            Command::CacheMemLimit(_)
+            | Command::ItemSizeMax(_)
+            | Command::Shutdown
+            | Command::Auth(_) => unreachable!("served by `admin`"),
+        }
+        Ok(())
+    }
+
+    /// Serve the commands changing the server, which are allowed after `auth` when an admin
+    /// token is configured. They are served by a proxy too.
+    async fn admin(&mut self, com: Command) -> std::io::Result<()> {
+        if com.is_admin() && !self.authenticated {
+            return self
+                .con
+                .write_response(b"CLIENT_ERROR unauthenticated")
+                .await;
+        }
+        match com {
+            Command::Auth(token) => {
+                // compared in constant time, the time of the reply does not tell how much of the
+                // token was guessed right.
+                let expected = self.settings.admin_token.as_deref().map(str::as_bytes);
+                if expected.is_some_and(|expected| expected.ct_eq(token.as_bytes()).into()) {
+                    self.authenticated = true;
+                    self.con.write_response(b"OK").await
+                } else {
+                    warn!("admin authentication failed");
+                    self.con
+                        .write_response(b"CLIENT_ERROR authentication failed")
+                        .await
+                }
+            }
+            Command::CacheMemLimit(megabytes) => {
+                let memory_limit = megabytes.saturating_mul(1024 * 1024);
+                match self.processor.set_memory_limit(memory_limit).await {
+                    Ok(()) => {
+                        info!(megabytes, "memory limit changed");
+                        self.con.write_response(b"OK").await
+                    }
+                    Err(err) => {
+                        let res = format!("CLIENT_ERROR {}", err);
+                        self.con.write_response(res.as_bytes()).await
+                    }
+                }
+            }
+            Command::ItemSizeMax(bytes) => {
+                if bytes == 0 || bytes > MAX_ITEM_SIZE_LIMIT {
+                    return self
+                        .con
+                        .write_response(b"CLIENT_ERROR item size out of range")
+                        .await;
+                }
+                self.processor.set_max_item_size(bytes);
+                info!(bytes, "max item size changed");
+                self.con.write_response(b"OK").await
+            }
+            Command::Shutdown => {
+                if !self.settings.enable_shutdown {
+                    return self
+                        .con
+                        .write_response(b"ERROR: shutdown not enabled")
+                        .await;
+                }
+                self.shutdown_request.notify_one();
+                self.con.write_response(b"OK").await
+            }
+            _ => unreachable!("not an admin command"),
+        }
+    }
+}
+
+impl Handler {
+    /// The key as stored, prefixed with the namespace selected on the connection.
+    fn scoped<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match &self.namespace {
+            Some(ns) => Cow::Owned(format!("{}{}{}", ns, NAMESPACE_SEPARATOR, key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Forward a command to the backends of the proxy. Only the stats of the routes are served
+    /// locally, the commands on the local store are not supported.
+    async fn forward(&mut self, proxy: &Proxy, com: Command) -> std::io::Result<()> {
+        match Request::try_from(com) {
+            Ok(req) => {
+                let no_reply = req.no_reply;
+                let reply = proxy.forward(req).await;
+                if !no_reply {
+                    self.con.write_raw(&reply).await?;
+                }
+                Ok(())
+            }
+            Err(Command::Stats(StatsGroup::General)) => {
+                for (name, value) in proxy.stats() {
+                    self.con.write_stat(&name, value).await?;
+                }
+                self.con.write_response(b"END").await
+            }
+            Err(_) => {
+                self.con
+                    .write_response(b"SERVER_ERROR not supported by the proxy")
+                    .await
+            }
+        }
+    }
+
+    /// Stream live events to the connection until it is closed or the server shuts down.
+    async fn watch(&mut self, kinds: Vec<EventKind>) -> std::io::Result<()> {
+        let mut events = self.processor.watch();
+        self.con.write_response(b"OK").await?;
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        if kinds.is_empty() || kinds.contains(&event.kind) {
+                            self.con.write_response(event.to_string().as_bytes()).await?;
                         }
                     }
+                    Err(RecvError::Lagged(skipped)) => {
+                        let line = format!("type=skipped count={}", skipped);
+                        self.con.write_response(line.as_bytes()).await?;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = self.shutdown.recv() => {
+                    return Ok(());
                 }
+            }
+        }
+    }
+
+    /// Push `INVALIDATE <key> <reason>` for every change to a key under one of the prefixes, until
+    /// the connection is closed or the server shuts down. The keys and prefixes are relative to
+    /// the namespace of the connection. A subscriber which falls behind is sent `INVALIDATE_ALL`,
+    /// as it can no longer tell which keys changed.
+    async fn subscribe(&mut self, prefixes: Vec<String>) -> std::io::Result<()> {
+        let scope = self.scoped("").into_owned();
+        let prefixes: Vec<_> = prefixes
+            .iter()
+            .map(|p| self.scoped(p).into_owned())
+            .collect();
+        let mut invalidations = self.processor.invalidations();
+        self.con.write_response(b"OK").await?;
+        loop {
+            tokio::select! {
+                invalidation = invalidations.recv() => match invalidation {
+                    Ok(Invalidation { key, reason }) => {
+                        let Some(relative) = key.strip_prefix(&scope) else {
+                            continue;
+                        };
+                        if prefixes.is_empty() || prefixes.iter().any(|p| key.starts_with(p)) {
+                            let line = format!("INVALIDATE {} {}", relative, reason);
+                            self.con.write_response(line.as_bytes()).await?;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        self.con.write_response(b"INVALIDATE_ALL").await?;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
                 _ = self.shutdown.recv() => {
                     return Ok(());
                 }
@@ -139,8 +508,274 @@ impl Handler {
     }
 }
 
-pub async fn run(listener: TcpListener, shutdown: impl Future) {
-    let processor = Arc::new(StoreProcessor::new());
+/// Serves the Redis commands of `RespCommand` from the same store as the memcached handler.
+struct RespHandler {
+    con: RespConnection,
+    processor: Arc<StoreProcessor>,
+    settings: Arc<Settings>,
+    shutdown: Receiver<()>,
+    _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl RespHandler {
+    async fn run(&mut self) -> std::io::Result<()> {
+        loop {
+            tokio::select! {
+                biased;
+                _ = self.shutdown.recv() => {
+                    // execute the requests already received before closing.
+                    while self.con.has_buffered() {
+                        if !self.next_request().await? {
+                            break;
+                        }
+                    }
+                    return self.con.flush().await;
+                }
+                readable = self.con.readable() => {
+                    if !readable? || !self.next_request().await? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read and execute a request, false if the client closed the connection.
+    async fn next_request(&mut self) -> std::io::Result<bool> {
+        let max_size = self.processor.max_item_size();
+        let Some(args) = self.con.read_request(max_size).await? else {
+            return Ok(false);
+        };
+        if args.is_empty() {
+            return Ok(true);
+        }
+        match RespCommand::parse(args) {
+            Ok(cmd) => {
+                let span = CommandSpan::new(cmd.name(), cmd.key(), self.settings.slow_command());
+                let res = self.execute(cmd).instrument(span.span().clone()).await;
+                span.finish();
+                res?;
+            }
+            Err(err) => self.con.write_error(&err.to_string()).await?,
+        }
+        self.con.flush().await?;
+        Ok(true)
+    }
+
+    async fn execute(&mut self, cmd: RespCommand) -> std::io::Result<()> {
+        match cmd {
+            RespCommand::Ping(None) => self.con.write_simple("PONG").await,
+            RespCommand::Ping(Some(msg)) => self.con.write_bulk(Some(&msg)).await,
+            RespCommand::Get(key) => {
+                let val = self.processor.get(&key).await;
+                self.con.write_bulk(val.as_ref().map(|v| &v.data)).await
+            }
+            RespCommand::MGet(keys) => {
+                self.con.write_array_len(keys.len()).await?;
+                for key in keys {
+                    let val = self.processor.get(&key).await;
+                    self.con.write_bulk(val.as_ref().map(|v| &v.data)).await?;
+                }
+                Ok(())
+            }
+            RespCommand::Set {
+                key,
+                data,
+                exp_time,
+                command,
+            } => {
+                let res = self
+                    .processor
+                    .execute_storage_command(StorageCommand {
+                        command,
+                        key,
+                        flags: 0,
+                        exp_time,
+                        no_reply: false,
+                        byte_count: data.len() as u32,
+                        cas_unique: 0,
+                        data,
+                    })
+                    .await?;
+                match res {
+                    StorageCommandResponse::Stored => self.con.write_simple("OK").await,
+                    _ => self.con.write_bulk(None).await,
+                }
+            }
+            RespCommand::Del(keys) => {
+                let mut deleted = 0;
+                for key in keys {
+                    if self.processor.delete(&key).await {
+                        deleted += 1;
+                    }
+                }
+                self.con.write_integer(deleted).await
+            }
+            RespCommand::IncrBy(key, delta) => match self.processor.incr(&key, delta).await {
+                Ok(n) => self.con.write_integer(n).await,
+                Err(err) => self.con.write_error(&err.to_string()).await,
+            },
+            RespCommand::Expire(key, secs) => {
+                // an exp_time of zero means no expiry to the store, so an expiry in the past
+                // deletes the key as Redis does.
+                let updated = if secs <= 0 {
+                    self.processor.delete(&key).await
+                } else {
+                    let exp_time = secs.min(u32::MAX as i64) as u32;
+                    self.processor.touch(&key, exp_time).await
+                };
+                self.con.write_integer(updated as i64).await
+            }
+            RespCommand::Ttl(key) => {
+                let ttl = match self.processor.ttl(&key).await {
+                    None => -2,
+                    Some(None) => -1,
+                    Some(Some(ttl)) => ttl.as_millis().div_ceil(1000) as i64,
+                };
+                self.con.write_integer(ttl).await
+            }
+            RespCommand::Append(key, data) => {
+                match self.processor.append_or_insert(&key, &data).await {
+                    Ok(len) => self.con.write_integer(len as i64).await,
+                    Err(err) => self.con.write_error(&err.to_string()).await,
+                }
+            }
+        }
+    }
+}
+
+/// The settings shared by the listener and the handlers, the slow command threshold is changed
+/// by a configuration reload.
+pub(crate) struct Settings {
+    /// Commands taking at least this many microseconds are logged as slow, `u64::MAX` disables it.
+    slow_command_us: AtomicU64,
+    enable_shutdown: bool,
+    admin_token: Option<String>,
+}
+
+impl Settings {
+    pub(crate) fn new(config: &Config) -> Settings {
+        let settings = Settings {
+            slow_command_us: AtomicU64::new(u64::MAX),
+            enable_shutdown: config.enable_shutdown,
+            admin_token: config.admin_token.clone(),
+        };
+        settings.apply(config);
+        settings
+    }
+
+    fn apply(&self, config: &Config) {
+        let slow_command_us = config
+            .slow_command
+            .map_or(u64::MAX, |threshold| threshold.as_micros() as u64);
+        self.slow_command_us
+            .store(slow_command_us, Ordering::Relaxed);
+    }
+
+    pub(crate) fn slow_command(&self) -> Option<Duration> {
+        match self.slow_command_us.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+}
+
+/// Apply the configurations received on `reload` to the running server. Only the settings which
+/// do not need a restart are applied, never completes.
+async fn reload_config(
+    mut reload: mpsc::Receiver<Config>,
+    processor: Arc<StoreProcessor>,
+    settings: Arc<Settings>,
+) {
+    while let Some(config) = reload.recv().await {
+        processor.set_max_item_size(config.max_item_size);
+        if config.memory_limit != processor.memory_limit()
+            && let Err(err) = processor.set_memory_limit(config.memory_limit).await
+        {
+            error!(cause = %err, "failed to change the memory limit");
+        }
+        settings.apply(&config);
+        info!(
+            max_item_size = config.max_item_size,
+            memory_limit = processor.memory_limit(),
+            slow_command = ?config.slow_command,
+            "configuration reloaded"
+        );
+    }
+    std::future::pending().await
+}
+
+/// The `debug` span of a command, with its name and key. `finish` records the latency of the
+/// command, and logs it if it took at least the slow command threshold.
+pub(crate) struct CommandSpan {
+    span: Span,
+    command: &'static str,
+    /// The key for the slow command log. It is only copied when the span is filtered out, the
+    /// log has the fields of the span otherwise.
+    key: Option<String>,
+    started: Instant,
+    slow_command: Option<Duration>,
+}
+
+impl CommandSpan {
+    pub(crate) fn new(
+        command: &'static str,
+        key: Option<&str>,
+        slow_command: Option<Duration>,
+    ) -> CommandSpan {
+        let span = debug_span!("command", command, key, latency_us = field::Empty);
+        let key = match slow_command {
+            Some(_) if span.is_disabled() => key.map(str::to_string),
+            _ => None,
+        };
+        CommandSpan {
+            span,
+            command,
+            key,
+            started: Instant::now(),
+            slow_command,
+        }
+    }
+
+    pub(crate) fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub(crate) fn finish(self) {
+        let elapsed = self.started.elapsed();
+        let latency_us = elapsed.as_micros() as u64;
+        self.span.record("latency_us", latency_us);
+        if self
+            .slow_command
+            .is_none_or(|threshold| elapsed < threshold)
+        {
+            return;
+        }
+        let command = self.command;
+        match self.key {
+            Some(key) => warn!(command, key, latency_us, "slow command"),
+            None if self.span.is_disabled() => warn!(command, latency_us, "slow command"),
+            None => warn!(parent: &self.span, latency_us, "slow command"),
+        }
+    }
+}
+
+/// Serve the store on every listener with the protocol of its frontend. The configurations sent on
+/// `reload` are applied without dropping the connections. When `shutdown` completes the listeners
+/// are closed and the connections are given `drain_timeout` to finish the commands in flight.
+/// Fails if the store cannot be created from `config`.
+pub async fn run(
+    listeners: Vec<(Frontend, TcpListener)>,
+    config: Config,
+    shutdown: impl Future,
+    reload: mpsc::Receiver<Config>,
+) -> std::io::Result<()> {
+    let processor = Arc::new(StoreProcessor::new(&config)?);
+    let proxy = if config.routes.is_empty() {
+        None
+    } else {
+        Some(Arc::new(Proxy::new(&config)?))
+    };
 
     // When the provided `shutdown` future completes, we must send a shutdown
     // message to all active connections. We use a broadcast channel for this
@@ -150,9 +785,42 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) {
     let (notify_shutdown, _) = broadcast::channel(1);
     let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
 
+    // axum manages the connections of the HTTP listeners, it is handed the shutdown signal and a
+    // `shutdown_complete` sender like a connection handler.
+    let mut tcp = Vec::with_capacity(listeners.len());
+    let mut http = Vec::new();
+    for (frontend, listener) in listeners {
+        match frontend {
+            Frontend::Memcached => tcp.push((TcpFrontend::Memcached, listener)),
+            Frontend::Resp => tcp.push((TcpFrontend::Resp, listener)),
+            Frontend::Http => http.push(listener),
+        }
+    }
+    let settings = Arc::new(Settings::new(&config));
+    for listener in http {
+        let app = http::router(processor.clone(), settings.clone());
+        let mut shutdown = notify_shutdown.subscribe();
+        let shutdown_complete = shutdown_complete_tx.clone();
+        tokio::spawn(async move {
+            let res = axum::serve(listener, app)
+                .with_graceful_shutdown(async move {
+                    let _ = shutdown.recv().await;
+                })
+                .await;
+            if let Err(err) = res {
+                error!("http server error: {:?}", err);
+            }
+            drop(shutdown_complete);
+        });
+    }
+
     let mut server = Listener {
         processor,
-        listener,
+        proxy,
+        settings,
+        shutdown_request: Arc::new(Notify::new()),
+        next_connection_id: 0,
+        listeners: tcp,
         notify_shutdown,
         shutdown_complete_tx,
     };
@@ -176,33 +844,41 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) {
     // asynchronous Rust. See the API docs for more details:
     //
     // https://docs.rs/tokio/*/tokio/macro.select.html
+    let reloads = reload_config(reload, server.processor.clone(), server.settings.clone());
+    let shutdown_request = server.shutdown_request.clone();
     tokio::select! {
         res = server.run() => {
-            // // If an error is received here, accepting connections from the TCP
-            // // listener failed multiple times and the server is giving up and
-            // // shutting down.
-            // //
-            // // Errors encountered when handling individual connections do not
-            // // bubble up to this point.
+            // If an error is received here, accepting connections from the TCP
+            // listener failed multiple times and the server is giving up and
+            // shutting down.
+            //
+            // Errors encountered when handling individual connections do not
+            // bubble up to this point.
             if let Err(err) = res {
-                panic!("todo: handle error: {:?}", err);
-                // error!(cause = %err, "failed to accept");
+                error!(cause = %err, "failed to accept");
             }
         }
         _ = shutdown => {
             // The shutdown signal has been received.
             info!("shutting down");
         }
+        _ = shutdown_request.notified() => {
+            info!("shutting down on request");
+        }
+        _ = reloads => {}
     }
 
     // Extract the `shutdown_complete` receiver and transmitter
     // explicitly drop `shutdown_transmitter`. This is important, as the
-    // `.await` below would otherwise never complete.
+    // `.await` below would otherwise never complete. The listening sockets
+    // are dropped too, so no new connections are accepted while draining.
     let Listener {
         notify_shutdown,
         shutdown_complete_tx,
+        listeners,
         ..
     } = server;
+    drop(listeners);
 
     // When `notify_shutdown` is dropped, all tasks which have `subscribe`d will
     // receive the shutdown signal and can exit
@@ -213,6 +889,392 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) {
     // Wait for all active connections to finish processing. As the `Sender`
     // handle held by the listener has been dropped above, the only remaining
     // `Sender` instances are held by connection handler tasks. When those drop,
-    // the `mpsc` channel will close and `recv()` will return `None`.
-    shutdown_complete_rx.recv().await;
+    // the `mpsc` channel will close and `recv()` will return `None`. A client
+    // which never finishes its command must not hold up the exit forever.
+    let drained = time::timeout(config.drain_timeout, shutdown_complete_rx.recv()).await;
+    if drained.is_err() {
+        warn!(timeout = ?config.drain_timeout, "connections still busy, exiting");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::sync::oneshot;
+    use tracing::Level;
+    use tracing::subscriber::DefaultGuard;
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    use super::*;
+    use crate::connection::MAX_DATA_SIZE;
+
+    /// Collects the logs written on the test thread.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogBuffer {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    /// Capture the logs up to `level` until the guard is dropped, with the closing of the spans.
+    /// The servers of a test run on its thread, so their logs are captured too.
+    fn capture_logs(level: Level) -> (LogBuffer, DefaultGuard) {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(level)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    /// Start a server with `config` serving `frontend` on a free port, it runs until the sender
+    /// is dropped.
+    pub(crate) async fn start_server(
+        frontend: Frontend,
+        config: Config,
+    ) -> (SocketAddr, oneshot::Sender<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        tokio::spawn(run(
+            vec![(frontend, listener)],
+            config,
+            shutdown_rx,
+            mpsc::channel(1).1,
+        ));
+        (addr, shutdown_tx)
+    }
+
+    /// Send `request` and read the reply up to and including `last_line`.
+    async fn exchange(
+        client: &mut BufReader<TcpStream>,
+        request: &[u8],
+        last_line: &str,
+    ) -> String {
+        client.get_mut().write_all(request).await.unwrap();
+        read_until_line(client, last_line).await
+    }
+
+    async fn read_until_line(client: &mut BufReader<TcpStream>, last_line: &str) -> String {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            let n = time::timeout(Duration::from_secs(1), client.read_line(&mut line))
+                .await
+                .expect("timed out waiting for a reply")
+                .unwrap();
+            reply.push_str(&line);
+            if n == 0 || line.trim_end() == last_line {
+                return reply;
+            }
+        }
+    }
+
+    async fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        BufReader::new(TcpStream::connect(addr).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let (addr, _shutdown) = start_server(Frontend::Memcached, Config::default()).await;
+        let mut subscriber = connect(addr).await;
+        assert_eq!(
+            "OK\r\n",
+            exchange(&mut subscriber, b"subscribe user:\r\n", "OK").await
+        );
+
+        let mut client = connect(addr).await;
+        exchange(&mut client, b"set other 0 0 1\r\na\r\n", "STORED").await;
+        exchange(&mut client, b"set user:1 0 0 1\r\na\r\n", "STORED").await;
+        exchange(&mut client, b"get user:1\r\n", "END").await;
+        exchange(&mut client, b"delete user:1\r\n", "DELETED").await;
+
+        // only the changes to the keys under the prefix are pushed.
+        let line = read_until_line(&mut subscriber, "INVALIDATE user:1 set").await;
+        assert_eq!("INVALIDATE user:1 set\r\n", line);
+        let line = read_until_line(&mut subscriber, "INVALIDATE user:1 delete").await;
+        assert_eq!("INVALIDATE user:1 delete\r\n", line);
+    }
+
+    #[tokio::test]
+    async fn test_slow_commands() {
+        let slow = Config {
+            slow_command: Some(Duration::ZERO),
+            ..Config::default()
+        };
+        // the key is logged whether the command span is enabled or not.
+        for level in [Level::WARN, Level::DEBUG] {
+            let (logs, _guard) = capture_logs(level);
+            let (addr, _shutdown) = start_server(Frontend::Memcached, slow.clone()).await;
+            let mut client = connect(addr).await;
+            exchange(&mut client, b"set slow:key 0 0 1\r\na\r\n", "STORED").await;
+            let logs = logs.take();
+            let line = logs.lines().find(|l| l.contains("slow command")).unwrap();
+            assert!(line.contains("WARN"), "{}", line);
+            assert!(
+                line.contains("set") && line.contains("slow:key"),
+                "{}",
+                line
+            );
+            assert!(line.contains("latency_us="), "{}", line);
+        }
+
+        // commands under the threshold are not logged.
+        let (logs, _guard) = capture_logs(Level::WARN);
+        let fast = Config {
+            slow_command: Some(Duration::from_secs(3600)),
+            ..Config::default()
+        };
+        let (addr, _shutdown) = start_server(Frontend::Memcached, fast).await;
+        let mut client = connect(addr).await;
+        exchange(&mut client, b"set key 0 0 1\r\na\r\n", "STORED").await;
+        assert!(!logs.take().contains("slow command"));
+    }
+
+    #[tokio::test]
+    async fn test_command_spans() {
+        let (logs, _guard) = capture_logs(Level::DEBUG);
+        let (addr, _shutdown) = start_server(Frontend::Memcached, Config::default()).await;
+        let mut client = connect(addr).await;
+        exchange(&mut client, b"get some:key\r\n", "END").await;
+        let lines = logs.take();
+        let line = lines
+            .lines()
+            .find(|l| l.contains("command{") && l.contains("close"))
+            .unwrap();
+        assert!(
+            line.contains("command{command=\"get\" key=\"some:key\""),
+            "{}",
+            line
+        );
+        assert!(line.contains("latency_us="), "{}", line);
+        assert!(!lines.contains("slow command"));
+
+        // the RESP and HTTP frontends trace their commands the same way.
+        for (frontend, request) in [
+            (Frontend::Resp, "*2\r\n$3\r\nGET\r\n$8\r\nsome:key\r\n"),
+            (
+                Frontend::Http,
+                "GET /keys/some:key HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+            ),
+        ] {
+            let (addr, _shutdown) = start_server(frontend, Config::default()).await;
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut reply = [0; 512];
+            // the span is closed before the reply is written.
+            assert!(client.read(&mut reply).await.unwrap() > 0);
+            let lines = logs.take();
+            let line = lines
+                .lines()
+                .find(|l| l.contains("command{") && l.contains("close"))
+                .unwrap();
+            assert!(
+                line.contains("command=\"get\" key=\"some:key\""),
+                "{}",
+                line
+            );
+            assert!(line.contains("latency_us="), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_commands() {
+        let (addr, _shutdown) = start_server(Frontend::Memcached, Config::default()).await;
+        let mut client = connect(addr).await;
+        let too_large = format!(
+            "set a 0 0 {}\r\n{}\r\n",
+            MAX_DATA_SIZE + 1,
+            "a".repeat(MAX_DATA_SIZE as usize + 1)
+        );
+        let replies = [
+            (
+                too_large.as_bytes(),
+                "SERVER_ERROR object too large for cache",
+            ),
+            (b"bogus\r\n", "ERROR"),
+            (b"get\r\n", "CLIENT_ERROR missing key"),
+            (b"set a 0 0 1\r\nab\r\n", "CLIENT_ERROR bad data chunk"),
+        ];
+        for (request, reply) in replies {
+            client.get_mut().write_all(request).await.unwrap();
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            assert_eq!(format!("{}\r\n", reply), line);
+        }
+        // the remains of the data block are read as a command, then the connection is in sync.
+        exchange(
+            &mut client,
+            b"",
+            "CLIENT_ERROR command not terminated with CRLF",
+        )
+        .await;
+        exchange(&mut client, b"set a 0 0 1\r\na\r\n", "STORED").await;
+    }
+
+    #[tokio::test]
+    async fn test_admin_commands() {
+        let config = Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let (addr, _shutdown) = start_server(Frontend::Memcached, config).await;
+        let mut client = connect(addr).await;
+        exchange(&mut client, b"set key 0 0 5\r\nvalue\r\n", "STORED").await;
+        let replies = [
+            ("cache_memlimit 64", "CLIENT_ERROR unauthenticated"),
+            ("auth secreT", "CLIENT_ERROR authentication failed"),
+            ("auth secrets", "CLIENT_ERROR authentication failed"),
+            ("cache_memlimit 64", "CLIENT_ERROR unauthenticated"),
+            ("auth secret", "OK"),
+            ("cache_memlimit 64", "OK"),
+            ("shutdown", "ERROR: shutdown not enabled"),
+        ];
+        for (command, reply) in replies {
+            let request = format!("{}\r\n", command);
+            let line = exchange(&mut client, request.as_bytes(), reply).await;
+            assert_eq!(format!("{}\r\n", reply), line, "{}", command);
+        }
+
+        // the items are kept by the resize.
+        let stats = exchange(&mut client, b"stats\r\n", "END").await;
+        assert!(
+            stats.contains("STAT limit_maxbytes 67108864\r\n"),
+            "{}",
+            stats
+        );
+        let value = exchange(&mut client, b"get key\r\n", "END").await;
+        assert_eq!("VALUE key 0 5\r\nvalue\r\nEND\r\n", value);
+
+        // the other connections are not authenticated.
+        let mut other = connect(addr).await;
+        let line = exchange(
+            &mut other,
+            b"item_size_max 1024\r\n",
+            "CLIENT_ERROR unauthenticated",
+        )
+        .await;
+        assert_eq!("CLIENT_ERROR unauthenticated\r\n", line);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_command() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            enable_shutdown: true,
+            ..Config::default()
+        };
+        let server = tokio::spawn(run(
+            vec![(Frontend::Memcached, listener)],
+            config,
+            std::future::pending::<()>(),
+            mpsc::channel(1).1,
+        ));
+        let mut client = connect(addr).await;
+        assert_eq!("OK\r\n", exchange(&mut client, b"shutdown\r\n", "OK").await);
+        // the connection is drained and closed, and the server exits.
+        let mut rest = Vec::new();
+        assert_eq!(0, client.read_to_end(&mut rest).await.unwrap());
+        time::timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let (reload_tx, reload_rx) = mpsc::channel(1);
+        tokio::spawn(run(
+            vec![(Frontend::Memcached, listener)],
+            Config::default(),
+            shutdown_rx,
+            reload_rx,
+        ));
+        let memory_limit = 64 * 1024 * 1024;
+        let config = Config {
+            max_item_size: 4,
+            memory_limit,
+            ..Config::default()
+        };
+        reload_tx.send(config).await.unwrap();
+
+        // the reload is applied by another task, the connection stays open.
+        let mut client = connect(addr).await;
+        let too_large = "SERVER_ERROR object too large for cache\r\n";
+        loop {
+            client
+                .get_mut()
+                .write_all(b"set a 0 0 5\r\nhello\r\n")
+                .await
+                .unwrap();
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            if line == too_large {
+                break;
+            }
+            assert_eq!("STORED\r\n", line);
+            tokio::task::yield_now().await;
+        }
+        let stats = exchange(&mut client, b"stats\r\n", "END").await;
+        let limit = format!("STAT limit_maxbytes {}\r\n", memory_limit);
+        assert!(stats.contains(&limit), "{}", stats);
+    }
+
+    #[tokio::test]
+    async fn test_drain_completes_command_in_flight() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(run(
+            vec![(Frontend::Memcached, listener)],
+            Config::default(),
+            shutdown_rx,
+            mpsc::channel(1).1,
+        ));
+
+        // the shutdown arrives in the middle of a command, which is still executed. It is sent
+        // with a `get`, so it has been received once the `get` is answered.
+        let mut client = connect(addr).await;
+        exchange(&mut client, b"get b\r\nset b 0 0 3\r\nab", "END").await;
+        shutdown_tx.send(()).unwrap();
+        // the listener is closed once the shutdown is received.
+        while TcpStream::connect(addr).await.is_ok() {
+            tokio::task::yield_now().await;
+        }
+        let reply = exchange(&mut client, b"c\r\n", "STORED").await;
+        assert_eq!("STORED\r\n", reply);
+
+        // the connection is closed once the command is done, and the server exits.
+        let mut rest = Vec::new();
+        assert_eq!(0, client.read_to_end(&mut rest).await.unwrap());
+        time::timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
 }
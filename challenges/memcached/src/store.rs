@@ -1,62 +1,146 @@
+use std::collections::HashMap;
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::sync::Arc;
-use std::sync::atomic::AtomicU64;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
 
-use moka::future::Cache;
-use tokio::sync::{Mutex, MutexGuard};
+use tokio::sync::{Mutex, MutexGuard, broadcast};
+use tracing::instrument;
 
+use crate::backend::{Backend, CasOutcome, ItemMeta, StorageBackend};
+use crate::compression::Compressor;
+use crate::config::{Config, EvictionPolicy, NAMESPACE_SEPARATOR};
+use crate::data::Data;
+use crate::hotkeys::HotKeys;
+use crate::lease::{LeaseGrant, Leases};
 use crate::protocol::{StorageCommand, StorageCommandResponse, StorageCommandType, Value};
+use crate::watch::{EventKind, Invalidation, InvalidationReason, WatchEvent, Watcher};
 
-struct Expiry;
+/// The stale values kept for the lease waiters take at most this share of the memory limit, on
+/// top of it.
+const STALE_MEMORY_SHARE: u64 = 16;
 
-/// expiry is derived from the ttl provided by the user on update and create.
-impl moka::Expiry<String, Arc<Value>> for Expiry {
-    fn expire_after_create(&self, _: &String, value: &Arc<Value>, _: Instant) -> Option<Duration> {
-        Some(Duration::from_secs(value.exp_time as u64))
+/// The items of a namespace, see `NamespaceConfig`.
+struct Namespace {
+    backend: Backend,
+    /// Changed by `cache_memlimit` for the default namespace.
+    memory_limit: AtomicU64,
+    max_ttl: Option<u32>,
+}
+
+impl Namespace {
+    fn new(
+        config: &Config,
+        max_ttl: Option<u32>,
+        watcher: Watcher,
+        leases: Option<Arc<Leases>>,
+    ) -> std::io::Result<Namespace> {
+        Ok(Namespace {
+            backend: Backend::new(config, watcher, leases)?,
+            memory_limit: AtomicU64::new(config.memory_limit),
+            max_ttl,
+        })
     }
 
-    fn expire_after_update(
-        &self,
-        _: &String,
-        value: &Arc<Value>,
-        _: Instant,
-        _: Option<Duration>,
-    ) -> Option<Duration> {
-        Some(Duration::from_secs(value.exp_time as u64))
+    fn memory_limit(&self) -> u64 {
+        self.memory_limit.load(Ordering::Relaxed)
+    }
+
+    async fn resize(&self, memory_limit: u64) {
+        self.backend.resize(memory_limit).await;
+        self.memory_limit.store(memory_limit, Ordering::Relaxed);
+    }
+
+    /// Cap an `exp_time` to the longest time to live of the namespace, zero being no expiry.
+    fn cap_ttl(&self, exp_time: u32) -> u32 {
+        match self.max_ttl {
+            None => exp_time,
+            Some(max_ttl) if exp_time == 0 => max_ttl,
+            Some(max_ttl) => exp_time.min(max_ttl),
+        }
     }
 }
 
 struct Store {
     cas_counter: AtomicU64,
     write_slots: Vec<Mutex<()>>,
-    cache: Cache<String, Arc<Value>>,
+    /// Holds the keys outside of the namespaces.
+    default: Namespace,
+    namespaces: HashMap<String, Namespace>,
 }
 
 impl Store {
-    pub fn new() -> Store {
+    /// Fails if a backend cannot be created from `config`, or the namespace quotas leave no memory
+    /// to the default namespace.
+    pub fn new(
+        config: &Config,
+        watcher: Watcher,
+        leases: Option<Arc<Leases>>,
+    ) -> std::io::Result<Store> {
         let cas_counter = AtomicU64::new(0);
-        let cache = Cache::builder()
-            // Configure the cache with an upper bound as the total byte count of all the data.The
-            // `weighted_size` is updated on a maintenance task which is 100ms by default.
-            .weigher(|_: &String, value: &Arc<Value>| value.data.len() as u32)
-            .max_capacity(1024 * 1024 * 1024) // 1GB // TODO make this configurable
-            // Provide a strategy for extracting the TTL from the value. TTL is reset on updates.
-            .expire_after(Expiry {})
-            .build();
         // Use the number of logical cores as the number of write lock slots.
         let write_slots = (0..num_cpus::get()).map(|_| Mutex::new(())).collect();
 
-        Store {
-            cache,
+        let memory_limit = config
+            .memory_limit
+            .checked_sub(config.namespace_quotas())
+            .filter(|limit| *limit > 0)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the namespace quotas must be less than the memory limit",
+                )
+            })?;
+        let default = Config {
+            memory_limit,
+            ..config.clone()
+        };
+        let namespaces = config
+            .namespaces
+            .iter()
+            .map(|ns| {
+                // each namespace is a backend of its own, with its own extstore directory.
+                let ns_config = Config {
+                    memory_limit: ns.memory_limit,
+                    ext_path: config.ext_path.as_ref().map(|path| path.join(&ns.name)),
+                    ..config.clone()
+                };
+                let namespace =
+                    Namespace::new(&ns_config, ns.max_ttl, watcher.clone(), leases.clone())?;
+                Ok((ns.name.clone(), namespace))
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Store {
+            default: Namespace::new(&default, None, watcher, leases)?,
+            namespaces,
             write_slots,
             cas_counter,
-        }
+        })
+    }
+
+    /// The namespace of a key, selected by the prefix of the key.
+    #[inline]
+    fn namespace(&self, key: &str) -> &Namespace {
+        key.split_once(NAMESPACE_SEPARATOR)
+            .and_then(|(name, _)| self.namespaces.get(name))
+            .unwrap_or(&self.default)
+    }
+
+    #[inline]
+    fn backend(&self, key: &str) -> &Backend {
+        &self.namespace(key).backend
+    }
+
+    fn backends(&self) -> impl Iterator<Item = &Backend> + Send + '_ {
+        std::iter::once(&self.default)
+            .chain(self.namespaces.values())
+            .map(|ns| &ns.backend)
     }
 
     // derive the slot index and then await.
     #[inline]
-    async fn lock(&self, key: &String) -> MutexGuard<'_, ()> {
+    async fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
         let mut hasher = DefaultHasher::new();
         key.hash(&mut hasher);
         let hash = hasher.finish();
@@ -64,29 +148,95 @@ impl Store {
         self.write_slots[slot].lock().await
     }
 
+    /// Lock every write slot, in order so it cannot deadlock with another `lock_all`.
+    async fn lock_all(&self) -> Vec<MutexGuard<'_, ()>> {
+        let mut guards = Vec::with_capacity(self.write_slots.len());
+        for slot in &self.write_slots {
+            guards.push(slot.lock().await);
+        }
+        guards
+    }
+
     #[inline]
     fn next_cas(&self) -> u64 {
-        self.cas_counter
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst) //TODO understand the SeqCst ordering
+        self.cas_counter.fetch_add(1, Ordering::SeqCst) //TODO understand the SeqCst ordering
     }
 }
 
+/// The result of a `gets`.
+pub(crate) enum Lookup {
+    Hit(Arc<Value>),
+    Miss,
+    /// A miss with leases enabled.
+    Lease(LeaseGrant),
+}
+
 pub(crate) struct StoreProcessor {
     store: Store,
+    /// Shared with the backends, which keep the expired values as stale values.
+    leases: Option<Arc<Leases>>,
+    hotkeys: Option<HotKeys>,
+    compressor: Option<Compressor>,
+    watcher: Watcher,
+    /// Adjusted by a reload of the configuration.
+    max_item_size: AtomicUsize,
 }
 
 impl StoreProcessor {
-    pub(crate) fn new() -> StoreProcessor {
-        let store = Store::new();
+    /// Fails if the store cannot be created from `config`.
+    pub(crate) fn new(config: &Config) -> std::io::Result<StoreProcessor> {
+        let watcher = Watcher::new();
+        let leases = config
+            .lease_ttl
+            .map(|ttl| Arc::new(Leases::new(ttl, config.memory_limit / STALE_MEMORY_SHARE)));
+        let store = Store::new(config, watcher.clone(), leases.clone())?;
+        let hotkeys = match config.hotkey_sample_rate {
+            0 => None,
+            rate => Some(HotKeys::new(rate)),
+        };
+        let compressor = config.compress_threshold.map(Compressor::new);
 
-        StoreProcessor { store }
+        Ok(StoreProcessor {
+            store,
+            leases,
+            hotkeys,
+            compressor,
+            watcher,
+            max_item_size: AtomicUsize::new(config.max_item_size),
+        })
     }
 
+    #[instrument(level = "trace", skip_all, fields(key = %args.key))]
     pub(crate) async fn execute_storage_command(
         &self,
         mut args: StorageCommand,
     ) -> std::io::Result<StorageCommandResponse> {
+        self.record_access(&args.key);
+        args.exp_time = self.store.namespace(&args.key).cap_ttl(args.exp_time);
         let _lock = self.store.lock(&args.key).await;
+        let key = args.key.clone();
+        let command = args.command.name();
+
+        let res = self.do_storage_command(args).await?;
+        if let Some(leases) = &self.leases
+            && res == StorageCommandResponse::Stored
+        {
+            // the key has been filled, a lease holder must not overwrite it.
+            leases.invalidate(&key, None);
+        }
+        if res == StorageCommandResponse::Stored {
+            self.watcher.invalidate(&key, InvalidationReason::Set);
+        }
+        self.watcher
+            .publish(|| WatchEvent::new(EventKind::Mutation, command, &key, res.to_kw_str()));
+        Ok(res)
+    }
+
+    async fn do_storage_command(
+        &self,
+        mut args: StorageCommand,
+    ) -> std::io::Result<StorageCommandResponse> {
+        let backend = self.store.backend(&args.key);
 
         match args.command {
             StorageCommandType::Set => {
@@ -94,7 +244,7 @@ impl StoreProcessor {
                 Ok(StorageCommandResponse::Stored)
             }
             StorageCommandType::Add => {
-                if self.store.cache.get(&args.key).await.is_some() {
+                if backend.meta(&args.key).await.is_some() {
                     Ok(StorageCommandResponse::NotStored)
                 } else {
                     self.do_insert(args).await;
@@ -102,53 +252,390 @@ impl StoreProcessor {
                 }
             }
             StorageCommandType::Replace => {
-                if self.store.cache.get(&args.key).await.is_none() {
+                if backend.meta(&args.key).await.is_none() {
                     Ok(StorageCommandResponse::NotStored)
                 } else {
                     self.do_insert(args).await;
                     Ok(StorageCommandResponse::Stored)
                 }
             }
-            StorageCommandType::Prepend => {
-                if let Some(val) = self.store.cache.get(&args.key).await {
-                    args.data.extend_from_slice(&val.data);
-                    self.do_insert(args).await;
-                    Ok(StorageCommandResponse::Stored)
-                } else {
-                    Ok(StorageCommandResponse::NotStored)
+            StorageCommandType::Prepend | StorageCommandType::Append => {
+                let Some(val) = self.fetch(&args.key).await else {
+                    return Ok(StorageCommandResponse::NotStored);
+                };
+                if val.data.len() + args.data.len() > self.max_item_size() {
+                    return Ok(StorageCommandResponse::NotStored);
                 }
-            }
-            StorageCommandType::Append => {
-                if let Some(val) = self.store.cache.get(&args.key).await {
-                    args.data.reserve(args.data.len());
-                    args.data.splice(0..0, val.data.iter().cloned());
-                    self.do_insert(args).await;
-                    Ok(StorageCommandResponse::Stored)
+                args.data = if args.command == StorageCommandType::Prepend {
+                    Data::concat(&args.data, &val.data)
                 } else {
-                    Ok(StorageCommandResponse::NotStored)
+                    Data::concat(&val.data, &args.data)
+                };
+                self.do_insert(args).await;
+                Ok(StorageCommandResponse::Stored)
+            }
+            StorageCommandType::Cas => {
+                let cas_unique = args.cas_unique;
+                let (key, value) = self.to_value(args);
+                match backend
+                    .compare_and_swap(key.clone(), cas_unique, value.clone())
+                    .await
+                {
+                    CasOutcome::Stored => Ok(StorageCommandResponse::Stored),
+                    CasOutcome::Exists => Ok(StorageCommandResponse::Exists),
+                    // a missing key may be filled by the holder of its lease.
+                    CasOutcome::NotFound
+                        if self
+                            .leases
+                            .as_ref()
+                            .is_some_and(|l| l.fill(&key, cas_unique)) =>
+                    {
+                        backend.insert(key, value).await;
+                        Ok(StorageCommandResponse::Stored)
+                    }
+                    CasOutcome::NotFound => Ok(StorageCommandResponse::NotFound),
                 }
             }
         }
     }
 
-    async fn do_insert(&self, args: StorageCommand) {
-        let value = Arc::new(Value {
+    fn to_value(&self, args: StorageCommand) -> (String, Arc<Value>) {
+        let mut value = Value {
             flags: args.flags,
             exp_time: args.exp_time,
             data: args.data,
             cas: self.store.next_cas(),
-        });
-        self.store.cache.insert(args.key, value).await
+            compressed: None,
+        };
+        if let Some(compressor) = &self.compressor {
+            compressor.compress(&mut value);
+        }
+        (args.key, Arc::new(value))
     }
 
+    /// Get the value of `key` as the client stored it.
+    async fn fetch(&self, key: &str) -> Option<Arc<Value>> {
+        let val = self.store.backend(key).get(key).await?;
+        match &self.compressor {
+            Some(compressor) => compressor.decompress(key, val),
+            None => Some(val),
+        }
+    }
+
+    async fn do_insert(&self, args: StorageCommand) {
+        let (key, value) = self.to_value(args);
+        self.store.backend(&key).insert(key, value).await
+    }
+
+    #[instrument(level = "trace", skip_all, fields(key = %key))]
     pub(crate) async fn get(&self, key: &str) -> Option<Arc<Value>> {
-        self.store.cache.get(key).await
+        self.record_access(key);
+        let val = self.fetch(key).await;
+        self.publish_fetch("get", key, val.is_some());
+        val
+    }
+
+    /// A `get` which issues a lease on a miss when leases are enabled.
+    #[instrument(level = "trace", skip_all, fields(key = %key))]
+    pub(crate) async fn gets(&self, key: &str) -> Lookup {
+        self.record_access(key);
+        let val = self.fetch(key).await;
+        self.publish_fetch("gets", key, val.is_some());
+        if let Some(val) = val {
+            return Lookup::Hit(val);
+        }
+        let Some(leases) = &self.leases else {
+            return Lookup::Miss;
+        };
+        // check again under the write lock, the key may have been filled since.
+        let _lock = self.store.lock(key).await;
+        if let Some(val) = self.fetch(key).await {
+            return Lookup::Hit(val);
+        }
+        match leases.acquire(key, || self.store.next_cas()) {
+            // an expired value is kept as the backend stored it.
+            LeaseGrant::Stale(val) => match &self.compressor {
+                Some(compressor) => compressor
+                    .decompress(key, val)
+                    .map_or(Lookup::Lease(LeaseGrant::Wait), |val| {
+                        Lookup::Lease(LeaseGrant::Stale(val))
+                    }),
+                None => Lookup::Lease(LeaseGrant::Stale(val)),
+            },
+            grant => Lookup::Lease(grant),
+        }
+    }
+
+    #[inline]
+    fn record_access(&self, key: &str) {
+        if let Some(hotkeys) = &self.hotkeys {
+            hotkeys.record(key);
+        }
+    }
+
+    fn publish_fetch(&self, command: &'static str, key: &str, hit: bool) {
+        self.watcher.publish(|| {
+            let status = if hit { "hit" } else { "miss" };
+            WatchEvent::new(EventKind::Fetch, command, key, status)
+        });
+    }
+
+    /// Returns true if the key was deleted.
+    #[instrument(level = "trace", skip_all, fields(key = %key))]
+    pub(crate) async fn delete(&self, key: &str) -> bool {
+        let _lock = self.store.lock(key).await;
+        if let Some(leases) = &self.leases {
+            let stale = self.fetch(key).await;
+            leases.invalidate(key, stale);
+        }
+        let deleted = self.store.backend(key).delete(key).await;
+        if deleted {
+            self.watcher.invalidate(key, InvalidationReason::Delete);
+        }
+        self.watcher.publish(|| {
+            let status = if deleted { "DELETED" } else { "NOT_FOUND" };
+            WatchEvent::new(EventKind::Mutation, "delete", key, status)
+        });
+        deleted
+    }
+
+    /// Returns true if the expiry of the key was updated.
+    #[instrument(level = "trace", skip_all, fields(key = %key))]
+    pub(crate) async fn touch(&self, key: &str, exp_time: u32) -> bool {
+        let namespace = self.store.namespace(key);
+        let exp_time = namespace.cap_ttl(exp_time);
+        let _lock = self.store.lock(key).await;
+        let touched = namespace.backend.touch(key, exp_time).await;
+        self.watcher.publish(|| {
+            let status = if touched { "TOUCHED" } else { "NOT_FOUND" };
+            WatchEvent::new(EventKind::Mutation, "touch", key, status)
+        });
+        touched
+    }
+
+    /// The remaining time to live of `key`: `None` if the key is missing and `Some(None)` if it
+    /// does not expire.
+    pub(crate) async fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        let meta = self.store.backend(key).meta(key).await?;
+        let now = SystemTime::now();
+        Some(
+            meta.exp
+                .map(|exp| exp.duration_since(now).unwrap_or_default()),
+        )
+    }
+
+    /// Add `delta` to the decimal integer held by `key`, a missing key counts as zero. Returns the
+    /// new value, or an error if the value is not an integer or the result overflows.
+    #[instrument(level = "trace", skip_all, fields(key = %key))]
+    pub(crate) async fn incr(&self, key: &str, delta: i64) -> std::io::Result<i64> {
+        let mut result = 0;
+        self.update("incr", key, |current| {
+            let n = match current {
+                None => 0,
+                // a value longer than any i64 is not made contiguous to be parsed.
+                Some(data) if data.len() > MAX_INTEGER_LEN => return Err(not_an_integer()),
+                Some(data) => std::str::from_utf8(&data.contiguous())
+                    .ok()
+                    .and_then(|s| s.parse::<i64>().ok())
+                    .ok_or_else(not_an_integer)?,
+            };
+            result = n.checked_add(delta).ok_or_else(not_an_integer)?;
+            Ok(result.to_string().into_bytes().into())
+        })
+        .await?;
+        Ok(result)
+    }
+
+    /// Append `data` to the value of `key`, creating the key if it is missing. Unlike the
+    /// memcached `append` the expiry is kept. Returns the new length of the value.
+    #[instrument(level = "trace", skip_all, fields(key = %key))]
+    pub(crate) async fn append_or_insert(&self, key: &str, data: &Data) -> std::io::Result<usize> {
+        self.update("append", key, |current| match current {
+            Some(current) => Ok(Data::concat(current, data)),
+            None => Ok(data.clone()),
+        })
+        .await
+    }
+
+    /// Replace the data of `key` with the result of `f` under the write lock of the key, keeping
+    /// its flags and remaining time to live. Returns the length of the new data.
+    async fn update(
+        &self,
+        command: &'static str,
+        key: &str,
+        f: impl FnOnce(Option<&Data>) -> std::io::Result<Data>,
+    ) -> std::io::Result<usize> {
+        self.record_access(key);
+        let _lock = self.store.lock(key).await;
+        let current = self.fetch(key).await;
+        let data = f(current.as_ref().map(|val| &val.data))?;
+        if data.len() > self.max_item_size() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "value too large",
+            ));
+        }
+        let (flags, exp_time) = match &current {
+            // a new key gets the longest time to live of its namespace, as with `set`.
+            None => (0, self.store.namespace(key).cap_ttl(0)),
+            // round up so an item about to expire is not made permanent.
+            Some(val) => match self.ttl(key).await.flatten() {
+                Some(ttl) => (val.flags, ttl.as_secs_f64().ceil().max(1.0) as u32),
+                None => (val.flags, 0),
+            },
+        };
+        let len = data.len();
+        self.do_insert(StorageCommand {
+            command: StorageCommandType::Set,
+            key: key.to_string(),
+            flags,
+            exp_time,
+            no_reply: false,
+            byte_count: len as u32,
+            cas_unique: 0,
+            data,
+        })
+        .await;
+        if let Some(leases) = &self.leases {
+            leases.invalidate(key, None);
+        }
+        self.watcher.invalidate(key, InvalidationReason::Set);
+        self.watcher
+            .publish(|| WatchEvent::new(EventKind::Mutation, command, key, "STORED"));
+        Ok(len)
+    }
+
+    /// The largest value accepted by a storage command.
+    pub(crate) fn max_item_size(&self) -> usize {
+        self.max_item_size.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_max_item_size(&self, max_item_size: usize) {
+        self.max_item_size.store(max_item_size, Ordering::Relaxed);
+    }
+
+    /// The memory limit of the store, including the quotas of the namespaces.
+    pub(crate) fn memory_limit(&self) -> u64 {
+        std::iter::once(&self.store.default)
+            .chain(self.store.namespaces.values())
+            .map(Namespace::memory_limit)
+            .sum()
+    }
+
+    /// Change the memory limit of the store, for `cache_memlimit`. The quotas of the namespaces
+    /// are kept, the keys outside of the namespaces get the rest. The writes wait while the cache
+    /// is resized, the items are kept unless they are over the new limit.
+    pub(crate) async fn set_memory_limit(&self, memory_limit: u64) -> std::io::Result<()> {
+        let quotas: u64 = self
+            .store
+            .namespaces
+            .values()
+            .map(Namespace::memory_limit)
+            .sum();
+        if memory_limit <= quotas {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the memory limit must be more than the namespace quotas",
+            ));
+        }
+        let _locks = self.store.lock_all().await;
+        self.store.default.resize(memory_limit - quotas).await;
+        Ok(())
+    }
+
+    /// Returns true if a namespace of this name is configured.
+    pub(crate) fn has_namespace(&self, name: &str) -> bool {
+        self.store.namespaces.contains_key(name)
+    }
+
+    /// The statistics of each namespace, for `stats namespaces`. The names are prefixed with the
+    /// namespace, e.g. `team:bytes`.
+    pub(crate) fn namespace_stats(&self) -> Vec<(String, u64)> {
+        let mut names: Vec<_> = self.store.namespaces.keys().collect();
+        names.sort();
+        let mut stats = Vec::with_capacity(names.len() * 4);
+        for name in names {
+            let ns = &self.store.namespaces[name];
+            stats.push((format!("{}:curr_items", name), ns.backend.item_count()));
+            stats.push((format!("{}:bytes", name), ns.backend.memory_usage()));
+            stats.push((format!("{}:limit_maxbytes", name), ns.memory_limit()));
+            stats.push((format!("{}:evictions", name), ns.backend.evictions()));
+        }
+        stats
+    }
+
+    /// The most accessed keys with their estimated number of accesses, for `stats hotkeys`.
+    pub(crate) fn hotkeys(&self) -> Vec<(String, u64)> {
+        self.hotkeys.as_ref().map(HotKeys::top).unwrap_or_default()
+    }
+
+    /// The metadata of every item, for `lru_crawler metadump`.
+    pub(crate) fn metadump(&self) -> impl Iterator<Item = (Arc<String>, ItemMeta)> + Send + '_ {
+        self.store.backends().flat_map(|backend| backend.iter())
+    }
+
+    /// Subscribe to the live events of the store, for `watch`.
+    pub(crate) fn watch(&self) -> broadcast::Receiver<WatchEvent> {
+        self.watcher.subscribe()
+    }
+
+    /// Subscribe to the invalidations of the keys, for `subscribe`.
+    pub(crate) fn invalidations(&self) -> broadcast::Receiver<Invalidation> {
+        self.watcher.invalidations()
+    }
+
+    /// General purpose statistics in the form of the `stats` command.
+    pub(crate) fn stats(&self) -> Vec<(&'static str, String)> {
+        let sum = |f: fn(&Backend) -> u64| self.store.backends().map(f).sum::<u64>();
+        let mut stats = vec![
+            ("curr_items", sum(Backend::item_count).to_string()),
+            ("bytes", sum(Backend::memory_usage).to_string()),
+            ("limit_maxbytes", self.memory_limit().to_string()),
+            ("evictions", sum(Backend::evictions).to_string()),
+        ];
+        for (policy, name) in [
+            (EvictionPolicy::TinyLfu, "evictions_tinylfu"),
+            (EvictionPolicy::Lru, "evictions_lru"),
+            (EvictionPolicy::Fifo, "evictions_fifo"),
+        ] {
+            let evictions: u64 = self
+                .store
+                .backends()
+                .filter(|backend| backend.policy() == policy)
+                .map(Backend::evictions)
+                .sum();
+            stats.push((name, evictions.to_string()));
+        }
+        let ext_used: Option<u64> = self.store.backends().map(Backend::ext_bytes_used).sum();
+        if let Some(used) = ext_used {
+            stats.push(("extstore_bytes_used", used.to_string()));
+        }
+        if let Some(compressor) = &self.compressor {
+            let (bytes_in, bytes_out) = compressor.bytes();
+            let ratio = bytes_in as f64 / bytes_out.max(1) as f64;
+            stats.push(("compression_bytes_in", bytes_in.to_string()));
+            stats.push(("compression_bytes_out", bytes_out.to_string()));
+            stats.push(("compression_ratio", format!("{:.2}", ratio)));
+        }
+        stats
     }
 }
 
+/// The length of the longest value `incr` can parse, `i64::MIN`.
+const MAX_INTEGER_LEN: usize = 20;
+
+fn not_an_integer() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "value is not an integer or out of range",
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{BackendKind, NamespaceConfig};
+    use crate::lease::LeaseGrant;
     use StorageCommandType::*;
 
     fn fixture(command: StorageCommandType, key: &str, data: &[u8]) -> StorageCommand {
@@ -156,19 +643,17 @@ mod tests {
             command,
             key: key.to_string(),
             exp_time: 60,
-            data: data.to_vec(),
+            data: data.to_vec().into(),
             flags: 0,
             byte_count: 0,
+            cas_unique: 0,
             no_reply: false,
         }
     }
 
-    // TODO:
-    // 1. verify CAS.
-
     #[tokio::test]
     async fn test_processor_storage_set_add_replace() -> std::io::Result<()> {
-        let processor = StoreProcessor::new();
+        let processor = StoreProcessor::new(&Config::default()).unwrap();
 
         {
             // tests an add against a key that does not exist
@@ -219,7 +704,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_processor_storage_append_prepend() -> std::io::Result<()> {
-        let processor = StoreProcessor::new();
+        let processor = StoreProcessor::new(&Config::default()).unwrap();
 
         {
             // append and prepend to non-existing keys
@@ -262,4 +747,414 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_processor_cas() -> std::io::Result<()> {
+        for backend in [BackendKind::Moka, BackendKind::Sharded] {
+            let config = Config {
+                backend,
+                ..Config::default()
+            };
+            let processor = StoreProcessor::new(&config).unwrap();
+
+            {
+                // cas against an unknown key
+                let mut command = fixture(Cas, "key", b"value1");
+                command.cas_unique = 0;
+                let res = processor.execute_storage_command(command).await?;
+                assert_eq!(StorageCommandResponse::NotFound, res);
+            }
+
+            processor
+                .execute_storage_command(fixture(Set, "key", b"value1"))
+                .await?;
+            let cas_unique = processor.get("key").await.unwrap().cas;
+
+            {
+                // cas with a stale unique
+                let mut command = fixture(Cas, "key", b"value2");
+                command.cas_unique = cas_unique + 1;
+                let res = processor.execute_storage_command(command).await?;
+                assert_eq!(StorageCommandResponse::Exists, res);
+            }
+
+            {
+                // cas with the current unique, the unique changes on store
+                let mut command = fixture(Cas, "key", b"value3");
+                command.cas_unique = cas_unique;
+                let res = processor.execute_storage_command(command).await?;
+                assert_eq!(StorageCommandResponse::Stored, res);
+                let res = processor.get("key").await.unwrap();
+                assert_eq!(b"value3".to_vec(), res.data);
+                assert_ne!(cas_unique, res.cas);
+            }
+
+            assert!(processor.touch("key", 120).await);
+            assert!(processor.delete("key").await);
+            assert!(!processor.touch("key", 120).await);
+            assert!(processor.get("key").await.is_none());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processor_leases() -> std::io::Result<()> {
+        let config = Config {
+            lease_ttl: Some(std::time::Duration::from_secs(10)),
+            ..Config::default()
+        };
+        let processor = StoreProcessor::new(&config).unwrap();
+
+        // the first miss wins the lease, the second is told to wait.
+        let Lookup::Lease(LeaseGrant::Granted(token)) = processor.gets("key").await else {
+            panic!("expected a lease");
+        };
+        assert!(matches!(
+            processor.gets("key").await,
+            Lookup::Lease(LeaseGrant::Wait)
+        ));
+
+        {
+            // a cas with a token that is not the lease does not fill the key
+            let mut command = fixture(Cas, "key", b"value1");
+            command.cas_unique = token + 1;
+            let res = processor.execute_storage_command(command).await?;
+            assert_eq!(StorageCommandResponse::NotFound, res);
+        }
+
+        {
+            // the lease holder fills the key
+            let mut command = fixture(Cas, "key", b"value1");
+            command.cas_unique = token;
+            let res = processor.execute_storage_command(command).await?;
+            assert_eq!(StorageCommandResponse::Stored, res);
+            assert!(matches!(processor.gets("key").await, Lookup::Hit(_)));
+        }
+
+        {
+            // after a delete the next client wins the lease, the others get the stale value
+            assert!(processor.delete("key").await);
+            assert!(matches!(
+                processor.gets("key").await,
+                Lookup::Lease(LeaseGrant::Granted(_))
+            ));
+            let Lookup::Lease(LeaseGrant::Stale(stale)) = processor.gets("key").await else {
+                panic!("expected a stale value");
+            };
+            assert_eq!(b"value1".to_vec(), stale.data);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processor_lease_on_expiry() -> std::io::Result<()> {
+        let config = Config {
+            backend: BackendKind::Sharded,
+            lease_ttl: Some(Duration::from_secs(10)),
+            compress_threshold: Some(16),
+            ..Config::default()
+        };
+        let processor = StoreProcessor::new(&config)?;
+        let data = [b'v'; 64];
+        processor
+            .execute_storage_command(StorageCommand {
+                exp_time: 1,
+                ..fixture(Set, "key", &data)
+            })
+            .await?;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        // the expired value is served, decompressed, to the clients waiting on the lease.
+        assert!(matches!(
+            processor.gets("key").await,
+            Lookup::Lease(LeaseGrant::Granted(_))
+        ));
+        let Lookup::Lease(LeaseGrant::Stale(stale)) = processor.gets("key").await else {
+            panic!("expected a stale value");
+        };
+        assert_eq!(data.to_vec(), stale.data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processor_invalidations() -> std::io::Result<()> {
+        let processor = StoreProcessor::new(&Config::default())?;
+        let mut invalidations = processor.invalidations();
+        processor
+            .execute_storage_command(fixture(Set, "key", b"value"))
+            .await?;
+        // reads, touches and commands which store nothing leave the key valid.
+        assert!(processor.get("key").await.is_some());
+        assert!(processor.touch("key", 120).await);
+        processor
+            .execute_storage_command(fixture(Add, "key", b"value"))
+            .await?;
+        assert!(processor.delete("key").await);
+        assert!(!processor.delete("key").await);
+        processor.incr("counter", 1).await?;
+
+        let mut received = Vec::new();
+        while let Ok(invalidation) = invalidations.try_recv() {
+            received.push((invalidation.key, invalidation.reason));
+        }
+        let expected = [
+            ("key", InvalidationReason::Set),
+            ("key", InvalidationReason::Delete),
+            ("counter", InvalidationReason::Set),
+        ];
+        let expected: Vec<_> = expected.map(|(k, r)| (k.to_string(), r)).into();
+        assert_eq!(expected, received);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processor_watch_metadump() -> std::io::Result<()> {
+        let processor = StoreProcessor::new(&Config::default()).unwrap();
+        let mut events = processor.watch();
+
+        processor
+            .execute_storage_command(fixture(Set, "key", b"value"))
+            .await?;
+        assert!(processor.get("key").await.is_some());
+        assert!(processor.get("unknown").await.is_none());
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(
+            (EventKind::Mutation, "set", "key", "STORED"),
+            (event.kind, event.command, event.key.as_str(), event.status)
+        );
+        let event = events.try_recv().unwrap();
+        assert_eq!((EventKind::Fetch, "hit"), (event.kind, event.status));
+        let event = events.try_recv().unwrap();
+        assert_eq!((EventKind::Fetch, "miss"), (event.kind, event.status));
+
+        processor
+            .execute_storage_command(fixture(Set, "key", b"value"))
+            .await?;
+        let dump: Vec<_> = processor.metadump().collect();
+        assert_eq!(1, dump.len());
+        assert_eq!("key", dump[0].0.as_str());
+        assert!(dump[0].1.exp.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processor_compression() -> std::io::Result<()> {
+        let config = Config {
+            backend: BackendKind::Sharded,
+            compress_threshold: Some(64),
+            ..Config::default()
+        };
+        let processor = StoreProcessor::new(&config).unwrap();
+        let data = br#"{"id":1,"name":"value"}"#.repeat(16);
+        processor
+            .execute_storage_command(fixture(Set, "key", &data))
+            .await?;
+        processor
+            .execute_storage_command(fixture(Append, "key", b"tail"))
+            .await?;
+
+        let val = processor.get("key").await.unwrap();
+        assert!(val.compressed.is_none());
+        assert_eq!([data.as_slice(), b"tail"].concat(), val.data);
+        // the stored value is compressed.
+        let stored = processor.store.backend("key").get("key").await.unwrap();
+        assert!(stored.compressed.is_some() && stored.data.len() < data.len());
+
+        let stats = processor.stats();
+        let ratio = stats.iter().find(|(name, _)| *name == "compression_ratio");
+        assert!(ratio.unwrap().1.parse::<f64>().unwrap() > 1.0);
+
+        // the stats only count the values still stored.
+        drop(stored);
+        assert!(processor.delete("key").await);
+        assert!(
+            processor
+                .stats()
+                .contains(&("compression_bytes_in", "0".to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processor_incr_append_ttl() -> std::io::Result<()> {
+        for backend in [BackendKind::Moka, BackendKind::Sharded] {
+            let config = Config {
+                backend,
+                ..Config::default()
+            };
+            let processor = StoreProcessor::new(&config).unwrap();
+            assert_eq!(5, processor.incr("counter", 5).await?);
+            assert_eq!(3, processor.incr("counter", -2).await?);
+            assert_eq!(Some(None), processor.ttl("counter").await);
+            assert!(processor.incr("counter", i64::MAX).await.is_err());
+
+            processor
+                .execute_storage_command(fixture(Set, "key", b"value"))
+                .await?;
+            assert!(processor.incr("key", 1).await.is_err());
+            assert_eq!(
+                8,
+                processor
+                    .append_or_insert("key", &b"abc".to_vec().into())
+                    .await?
+            );
+            assert_eq!(
+                b"valueabc".to_vec(),
+                processor.get("key").await.unwrap().data
+            );
+            // the expiry of the fixture is kept.
+            let ttl = processor.ttl("key").await.unwrap().unwrap();
+            assert!(ttl > Duration::from_secs(55) && ttl <= Duration::from_secs(60));
+
+            assert_eq!(
+                3,
+                processor
+                    .append_or_insert("new", &b"abc".to_vec().into())
+                    .await?
+            );
+            assert_eq!(None, processor.ttl("missing").await);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processor_namespaces() -> std::io::Result<()> {
+        let config = Config {
+            backend: BackendKind::Sharded,
+            memory_limit: 64 * 1024,
+            namespaces: vec![NamespaceConfig {
+                name: "bulk".to_string(),
+                memory_limit: 16 * 1024,
+                max_ttl: Some(10),
+            }],
+            ..Config::default()
+        };
+        config.validate()?;
+        let processor = StoreProcessor::new(&config)?;
+        processor
+            .execute_storage_command(fixture(Set, "keep", b"value"))
+            .await?;
+        // a bulk load only evicts from its own namespace.
+        for i in 0..100 {
+            let key = format!("bulk:{}", i);
+            processor
+                .execute_storage_command(fixture(Set, &key, &[0; 1024]))
+                .await?;
+        }
+        assert!(processor.get("keep").await.is_some());
+        let stats = processor.namespace_stats();
+        assert!(stats.contains(&("bulk:limit_maxbytes".to_string(), 16 * 1024)));
+        let evictions = stats.iter().find(|(name, _)| name == "bulk:evictions");
+        assert!(evictions.unwrap().1 > 0);
+        // the sharded backend defaults to LRU, the evictions are reported under that policy.
+        let stats = processor.stats();
+        let lru = stats.iter().find(|(name, _)| *name == "evictions_lru");
+        assert!(lru.unwrap().1.parse::<u64>().unwrap() > 0);
+        assert!(stats.contains(&("evictions_tinylfu", "0".to_string())));
+
+        // the ttl of the namespace caps items stored without an expiry.
+        processor
+            .execute_storage_command(StorageCommand {
+                exp_time: 0,
+                ..fixture(Set, "bulk:capped", b"value")
+            })
+            .await?;
+        let ttl = processor.ttl("bulk:capped").await.unwrap().unwrap();
+        assert!(ttl <= Duration::from_secs(10));
+        // and the keys created by INCR and APPEND.
+        processor.incr("bulk:counter", 1).await?;
+        processor
+            .append_or_insert("bulk:log", &b"line".to_vec().into())
+            .await?;
+        for key in ["bulk:counter", "bulk:log"] {
+            let ttl = processor.ttl(key).await.unwrap().unwrap();
+            assert!(ttl <= Duration::from_secs(10));
+        }
+        assert!(processor.has_namespace("bulk") && !processor.has_namespace("keep"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_processor_quotas_over_memory_limit() {
+        // `validate` is not called on this path, the quotas are checked again.
+        let config = Config {
+            memory_limit: 1024,
+            namespaces: vec![NamespaceConfig {
+                name: "bulk".to_string(),
+                memory_limit: 2048,
+                max_ttl: None,
+            }],
+            ..Config::default()
+        };
+        assert!(StoreProcessor::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_processor_memory_limit() -> std::io::Result<()> {
+        let config = Config {
+            memory_limit: 64 * 1024,
+            namespaces: vec![NamespaceConfig {
+                name: "bulk".to_string(),
+                memory_limit: 16 * 1024,
+                max_ttl: None,
+            }],
+            ..Config::default()
+        };
+        let processor = StoreProcessor::new(&config).unwrap();
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            processor
+                .execute_storage_command(fixture(Set, &key, b"value"))
+                .await?;
+        }
+        assert!(processor.set_memory_limit(16 * 1024).await.is_err());
+
+        // the items survive the cache being rebuilt, with what was left of their ttl.
+        processor.set_memory_limit(128 * 1024).await?;
+        for i in 0..10 {
+            assert!(processor.get(&format!("key{}", i)).await.is_some());
+        }
+        let ttl = processor.ttl("key0").await.unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(55) && ttl <= Duration::from_secs(60));
+        let limit = processor
+            .stats()
+            .into_iter()
+            .find(|(name, _)| *name == "limit_maxbytes");
+        assert_eq!((128 * 1024).to_string(), limit.unwrap().1);
+        let stats = processor.namespace_stats();
+        assert!(stats.contains(&("bulk:limit_maxbytes".to_string(), 16 * 1024)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processor_large_items() -> std::io::Result<()> {
+        let config = Config {
+            max_item_size: 3 * 1024 * 1024,
+            ..Config::default()
+        };
+        let processor = StoreProcessor::new(&config).unwrap();
+        let part = vec![7; 1024 * 1024 + 1];
+        processor
+            .execute_storage_command(fixture(Set, "large", &part))
+            .await?;
+        let res = processor
+            .execute_storage_command(fixture(Append, "large", &part))
+            .await?;
+        assert_eq!(StorageCommandResponse::Stored, res);
+        let val = processor.get("large").await.unwrap();
+        assert!(matches!(val.data, Data::Chunked(_)));
+        assert_eq!([part.clone(), part.clone()].concat(), val.data);
+
+        // an append may not grow the item past the limit.
+        let res = processor
+            .execute_storage_command(fixture(Append, "large", &part))
+            .await?;
+        assert_eq!(StorageCommandResponse::NotStored, res);
+        assert_eq!(
+            2 * part.len(),
+            processor.get("large").await.unwrap().data.len()
+        );
+        Ok(())
+    }
 }
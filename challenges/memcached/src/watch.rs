@@ -0,0 +1,151 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::broadcast;
+
+/// The number of events buffered per watcher. A watcher which falls further behind skips events,
+/// so watchers observe a sample of the traffic rather than slowing it down.
+const WATCH_BUFFER: usize = 1024;
+
+/// The number of invalidations buffered per subscriber. A subscriber which falls further behind
+/// has to drop its whole cache, so the buffer is larger than the one of the watchers.
+const INVALIDATION_BUFFER: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum EventKind {
+    Fetch,
+    Mutation,
+    Eviction,
+    Expiration,
+}
+
+impl EventKind {
+    pub(crate) fn from_bytes(s: &[u8]) -> Option<EventKind> {
+        match s {
+            b"fetchers" => Some(EventKind::Fetch),
+            b"mutations" => Some(EventKind::Mutation),
+            b"evictions" => Some(EventKind::Eviction),
+            b"expirations" => Some(EventKind::Expiration),
+            _ => None,
+        }
+    }
+}
+
+/// A live event, published to the connections running `watch`.
+#[derive(Debug, Clone)]
+pub(crate) struct WatchEvent {
+    pub(crate) kind: EventKind,
+    /// The command, or the cause of an eviction.
+    pub(crate) command: &'static str,
+    pub(crate) key: String,
+    pub(crate) status: &'static str,
+    pub(crate) time: SystemTime,
+}
+
+impl WatchEvent {
+    pub(crate) fn new(
+        kind: EventKind,
+        command: &'static str,
+        key: &str,
+        status: &'static str,
+    ) -> WatchEvent {
+        WatchEvent {
+            kind,
+            command,
+            key: key.to_string(),
+            status,
+            time: SystemTime::now(),
+        }
+    }
+}
+
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ts = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let kind = match self.kind {
+            EventKind::Fetch => "fetch",
+            EventKind::Mutation => "mutation",
+            EventKind::Eviction => "eviction",
+            EventKind::Expiration => "expiration",
+        };
+        write!(
+            f,
+            "ts={}.{:06} type={} cmd={} key={} status={}",
+            ts.as_secs(),
+            ts.subsec_micros(),
+            kind,
+            self.command,
+            self.key,
+            self.status
+        )
+    }
+}
+
+/// Why a key must be dropped from a client side cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum InvalidationReason {
+    Set,
+    Delete,
+    Evicted,
+    Expired,
+}
+
+impl fmt::Display for InvalidationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InvalidationReason::Set => "set",
+            InvalidationReason::Delete => "delete",
+            InvalidationReason::Evicted => "evicted",
+            InvalidationReason::Expired => "expired",
+        })
+    }
+}
+
+/// A change to a key, published to the connections running `subscribe`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Invalidation {
+    pub(crate) key: String,
+    pub(crate) reason: InvalidationReason,
+}
+
+/// Fans events out to the watching connections, and invalidations out to the subscribed ones.
+/// Cloned into every component that publishes.
+#[derive(Clone)]
+pub(crate) struct Watcher {
+    tx: broadcast::Sender<WatchEvent>,
+    invalidations: broadcast::Sender<Invalidation>,
+}
+
+impl Watcher {
+    pub(crate) fn new() -> Watcher {
+        let (tx, _) = broadcast::channel(WATCH_BUFFER);
+        let (invalidations, _) = broadcast::channel(INVALIDATION_BUFFER);
+        Watcher { tx, invalidations }
+    }
+
+    /// Publish an event. The event is only built when a connection is watching, so publishing is
+    /// close to free otherwise.
+    #[inline]
+    pub(crate) fn publish(&self, event: impl FnOnce() -> WatchEvent) {
+        if self.tx.receiver_count() > 0 {
+            let _ = self.tx.send(event());
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<WatchEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Publish the invalidation of `key`, only when a connection is subscribed.
+    #[inline]
+    pub(crate) fn invalidate(&self, key: &str, reason: InvalidationReason) {
+        if self.invalidations.receiver_count() > 0 {
+            let key = key.to_string();
+            let _ = self.invalidations.send(Invalidation { key, reason });
+        }
+    }
+
+    pub(crate) fn invalidations(&self) -> broadcast::Receiver<Invalidation> {
+        self.invalidations.subscribe()
+    }
+}